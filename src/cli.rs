@@ -1,6 +1,7 @@
 use crate::file::{
     compression::Compression,
-    functions::{FileCopyFromParams, FileCopyToParams, Partition},
+    functions::{FileCopyFromParams, FileCopyToParams, Partition, PartitionPath, PartitionSize},
+    glob::Glob,
 };
 use clap::Parser;
 use std::path::PathBuf;
@@ -25,21 +26,33 @@ pub enum Docker {
         /// full qualified name of the docker image
         #[clap(short = 'd', long = "docker-image", required(true))]
         docker_image: String,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// partition to store the image to
-        #[clap(short = 'a', long = "partition", value_enum, default_value = "factory")]
+        /// partition to store the image to [boot, rootA, rootB, cert, factory, etc, data, <number>, name=<GPT partition name>, label=<filesystem label>]
+        #[clap(short = 'a', long = "partition", value_parser = clap::value_parser!(Partition), default_value = "factory")]
         partition: Partition,
         /// destination path of the docker image in the firmware image (must end in ".tar.gz")
         #[clap(short = 'e', long = "dest")]
         dest: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
 }
 
@@ -49,27 +62,198 @@ pub enum Docker {
 pub enum File {
     /// file commands, e.g. copy multiple files to/from image
     CopyToImage {
-        /// vector of copy triples in the format [in-file-path,out-partition:out-file-path]
+        /// vector of copy triples in the format [in-file-path,out-partition:out-file-path]; in-file-path may be a directory which is copied recursively;
+        /// optionally followed by [,uid=<uid>][,gid=<gid>][,mode=<octal mode>][,mtime=<unix seconds or RFC 3339 time>] to set the attributes of the copied files
         #[clap(short = 'f', long = "files", value_parser = clap::value_parser!(FileCopyToParams), required(true))]
         file_copy_params: Vec<FileCopyToParams>,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// optional: when copying directories only copy files matching one of these glob patterns
+        #[arg(long = "include", value_parser = clap::value_parser!(Glob))]
+        include: Vec<Glob>,
+        /// optional: when copying directories skip files and directories matching one of these glob patterns
+        #[arg(long = "exclude", value_parser = clap::value_parser!(Glob))]
+        exclude: Vec<Glob>,
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// copy files from image
     CopyFromImage {
-        /// vector of copy triples in the format [in-partition:in-file-path,out-file-path]
+        /// vector of copy triples in the format [in-partition:in-file-path,out-file-path]; in-file-path may be a directory which is copied recursively
         #[clap(short = 'f', long = "files", value_parser = clap::value_parser!(FileCopyFromParams), required(true))]
         file_copy_params: Vec<FileCopyFromParams>,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// optional: when copying directories only copy files matching one of these glob patterns
+        #[arg(long = "include", value_parser = clap::value_parser!(Glob))]
+        include: Vec<Glob>,
+        /// optional: when copying directories skip files and directories matching one of these glob patterns
+        #[arg(long = "exclude", value_parser = clap::value_parser!(Glob))]
+        exclude: Vec<Glob>,
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
+        /// optional: copy symbolic links as links instead of the files they point to
+        #[arg(short = 'P', long = "no-dereference")]
+        no_dereference: bool,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+    },
+    /// remove files or directories from image
+    Rm {
+        /// vector of paths in the format [partition:path]
+        #[clap(value_parser = clap::value_parser!(PartitionPath), required(true))]
+        paths: Vec<PartitionPath>,
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: remove directories and their content recursively
+        #[arg(short = 'r', long = "recursive")]
+        recursive: bool,
+        /// optional: generate bmap file
+        #[arg(short = 'b', long = "generate-bmap-file")]
+        generate_bmap: bool,
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
+        compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
+    },
+    /// move or rename a file or directory within a partition of image
+    Mv {
+        /// source path in the format [partition:path]
+        source: PartitionPath,
+        /// destination path in the format [partition:path]; an existing
+        /// directory receives the source
+        destination: PartitionPath,
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: generate bmap file
+        #[arg(short = 'b', long = "generate-bmap-file")]
+        generate_bmap: bool,
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
+        compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
+    },
+    /// list files and directories in image
+    Ls {
+        /// vector of paths in the format [partition:path]
+        #[clap(value_parser = clap::value_parser!(PartitionPath), required(true))]
+        paths: Vec<PartitionPath>,
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: list subdirectories recursively
+        #[arg(short = 'r', long = "recursive")]
+        recursive: bool,
+        /// optional: print entries as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(after_help = COPYRIGHT)]
+/// inspect a firmware image
+pub enum Image {
+    /// print compression, partition table, free space per filesystem and os-release information of an image
+    Info {
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// optional: print information as json
+        #[arg(short = 'j', long = "json")]
+        json: bool,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+    },
+    /// grow a partition and its ext4 filesystem; partitions behind it are moved towards the end of the image if necessary
+    ResizePartition {
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// partition to grow [rootA, rootB, cert, factory, etc, data, <number>, name=<GPT partition name>, label=<filesystem label>]
+        #[clap(short = 'a', long = "partition", value_parser = clap::value_parser!(Partition))]
+        partition: Partition,
+        /// new size of the partition in bytes with optional unit [K, M, G, T], e.g. "2G"; prefix with "+" to grow by the given size, e.g. "+512M"
+        #[clap(short = 's', long = "size", value_parser = clap::value_parser!(PartitionSize))]
+        size: PartitionSize,
+        /// optional: generate bmap file
+        #[arg(short = 'b', long = "generate-bmap-file")]
+        generate_bmap: bool,
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
+        compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
+    },
+    /// write an image to a block device or file; if a bmap file is given or found next to the image, only mapped blocks are written and their checksums are verified
+    Flash {
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
+        #[arg(short = 'i', long = "image")]
+        image: PathBuf,
+        /// target block device or file, e.g. /dev/sdb
+        #[arg(short = 't', long = "target")]
+        target: PathBuf,
+        /// optional: path to bmap file, defaults to <image>.bmap with or without compression extension
+        #[arg(short = 'm', long = "bmap")]
+        bmap: Option<PathBuf>,
+        /// optional: also write to mounted or non-removable block devices
+        #[arg(short = 'f', long = "force")]
+        force: bool,
     },
 }
 
@@ -85,22 +269,34 @@ pub enum IdentityConfig {
         /// optional: path to extra DPS payload file
         #[arg(short = 'e', long = "extra-dps-payload")]
         payload: Option<PathBuf>,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// EXPERIMENTAL: set transparent gateway config.toml file and additional certificates and keys
     SetIotedgeGatewayConfig {
         /// path to config.toml file
         #[arg(short = 'c', long = "config")]
         config: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// path to root ca certificate file
@@ -112,30 +308,54 @@ pub enum IdentityConfig {
         /// path to device identity certificate key file
         #[arg(short = 'k', long = "device_identity_key")]
         device_identity_key: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// EXPERIMENTAL: set leaf device config.toml file and additional certificate
     SetIotLeafSasConfig {
         /// path to config.toml file
         #[arg(short = 'c', long = "config")]
         config: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// path to root ca certificate file
         #[arg(short = 'r', long = "root_ca")]
         root_ca: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// set certificates in order to support X.509 based DPS provisioning and certificate renewal via EST
     SetDeviceCertificate {
@@ -145,7 +365,7 @@ pub enum IdentityConfig {
         /// path to intermediate key pem file
         #[arg(short = 'k', long = "intermediate-key")]
         intermediate_key: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// device id
@@ -154,12 +374,24 @@ pub enum IdentityConfig {
         /// period of validity in days
         #[arg(short = 'D', long = "days")]
         days: u32,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// set certificates in order to support X.509 based DPS provisioning WITHOUT certificate renewal via EST
     SetDeviceCertificateNoEst {
@@ -169,15 +401,27 @@ pub enum IdentityConfig {
         /// path to device key pem file
         #[arg(short = 'k', long = "device-key")]
         device_key: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// generate and set bootstrap certificate for edge ca issuance/renewal.
     SetEdgeCaCertificate {
@@ -187,7 +431,7 @@ pub enum IdentityConfig {
         /// path to intermediate key pem file
         #[arg(short = 'k', long = "intermediate-key")]
         intermediate_key: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// device id
@@ -196,12 +440,24 @@ pub enum IdentityConfig {
         /// period of validity in days
         #[arg(short = 'D', long = "days")]
         days: u32,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
 }
 
@@ -214,15 +470,27 @@ pub enum IotHubDeviceUpdate {
         /// path to device-update configuration file
         #[arg(short = 'c', long = "config")]
         iot_hub_device_update_config: PathBuf,
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
     /// import update to azure iot-hub
     ImportUpdate {
@@ -330,18 +598,30 @@ pub enum IotHubDeviceUpdate {
 pub enum SshConfig {
     /// set ssh tunnel certificate
     SetCertificate {
-        /// path to wic image file (optionally compressed with xz, bzip2 or gzip)
+        /// path to wic image file (optionally compressed with xz, bzip2, gzip or zstd)
         #[arg(short = 'i', long = "image")]
         image: PathBuf,
         /// path to public key of the ssh root ca
         #[arg(short = 'r', long = "root_ca")]
         root_ca: PathBuf,
-        /// optional: generate bmap file (currently not working in docker image)
+        /// optional: generate bmap file
         #[arg(short = 'b', long = "generate-bmap-file")]
         generate_bmap: bool,
-        /// optional: pack image [xz, bzip2, gzip] (for xz default level '9' is used, which can be overwritten by setting 'XZ_COMPRESSION_LEVEL=')
-        #[arg(short = 'p', long = "pack-image", value_enum)]
+        /// optional: pack image [xz, bzip2, gzip, zstd]
+        #[arg(short = 'p', long = "pack-image", value_enum, group = "pack")]
         compress_image: Option<Compression>,
+        /// optional: compression level of packed image [xz: 0-9, bzip2: 1-9, gzip: 0-9, zstd: 1-22] (default: 9, for zstd 19)
+        #[arg(long = "compression-level", requires = "pack")]
+        compression_level: Option<u32>,
+        /// optional: number of threads used to pack the image (default: number of cpus)
+        #[arg(long = "threads", requires = "pack", value_parser = clap::value_parser!(u32).range(1..))]
+        threads: Option<u32>,
+        /// optional: directory for temporary files, e.g. the decompressed image (default: $TMPDIR or /tmp)
+        #[arg(long = "work-dir")]
+        work_dir: Option<PathBuf>,
+        /// optional: write the resulting image to this path instead of modifying the input image; packed according to the extension [xz, bzip2, bz2, gzip, gz, zst, zstd]
+        #[arg(short = 'o', long = "output", group = "pack")]
+        output: Option<PathBuf>,
     },
 
     /// set ssh connection parameters (see
@@ -387,6 +667,8 @@ pub enum Command {
     #[command(subcommand)]
     Identity(IdentityConfig),
     #[command(subcommand)]
+    Image(Image),
+    #[command(subcommand)]
     IotHubDeviceUpdate(IotHubDeviceUpdate),
     #[command(subcommand)]
     Ssh(SshConfig),
@@ -54,7 +54,10 @@ pub fn pull_image(name: impl AsRef<str>, arch: Architecture) -> Result<PathBuf>
             fs::canonicalize(&out_path).unwrap().to_string_lossy(),
         ))?;
 
-    Compression::gzip.compress(&mut image_file, &mut out_file)?;
+    Compression::gzip {
+        compression_level: 9,
+    }
+    .compress(&mut image_file, &mut out_file, num_cpus::get() as u32)?;
 
     let error_code = child.wait()?;
 
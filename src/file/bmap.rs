@@ -0,0 +1,421 @@
+use anyhow::{Context, Result};
+use log::debug;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+// block size used by bmaptool
+const BMAP_BLOCK_SIZE: u64 = 4096;
+const BMAP_VERSION: &str = "2.0";
+const BMAP_MAJOR_VERSION: &str = "2";
+const CHECKSUM_TYPE: &str = "sha256";
+const CHECKSUM_LEN: usize = 64;
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Consecutive blocks of an image which contain data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BmapRange {
+    pub first: u64,
+    /// inclusive
+    pub last: u64,
+    /// hex encoded sha256 of the range, the last block of the image is only
+    /// taken into account up to the end of the image
+    pub checksum: String,
+}
+
+/// Block map of an image in the format of bmaptool, i.e. the list of mapped
+/// blocks which have to be written to a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub ranges: Vec<BmapRange>,
+}
+
+impl Bmap {
+    /// Scans `image_file` for allocated ranges and calculates their checksums.
+    pub fn create(image_file: &Path) -> Result<Bmap> {
+        let mut file = File::open(image_file).context(format!(
+            "Bmap::create: cannot open {}",
+            image_file.display()
+        ))?;
+        let image_size = file.metadata()?.len();
+
+        let mut ranges = vec![];
+        let mut buf = vec![0u8; IO_CHUNK_SIZE];
+
+        for (first, last) in mapped_blocks(&file, image_size, BMAP_BLOCK_SIZE)
+            .context("Bmap::create: cannot determine mapped blocks")?
+        {
+            let start = first * BMAP_BLOCK_SIZE;
+            let end = ((last + 1) * BMAP_BLOCK_SIZE).min(image_size);
+            let mut hasher = Sha256::new();
+
+            file.seek(SeekFrom::Start(start))?;
+            let mut pos = start;
+            while pos < end {
+                let n = (end - pos).min(IO_CHUNK_SIZE as u64) as usize;
+                file.read_exact(&mut buf[..n])
+                    .context("Bmap::create: cannot read image")?;
+                hasher.update(&buf[..n]);
+                pos += n as u64;
+            }
+
+            ranges.push(BmapRange {
+                first,
+                last,
+                checksum: to_hex(&hasher.finalize()),
+            });
+        }
+
+        Ok(Bmap {
+            image_size,
+            block_size: BMAP_BLOCK_SIZE,
+            ranges,
+        })
+    }
+
+    /// Reads a bmap file of version 2.x as written by bmaptool and verifies its
+    /// checksum.
+    pub fn from_file(bmap_file: &Path) -> Result<Bmap> {
+        let xml = std::fs::read_to_string(bmap_file).context(format!(
+            "Bmap::from_file: cannot read {}",
+            bmap_file.display()
+        ))?;
+
+        Bmap::parse(&xml).context(format!(
+            "Bmap::from_file: invalid bmap file {}",
+            bmap_file.display()
+        ))
+    }
+
+    fn parse(xml: &str) -> Result<Bmap> {
+        let version = Regex::new(r#"<bmap +version *= *"([0-9]+)\.[0-9]+" *>"#)?
+            .captures(xml)
+            .context("bmap version missing")?;
+        anyhow::ensure!(
+            &version[1] == BMAP_MAJOR_VERSION,
+            "unsupported bmap version {}, only {BMAP_MAJOR_VERSION}.x is supported",
+            &version[1]
+        );
+
+        let checksum_type = element(xml, "ChecksumType")?;
+        anyhow::ensure!(
+            checksum_type == CHECKSUM_TYPE,
+            "unsupported checksum type {checksum_type}"
+        );
+
+        let file_checksum = element(xml, "BmapFileChecksum")?;
+        let zeroed = xml.replacen(file_checksum, &"0".repeat(file_checksum.len()), 1);
+        anyhow::ensure!(
+            to_hex(&Sha256::digest(zeroed.as_bytes())) == file_checksum,
+            "checksum mismatch, the file is corrupted"
+        );
+
+        let image_size: u64 = element(xml, "ImageSize")?.parse()?;
+        let block_size: u64 = element(xml, "BlockSize")?.parse()?;
+        anyhow::ensure!(block_size > 0, "invalid block size 0");
+        let blocks_count = image_size.div_ceil(block_size);
+
+        let mut ranges: Vec<BmapRange> = vec![];
+        for c in Regex::new(
+            r#"<Range +chksum *= *"([0-9a-f]+)" *> *([0-9]+)(?: *- *([0-9]+))? *</Range>"#,
+        )?
+        .captures_iter(xml)
+        {
+            let first: u64 = c[2].parse()?;
+            let last = match c.get(3) {
+                Some(last) => last.as_str().parse()?,
+                None => first,
+            };
+            anyhow::ensure!(
+                first <= last
+                    && last < blocks_count
+                    && ranges.last().is_none_or(|r| r.last < first),
+                "invalid block range {first}-{last}"
+            );
+            ranges.push(BmapRange {
+                first,
+                last,
+                checksum: c[1].to_string(),
+            });
+        }
+
+        let bmap = Bmap {
+            image_size,
+            block_size,
+            ranges,
+        };
+        let mapped: u64 = element(xml, "MappedBlocksCount")?.parse()?;
+        anyhow::ensure!(
+            mapped == bmap.mapped_blocks_count(),
+            "{mapped} mapped blocks expected but ranges contain {}",
+            bmap.mapped_blocks_count()
+        );
+
+        Ok(bmap)
+    }
+
+    pub fn blocks_count(&self) -> u64 {
+        self.image_size.div_ceil(self.block_size)
+    }
+
+    pub fn mapped_blocks_count(&self) -> u64 {
+        self.ranges.iter().map(|r| r.last - r.first + 1).sum()
+    }
+
+    /// Writes the block map as XML in the format of bmaptool version 2.0.
+    pub fn write_to(&self, bmap_file: &Path) -> Result<()> {
+        let xml = self.to_xml(&"0".repeat(CHECKSUM_LEN));
+        // the checksum of the file is calculated with the checksum field set
+        // to zeros
+        let xml = self.to_xml(&to_hex(&Sha256::digest(xml.as_bytes())));
+
+        File::create(bmap_file)
+            .and_then(|mut f| f.write_all(xml.as_bytes()))
+            .context(format!(
+                "Bmap::write_to: cannot write {}",
+                bmap_file.display()
+            ))
+    }
+
+    fn to_xml(&self, file_checksum: &str) -> String {
+        let mapped = self.mapped_blocks_count();
+        let percent = if self.blocks_count() == 0 {
+            0.0
+        } else {
+            mapped as f64 * 100.0 / self.blocks_count() as f64
+        };
+
+        let mut xml = format!(
+            r#"<?xml version="1.0" ?>
+<!-- This file contains the block map for an image file, which is basically
+     a list of useful (mapped) block numbers in the image file. In other words,
+     it lists only those blocks which contain data (boot sector, partition
+     table, file-system metadata, files, directories, extents, etc). These
+     blocks have to be copied to the target device. The other blocks do not
+     contain any useful data and do not have to be copied to the target
+     device. -->
+
+<bmap version="{BMAP_VERSION}">
+    <!-- Image size in bytes: {image_size_human} -->
+    <ImageSize> {image_size} </ImageSize>
+
+    <!-- Size of a block in bytes -->
+    <BlockSize> {block_size} </BlockSize>
+
+    <!-- Count of blocks in the image file -->
+    <BlocksCount> {blocks_count} </BlocksCount>
+
+    <!-- Count of mapped blocks: {mapped_human} or {percent:.1}% -->
+    <MappedBlocksCount> {mapped} </MappedBlocksCount>
+
+    <!-- Type of checksum used in this file -->
+    <ChecksumType> {CHECKSUM_TYPE} </ChecksumType>
+
+    <!-- The checksum of this bmap file. When it is calculated, the value of
+         the checksum has be zero (all ASCII "0" symbols). -->
+    <BmapFileChecksum> {file_checksum} </BmapFileChecksum>
+
+    <!-- The block map which consists of elements which may either be a
+         range of blocks or a single block. The 'chksum' attribute
+         (if present) is the checksum of this blocks range. -->
+    <BlockMap>
+"#,
+            image_size_human = human_size(self.image_size),
+            image_size = self.image_size,
+            block_size = self.block_size,
+            blocks_count = self.blocks_count(),
+            mapped_human = human_size(mapped * self.block_size),
+        );
+
+        for r in &self.ranges {
+            let blocks = if r.first == r.last {
+                r.first.to_string()
+            } else {
+                format!("{}-{}", r.first, r.last)
+            };
+            let _ = writeln!(
+                xml,
+                r#"        <Range chksum="{}"> {blocks} </Range>"#,
+                r.checksum
+            );
+        }
+
+        xml.push_str("    </BlockMap>\n</bmap>\n");
+        xml
+    }
+}
+
+// content of the first element `tag` without surrounding whitespace
+fn element<'a>(xml: &'a str, tag: &str) -> Result<&'a str> {
+    let start = format!("<{tag}>");
+    let end = format!("</{tag}>");
+
+    xml.split_once(&start)
+        .and_then(|(_, rest)| rest.split_once(&end))
+        .map(|(value, _)| value.trim())
+        .context(format!("element {tag} missing"))
+}
+
+// Returns the inclusive ranges of blocks containing data according to
+// SEEK_DATA/SEEK_HOLE. Adjacent ranges are merged. If the filesystem doesn't
+// support these, the whole file is considered as mapped.
+fn mapped_blocks(file: &File, size: u64, block_size: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut offset = 0;
+
+    while offset < size {
+        let data = match seek(file, offset, libc::SEEK_DATA) {
+            Ok(data) => data,
+            // no more data behind offset
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                debug!("mapped_blocks: SEEK_DATA not supported: {e}");
+                return Ok(vec![(0, size.div_ceil(block_size) - 1)]);
+            }
+            Err(e) => return Err(e),
+        };
+        let hole = seek(file, data, libc::SEEK_HOLE)?.min(size);
+
+        let first = data / block_size;
+        let last = (hole - 1) / block_size;
+
+        match ranges.last_mut() {
+            Some((_, prev_last)) if first <= *prev_last + 1 => *prev_last = last,
+            _ => ranges.push((first, last)),
+        }
+
+        offset = hole;
+    }
+
+    Ok(ranges)
+}
+
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    // SAFETY: lseek only operates on the given file descriptor which stays
+    // valid for the lifetime of `file`
+    let res = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(res as u64)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} bytes")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bmap_of_sparse_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let bmap_file = dir.path().join("image.wic.bmap");
+
+        // data in blocks 0, 1 and 5, a partial block 10 at the end
+        let mut file = File::create(&image).unwrap();
+        file.write_all(&[1; 2 * BMAP_BLOCK_SIZE as usize]).unwrap();
+        file.seek(SeekFrom::Start(5 * BMAP_BLOCK_SIZE)).unwrap();
+        file.write_all(&[2; BMAP_BLOCK_SIZE as usize]).unwrap();
+        file.seek(SeekFrom::Start(10 * BMAP_BLOCK_SIZE)).unwrap();
+        file.write_all(&[3; 100]).unwrap();
+        drop(file);
+
+        let bmap = Bmap::create(&image).unwrap();
+        assert_eq!(bmap.image_size, 10 * BMAP_BLOCK_SIZE + 100);
+        assert_eq!(bmap.blocks_count(), 11);
+
+        // filesystems without holes map everything
+        let ranges: Vec<_> = bmap.ranges.iter().map(|r| (r.first, r.last)).collect();
+        if ranges.len() > 1 {
+            assert_eq!(ranges, vec![(0, 1), (5, 5), (10, 10)]);
+            assert_eq!(bmap.ranges[2].checksum, to_hex(&Sha256::digest([3; 100])));
+        }
+
+        bmap.write_to(&bmap_file).unwrap();
+        let xml = std::fs::read_to_string(&bmap_file).unwrap();
+        assert!(xml.contains("<ImageSize> 41060 </ImageSize>"));
+        assert!(xml.contains("<BlocksCount> 11 </BlocksCount>"));
+
+        // the file checksum is calculated over the file with zeroed checksum
+        let checksum = bmap.to_xml(&"0".repeat(CHECKSUM_LEN));
+        let checksum = to_hex(&Sha256::digest(checksum.as_bytes()));
+        assert!(xml.contains(&format!(
+            "<BmapFileChecksum> {checksum} </BmapFileChecksum>"
+        )));
+    }
+
+    #[test]
+    fn bmap_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let bmap_file = dir.path().join("image.wic.bmap");
+        let bmap = Bmap {
+            image_size: 3 * BMAP_BLOCK_SIZE + 1,
+            block_size: BMAP_BLOCK_SIZE,
+            ranges: vec![
+                BmapRange {
+                    first: 0,
+                    last: 1,
+                    checksum: "0".repeat(CHECKSUM_LEN),
+                },
+                BmapRange {
+                    first: 3,
+                    last: 3,
+                    checksum: "f".repeat(CHECKSUM_LEN),
+                },
+            ],
+        };
+
+        bmap.write_to(&bmap_file).unwrap();
+        assert_eq!(Bmap::from_file(&bmap_file).unwrap(), bmap);
+
+        // any modification invalidates the file checksum
+        let xml = std::fs::read_to_string(&bmap_file).unwrap();
+        let modified = xml.replace("> 3 </Range>", "> 2 </Range>");
+        assert!(Bmap::parse(&modified).is_err());
+
+        // ranges beyond the image
+        let mut invalid = bmap.clone();
+        invalid.ranges[1].first = 4;
+        invalid.ranges[1].last = 4;
+        invalid.write_to(&bmap_file).unwrap();
+        assert!(Bmap::from_file(&bmap_file).is_err());
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(100), "100 bytes");
+        assert_eq!(human_size(4 * 1024 * 1024), "4.0 MiB");
+        assert_eq!(human_size(1536), "1.5 KiB");
+    }
+}
@@ -1,69 +1,180 @@
 use anyhow::{Context, Result};
-use filemagic::Magic;
-use log::debug;
-use std::env;
+use log::{debug, warn};
+use std::fmt::{self, Display};
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::discriminant;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+// length of the longest magic number, i.e. the one of xz
+const MAGIC_LEN: usize = 6;
+// size of the independently compressed blocks of parallel gzip and bzip2
+const PARALLEL_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+// size of the independently compressed xz blocks, which allow reading
+// compressed images without decompressing them as a whole
+const XZ_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+// granularity of holes in decompressed images
+const SPARSE_BLOCK_SIZE: usize = 4096;
+
 #[derive(Clone, Debug, EnumIter)]
 #[allow(non_camel_case_types)]
 pub enum Compression {
     xz { compression_level: u32 },
-    bzip2,
-    gzip,
+    bzip2 { compression_level: u32 },
+    gzip { compression_level: u32 },
+    zstd { compression_level: u32 },
 }
 
 impl FromStr for Compression {
     type Err = anyhow::Error;
 
     fn from_str(input: &str) -> Result<Compression> {
-        match input {
-            "xz" => {
-                let level = env::var("XZ_COMPRESSION_LEVEL")
-                    .unwrap_or_else(|_| "9".to_string())
-                    .parse()
-                    .unwrap_or(9);
+        let compression = match input {
+            "xz" => Compression::xz {
+                compression_level: 0,
+            },
+            "bzip2" => Compression::bzip2 {
+                compression_level: 0,
+            },
+            "gzip" => Compression::gzip {
+                compression_level: 0,
+            },
+            "zstd" | "zst" => Compression::zstd {
+                compression_level: 0,
+            },
+            _ => anyhow::bail!("unknown compression: use either xz, bzip2, gzip or zstd"),
+        };
 
-                let level = if (0..=9).contains(&level) { level } else { 4 };
+        Ok(compression.with_default_level())
+    }
+}
 
-                Ok(Compression::xz {
-                    compression_level: level,
-                })
-            }
-            "bzip2" => Ok(Compression::bzip2),
-            "gzip" => Ok(Compression::gzip),
-            _ => anyhow::bail!("unknown compression: use either xz, bzip2 or gzip"),
-        }
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match &self {
+            Compression::zstd { .. } => "zstd",
+            _ => self.extension(),
+        };
+        write!(f, "{name}")
     }
 }
 
 impl Compression {
+    /// Returns the compression with `level` if it is valid for the algorithm.
+    pub fn with_level(mut self, level: u32) -> Result<Compression> {
+        let levels = self.levels();
+        anyhow::ensure!(
+            levels.contains(&level),
+            "invalid compression level {level} for {self}: use {} to {}",
+            levels.start(),
+            levels.end()
+        );
+
+        self.set_level(level);
+        Ok(self)
+    }
+
+    fn with_default_level(mut self) -> Compression {
+        self.set_level(self.default_level());
+        self
+    }
+
+    fn set_level(&mut self, level: u32) {
+        match self {
+            Compression::xz { compression_level }
+            | Compression::bzip2 { compression_level }
+            | Compression::gzip { compression_level }
+            | Compression::zstd { compression_level } => *compression_level = level,
+        }
+    }
+
+    fn levels(&self) -> RangeInclusive<u32> {
+        match &self {
+            Compression::xz { .. } | Compression::gzip { .. } => 0..=9,
+            Compression::bzip2 { .. } => 1..=9,
+            Compression::zstd { .. } => 1..=22,
+        }
+    }
+
+    // best compression, for zstd without the memory hungry ultra levels
+    fn default_level(&self) -> u32 {
+        match &self {
+            Compression::xz { .. } | Compression::bzip2 { .. } | Compression::gzip { .. } => 9,
+            Compression::zstd { .. } => 19,
+        }
+    }
+
+    /// Compresses `source` into `destination` using up to `threads` threads.
+    /// gzip and bzip2 compress blocks of the source independently in parallel,
+    /// the result is a sequence of members respectively streams which
+    /// standard decompressors read as one file. xz images always consist of
+    /// blocks with an index, which allows random access.
     pub fn compress(
         &self,
         source: &mut std::fs::File,
         destination: &mut std::fs::File,
+        threads: u32,
     ) -> std::io::Result<u64> {
         let mut enc: Box<dyn std::io::Write> = match &self {
-            Compression::bzip2 => Box::new(bzip2::write::BzEncoder::new(
+            Compression::bzip2 { compression_level } if threads > 1 => {
+                let level = bzip2::Compression::new(*compression_level);
+                return compress_parallel(
+                    source,
+                    destination,
+                    threads,
+                    PARALLEL_BLOCK_SIZE,
+                    |block| {
+                        let mut enc = bzip2::write::BzEncoder::new(Vec::new(), level);
+                        enc.write_all(block)?;
+                        enc.finish()
+                    },
+                );
+            }
+            Compression::gzip { compression_level } if threads > 1 => {
+                let level = flate2::Compression::new(*compression_level);
+                return compress_parallel(
+                    source,
+                    destination,
+                    threads,
+                    PARALLEL_BLOCK_SIZE,
+                    |block| {
+                        let mut enc = flate2::write::GzEncoder::new(Vec::new(), level);
+                        enc.write_all(block)?;
+                        enc.finish()
+                    },
+                );
+            }
+            Compression::bzip2 { compression_level } => Box::new(bzip2::write::BzEncoder::new(
                 destination,
-                bzip2::Compression::best(),
+                bzip2::Compression::new(*compression_level),
             )),
-            Compression::gzip => Box::new(flate2::write::GzEncoder::new(
+            Compression::gzip { compression_level } => Box::new(flate2::write::GzEncoder::new(
                 destination,
-                flate2::Compression::best(),
+                flate2::Compression::new(*compression_level),
             )),
             Compression::xz {
                 compression_level: level,
             } => {
                 let stream = xz2::stream::MtStreamBuilder::new()
-                    .threads(num_cpus::get() as u32)
+                    .threads(threads)
                     .preset(*level)
+                    .block_size(XZ_BLOCK_SIZE)
                     .encoder()?;
                 Box::new(xz2::write::XzEncoder::new_stream(destination, stream))
             }
+            Compression::zstd { compression_level } => {
+                let mut enc =
+                    zstd::stream::write::Encoder::new(destination, *compression_level as i32)?;
+                if threads > 1 {
+                    enc.multithread(threads)?;
+                }
+                Box::new(enc.auto_finish())
+            }
         };
 
         let bytes_written = std::io::copy(source, &mut enc)?;
@@ -71,89 +182,393 @@ impl Compression {
         Ok(bytes_written)
     }
 
+    /// Decompresses `source` into `destination`. Blocks of zeros are skipped,
+    /// so that `destination` stays sparse.
     pub fn decompress(
         &self,
         source: &mut std::fs::File,
         destination: &mut std::fs::File,
     ) -> std::io::Result<u64> {
-        let mut dec: Box<dyn std::io::Write> = match &self {
-            Compression::bzip2 => Box::new(bzip2::write::BzDecoder::new(destination)),
-            Compression::gzip => Box::new(flate2::write::GzDecoder::new(destination)),
-            Compression::xz { .. } => Box::new(xz2::write::XzDecoder::new(destination)),
-        };
+        copy_sparse(&mut self.decoder(source)?, destination)
+    }
 
-        let bytes_written = std::io::copy(source, &mut dec)?;
-        dec.write_all(&[])?;
-        dec.flush()?;
-        Ok(bytes_written)
+    /// Returns a reader which decompresses `source` on the fly.
+    pub fn decoder<'a>(&self, source: impl Read + 'a) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match &self {
+            Compression::bzip2 { .. } => Box::new(bzip2::read::MultiBzDecoder::new(source)),
+            Compression::gzip { .. } => Box::new(flate2::read::MultiGzDecoder::new(source)),
+            Compression::xz { .. } => Box::new(xz2::read::XzDecoder::new_multi_decoder(source)),
+            Compression::zstd { .. } => Box::new(zstd::stream::read::Decoder::new(source)?),
+        })
     }
 
-    fn marker(&self) -> &'static str {
+    fn magic(&self) -> &'static [u8] {
         match &self {
-            Compression::bzip2 => "bzip2 compressed data",
-            Compression::gzip => "gzip compressed data",
-            Compression::xz { .. } => "XZ compressed data",
+            Compression::bzip2 { .. } => b"BZh",
+            Compression::gzip { .. } => &[0x1f, 0x8b],
+            Compression::xz { .. } => &[0xfd, b'7', b'z', b'X', b'Z', 0x00],
+            Compression::zstd { .. } => &[0x28, 0xb5, 0x2f, 0xfd],
+        }
+    }
+
+    // all file extensions commonly used for the compression
+    fn extensions(&self) -> &'static [&'static str] {
+        match &self {
+            Compression::bzip2 { .. } => &["bzip2", "bz2"],
+            Compression::gzip { .. } => &["gzip", "gz"],
+            Compression::xz { .. } => &["xz"],
+            Compression::zstd { .. } => &["zst", "zstd"],
         }
     }
 
     fn extension(&self) -> &'static str {
         match &self {
-            Compression::bzip2 => "bzip2",
-            Compression::gzip => "gzip",
+            Compression::bzip2 { .. } => "bzip2",
+            Compression::gzip { .. } => "gzip",
             Compression::xz { .. } => "xz",
+            Compression::zstd { .. } => "zst",
         }
     }
 
-    pub fn from_file(image_file_name: &PathBuf) -> Result<Option<Compression>> {
-        let detector = Magic::open(Default::default())
-            .context("image::compression: failed to open libmagic")?;
-
-        detector
-            .load::<String>(&[])
-            .context("image::compression: failed to load libmagic")?;
+    /// Detects the compression of a file by its magic bytes. A warning is
+    /// logged if the file extension suggests a different compression.
+    pub fn from_file(image_file_name: &Path) -> Result<Option<Compression>> {
+        let mut header = [0u8; MAGIC_LEN];
+        let mut file =
+            File::open(image_file_name).context("image::compression: failed to open image")?;
+        let n = read_full(&mut file, &mut header)
+            .context("image::compression: failed to read image")?;
 
-        let magic = detector
-            .file(image_file_name)
-            .context("image::compression: failed to open image")?;
+        let detected = Compression::from_magic(&header[..n]);
+        let by_extension = Compression::from_extension(image_file_name);
 
-        for c in Compression::iter() {
-            if magic.contains(c.marker()) {
-                return Ok(Some(c));
-            }
+        if detected.as_ref().map(discriminant) != by_extension.as_ref().map(discriminant) {
+            let describe = |c: &Option<Compression>| {
+                c.as_ref()
+                    .map_or("uncompressed".to_string(), |c| format!("{c} compressed"))
+            };
+            warn!(
+                "{} is {} but its extension suggests {}",
+                image_file_name.display(),
+                describe(&detected),
+                describe(&by_extension)
+            );
         }
 
-        Ok(None)
+        Ok(detected)
+    }
+
+    fn from_magic(header: &[u8]) -> Option<Compression> {
+        Compression::iter()
+            .find(|c| header.starts_with(c.magic()))
+            .map(Compression::with_default_level)
     }
+
+    /// Returns the compression suggested by the extension of a file name.
+    pub(crate) fn from_extension(image_file_name: &Path) -> Option<Compression> {
+        let extension = image_file_name.extension()?.to_str()?;
+
+        Compression::iter()
+            .find(|c| c.extensions().contains(&extension))
+            .map(Compression::with_default_level)
+    }
+}
+
+/// Streams the decompressed `image_file_name` into `destination`.
+pub fn decompress(
+    image_file_name: &Path,
+    destination: &Path,
+    compression: &Compression,
+) -> Result<()> {
+    let mut source = File::open(image_file_name).context(format!(
+        "decompress: cannot open {}",
+        image_file_name.display()
+    ))?;
+    let mut destination_file = File::create(destination).context(format!(
+        "decompress: cannot create {}",
+        destination.display()
+    ))?;
+    debug!("decompress {image_file_name:?} to {destination:?}");
+    let bytes_written = compression
+        .decompress(&mut source, &mut destination_file)
+        .context(format!(
+            "decompress: cannot decompress {}",
+            image_file_name.display()
+        ))?;
+    debug!("image::decompress: copied {} bytes.", bytes_written);
+    Ok(())
 }
 
-pub fn decompress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
-    let mut new_image_file = PathBuf::from(image_file_name);
+/// Streams the compressed `image_file_name` into `destination`.
+pub fn compress(
+    image_file_name: &Path,
+    destination: &Path,
+    compression: &Compression,
+    threads: u32,
+) -> Result<()> {
+    let mut source = File::open(image_file_name).context(format!(
+        "compress: cannot open {}",
+        image_file_name.display()
+    ))?;
+    let mut destination_file = File::create(destination)
+        .context(format!("compress: cannot create {}", destination.display()))?;
+    debug!("compress {image_file_name:?} to {destination:?}");
+    let bytes_written = compression
+        .compress(&mut source, &mut destination_file, threads)
+        .context(format!(
+            "compress: cannot compress {}",
+            image_file_name.display()
+        ))?;
+    debug!("image::compress: copied {} bytes.", bytes_written);
+    Ok(())
+}
 
-    if new_image_file
+/// Name of the image inside of a compressed file, i.e. without the
+/// compression extension if there is one.
+pub fn decompressed_file_name(image_file_name: &Path, compression: &Compression) -> PathBuf {
+    let mut name = PathBuf::from(image_file_name.file_name().unwrap_or_default());
+    if name
         .extension()
-        .is_some_and(|ext| ext == compression.extension())
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| compression.extensions().contains(&ext))
     {
-        new_image_file.set_extension("");
+        name.set_extension("");
     }
+    name
+}
 
-    let mut destination = File::create(&new_image_file)?;
-    let mut source = File::open(image_file_name)?;
-    debug!("decompress {image_file_name:?} to {new_image_file:?}");
-    let bytes_written = compression.decompress(&mut source, &mut destination)?;
-    debug!("image::decompress: copied {} bytes.", bytes_written);
-    Ok(new_image_file)
+/// Name of `image_file_name` compressed with `compression`.
+pub fn compressed_file_name(image_file_name: &Path, compression: &Compression) -> PathBuf {
+    let mut name = image_file_name.as_os_str().to_os_string();
+    name.push(".");
+    name.push(compression.extension());
+    PathBuf::from(name)
 }
 
-pub fn compress(image_file_name: &PathBuf, compression: &Compression) -> Result<PathBuf> {
-    let new_image_file = PathBuf::from(format!(
-        "{}.{}",
-        image_file_name.to_str().unwrap(),
-        compression.extension()
-    ));
-    let mut destination = File::create(&new_image_file)?;
-    let mut source = File::open(image_file_name)?;
-    debug!("compress {image_file_name:?} to {new_image_file:?}");
-    let bytes_written = compression.compress(&mut source, &mut destination)?;
-    debug!("image::compress: copied {} bytes.", bytes_written);
-    Ok(new_image_file)
+// Reads `source` in blocks of `block_size`, compresses up to `threads` blocks
+// at a time in parallel with `compress_block` and writes the results in order.
+fn compress_parallel<F>(
+    source: &mut dyn Read,
+    destination: &mut dyn Write,
+    threads: u32,
+    block_size: usize,
+    compress_block: F,
+) -> io::Result<u64>
+where
+    F: Fn(&[u8]) -> io::Result<Vec<u8>> + Sync,
+{
+    let mut bytes_read = 0u64;
+    let mut eof = false;
+
+    while !eof {
+        let mut blocks = vec![];
+        while blocks.len() < threads as usize && !eof {
+            let mut block = vec![0u8; block_size];
+            let n = read_full(source, &mut block)?;
+            block.truncate(n);
+            eof = n < block_size;
+            // an empty source still results in one empty member
+            if n > 0 || bytes_read == 0 {
+                blocks.push(block);
+            }
+            bytes_read += n as u64;
+        }
+
+        let compressed = std::thread::scope(|s| {
+            let handles: Vec<_> = blocks
+                .iter()
+                .map(|block| s.spawn(|| compress_block(block)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| {
+                    h.join()
+                        .unwrap_or_else(|_| Err(io::Error::other("compression thread panicked")))
+                })
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+
+        for c in compressed {
+            destination.write_all(&c)?;
+        }
+    }
+
+    destination.flush()?;
+    Ok(bytes_read)
+}
+
+// Copies `source` to `destination` without writing blocks which only contain
+// zeros, the destination is extended to the full length at the end.
+fn copy_sparse(source: &mut dyn Read, destination: &mut File) -> io::Result<u64> {
+    let mut buf = vec![0u8; IO_CHUNK_SIZE];
+    let mut pos = 0u64;
+
+    loop {
+        let n = read_full(source, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        for block in buf[..n].chunks(SPARSE_BLOCK_SIZE) {
+            if block.iter().any(|b| *b != 0) {
+                destination.seek(SeekFrom::Start(pos))?;
+                destination.write_all(block)?;
+            }
+            pos += block.len() as u64;
+        }
+    }
+
+    destination.set_len(pos)?;
+    Ok(pos)
+}
+
+// like read_exact, but a short read is only returned at the end of the stream
+fn read_full(source: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match source.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_levels() {
+        let c = |s: &str| Compression::from_str(s).unwrap();
+
+        assert!(matches!(
+            c("xz"),
+            Compression::xz {
+                compression_level: 9
+            }
+        ));
+        assert!(matches!(
+            c("zst"),
+            Compression::zstd {
+                compression_level: 19
+            }
+        ));
+        assert_eq!(c("zstd").to_string(), "zstd");
+        assert_eq!(c("bzip2").to_string(), "bzip2");
+
+        assert!(c("xz").with_level(0).is_ok());
+        assert!(c("xz").with_level(10).is_err());
+        assert!(c("bzip2").with_level(0).is_err());
+        assert!(c("gzip").with_level(9).is_ok());
+        assert!(c("zstd").with_level(22).is_ok());
+        assert!(c("zstd").with_level(23).is_err());
+        assert!(Compression::from_str("lz4").is_err());
+    }
+
+    #[test]
+    fn compression_detection() {
+        let dir = tempfile::tempdir().unwrap();
+        let detect = |name: &str, content: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            Compression::from_file(&path)
+                .unwrap()
+                .map(|c| c.to_string())
+        };
+
+        let xz = [0xfd, b'7', b'z', b'X', b'Z', 0x00, 0x00];
+        assert_eq!(detect("image.wic.xz", &xz).as_deref(), Some("xz"));
+        assert_eq!(
+            detect("image.wic.gz", &[0x1f, 0x8b, 8]).as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(
+            detect("image.wic.bz2", b"BZh91AY").as_deref(),
+            Some("bzip2")
+        );
+        assert_eq!(
+            detect("image.wic.zst", &[0x28, 0xb5, 0x2f, 0xfd, 0]).as_deref(),
+            Some("zstd")
+        );
+        assert_eq!(detect("image.wic", &[0; 512]), None);
+        assert_eq!(detect("empty.wic", &[]), None);
+
+        // the content wins over a misleading extension
+        assert_eq!(detect("image.wic.gz", &xz).as_deref(), Some("xz"));
+        assert_eq!(detect("image.wic.xz", &[0; 512]), None);
+    }
+
+    #[test]
+    fn decompress_sparse() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let compressed = dir.path().join("image.wic.gzip");
+        let decompressed = dir.path().join("decompressed.wic");
+
+        let mut data = vec![0u8; 3 * IO_CHUNK_SIZE + 100];
+        data[10] = 1;
+        data[2 * IO_CHUNK_SIZE + SPARSE_BLOCK_SIZE] = 2;
+        std::fs::write(&image, &data).unwrap();
+
+        let c = Compression::from_str("gzip").unwrap();
+        assert_eq!(compressed_file_name(&image, &c), compressed);
+        for name in ["image.wic.gzip", "image.wic.gz", "image.wic"] {
+            assert_eq!(
+                decompressed_file_name(Path::new(name), &c),
+                PathBuf::from("image.wic")
+            );
+        }
+
+        compress(&image, &compressed, &c, 1).unwrap();
+        decompress(&compressed, &decompressed, &c).unwrap();
+        assert_eq!(std::fs::read(&decompressed).unwrap(), data);
+    }
+
+    #[test]
+    fn compress_parallel_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("image.wic");
+        let compressed = dir.path().join("image.wic.compressed");
+        let decompressed = dir.path().join("decompressed.wic");
+
+        // small blocks, so that a few of them are compressed in parallel
+        let block_size = 64 * 1024;
+        let data: Vec<u8> = (0..5 * block_size + 100).map(|i| (i % 251) as u8).collect();
+
+        for c in ["gzip", "bzip2"] {
+            let c = Compression::from_str(c).unwrap().with_level(1).unwrap();
+            let compress_block = |block: &[u8]| match c {
+                Compression::gzip { .. } => {
+                    let mut enc =
+                        flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                    enc.write_all(block)?;
+                    enc.finish()
+                }
+                _ => {
+                    let mut enc =
+                        bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                    enc.write_all(block)?;
+                    enc.finish()
+                }
+            };
+
+            for data in [&data[..], &[]] {
+                let mut file = File::create(&compressed).unwrap();
+                let n = compress_parallel(&mut &data[..], &mut file, 2, block_size, compress_block)
+                    .unwrap();
+                assert_eq!(n, data.len() as u64);
+                decompress(&compressed, &decompressed, &c).unwrap();
+                assert_eq!(std::fs::read(&decompressed).unwrap(), data);
+            }
+
+            // compress() takes the same path with the default block size
+            std::fs::write(&image, &data).unwrap();
+            compress(&image, &compressed, &c, 2).unwrap();
+            decompress(&compressed, &decompressed, &c).unwrap();
+            assert_eq!(std::fs::read(&decompressed).unwrap(), data);
+        }
+    }
 }
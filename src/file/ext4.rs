@@ -0,0 +1,2750 @@
+use super::filesystem::{
+    FileAttributes, FileType, Filesystem, Metadata, ReadLink, Usage, io_error,
+};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_MAGIC: u16 = 0xEF53;
+const EXTENT_MAGIC: u16 = 0xF30A;
+const ROOT_INO: u32 = 2;
+const RESIZE_INO: u32 = 7;
+const MAX_EXTENT_LEN: u64 = 32768;
+const MAX_NAME_LEN: usize = 255;
+const DIRENT_TAIL_LEN: usize = 12;
+// data is streamed in chunks of this size when reading or writing files
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+
+// groups added by grow() need at least this many blocks besides their metadata
+const MIN_GROUP_DATA_BLOCKS: u64 = 50;
+
+const COMPAT_RESIZE_INODE: u32 = 0x10;
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+const INCOMPAT_CASEFOLD: u32 = 0x20000;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_META_BG
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_INLINE_DATA
+    // only for reading, encrypted files are refused and casefolded directories
+    // are looked up case-sensitively
+    | INCOMPAT_ENCRYPT
+    | INCOMPAT_CASEFOLD;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_BIGALLOC: u32 = 0x200;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+const RO_COMPAT_READONLY: u32 = 0x1000;
+
+const BG_INODE_UNINIT: u16 = 0x1;
+const BG_BLOCK_UNINIT: u16 = 0x2;
+const BG_INODE_ZEROED: u16 = 0x4;
+
+const INODE_ENCRYPT_FL: u32 = 0x800;
+const INODE_INDEX_FL: u32 = 0x1000;
+const INODE_EXTENTS_FL: u32 = 0x80000;
+const INODE_INLINE_DATA_FL: u32 = 0x1000_0000;
+
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+const S_IFIFO: u16 = 0x1000;
+const S_IFCHR: u16 = 0x2000;
+const S_IFBLK: u16 = 0x6000;
+const S_IFSOCK: u16 = 0xC000;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_CHRDEV: u8 = 3;
+const FT_BLKDEV: u8 = 4;
+const FT_FIFO: u8 = 5;
+const FT_SOCK: u8 = 6;
+const FT_SYMLINK: u8 = 7;
+
+const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+pub struct Ext4<D> {
+    dev: D,
+    sb: Vec<u8>,
+    sb_dirty: bool,
+    block_size: u64,
+    groups: Vec<Vec<u8>>,
+    dirty_groups: BTreeSet<u32>,
+    block_bitmaps: HashMap<u32, Vec<u8>>,
+    dirty_block_bitmaps: BTreeSet<u32>,
+    inode_bitmaps: HashMap<u32, Vec<u8>>,
+    dirty_inode_bitmaps: BTreeSet<u32>,
+    // modified metadata blocks are kept back until flush() so that a failing
+    // operation never leaves a half updated filesystem behind, file data is
+    // written to free blocks right away (see write_file() for overwrites)
+    dirty_blocks: HashMap<u64, Vec<u8>>,
+    csum_seed: u32,
+}
+
+struct Inode {
+    ino: u32,
+    raw: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+struct Extent {
+    logical: u64,
+    start: u64,
+    len: u64,
+    uninit: bool,
+}
+
+#[derive(Default)]
+struct BlockMap {
+    extents: Vec<Extent>,
+    // extent tree or indirect blocks which are needed to describe the mapping
+    meta_blocks: Vec<u64>,
+}
+
+struct DirEntry {
+    ino: u32,
+    name: Vec<u8>,
+}
+
+impl<D: Read + Write + Seek> Ext4<D> {
+    pub fn open(mut dev: D) -> Result<Self> {
+        let mut sb = vec![0u8; SUPERBLOCK_SIZE];
+        dev.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))
+            .and_then(|_| dev.read_exact(&mut sb))
+            .context("ext4: cannot read superblock")?;
+
+        anyhow::ensure!(
+            le16(&sb, 0x38) == EXT4_MAGIC,
+            "ext4: invalid superblock magic"
+        );
+
+        let incompat = le32(&sb, 0x60);
+        anyhow::ensure!(
+            incompat & !INCOMPAT_SUPPORTED == 0,
+            "ext4: unsupported incompatible features {:#x}",
+            incompat & !INCOMPAT_SUPPORTED
+        );
+        anyhow::ensure!(
+            incompat & (INCOMPAT_JOURNAL_DEV | INCOMPAT_DIRDATA) == 0,
+            "ext4: external journal devices are not supported"
+        );
+
+        let log_block_size = le32(&sb, 0x18);
+        anyhow::ensure!(
+            log_block_size <= 6,
+            "ext4: invalid block size exponent {log_block_size}"
+        );
+        let block_size = 1024u64 << log_block_size;
+
+        let csum_seed = if incompat & INCOMPAT_CSUM_SEED != 0 {
+            le32(&sb, 0x270)
+        } else {
+            crc32c(!0, &sb[0x68..0x78])
+        };
+
+        let mut fs = Ext4 {
+            dev,
+            sb,
+            sb_dirty: false,
+            block_size,
+            groups: vec![],
+            dirty_groups: BTreeSet::new(),
+            block_bitmaps: HashMap::new(),
+            dirty_block_bitmaps: BTreeSet::new(),
+            inode_bitmaps: HashMap::new(),
+            dirty_inode_bitmaps: BTreeSet::new(),
+            dirty_blocks: HashMap::new(),
+            csum_seed,
+        };
+
+        anyhow::ensure!(
+            fs.blocks_per_group() > 0 && fs.inodes_per_group() > 0,
+            "ext4: invalid group geometry"
+        );
+
+        let group_count = fs.group_count();
+        let desc_size = fs.desc_size();
+        let per_block = (block_size / desc_size as u64) as u32;
+
+        for i in 0..group_count.div_ceil(per_block) {
+            let block = fs.read_block(fs.gdt_block(i))?;
+            for desc in block
+                .chunks(desc_size)
+                .take((group_count - i * per_block) as usize)
+            {
+                let mut raw = desc.to_vec();
+                // we always keep 64 bytes so that the *_hi fields read as zero
+                raw.resize(64, 0);
+                fs.groups.push(raw);
+            }
+        }
+
+        debug!(
+            "ext4: block_size={} groups={} inodes_per_group={} features=({:#x},{:#x},{:#x})",
+            block_size,
+            group_count,
+            fs.inodes_per_group(),
+            fs.compat(),
+            fs.incompat(),
+            fs.ro_compat()
+        );
+
+        Ok(fs)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.incompat() & INCOMPAT_RECOVER == 0,
+            "ext4: filesystem journal needs recovery, please run e2fsck first"
+        );
+        anyhow::ensure!(
+            self.incompat() & INCOMPAT_MMP == 0,
+            "ext4: writing to filesystems with multi-mount protection is not supported"
+        );
+        anyhow::ensure!(
+            self.incompat() & (INCOMPAT_ENCRYPT | INCOMPAT_CASEFOLD) == 0,
+            "ext4: writing to filesystems with encryption or casefolding is not supported"
+        );
+        anyhow::ensure!(
+            self.ro_compat() & (RO_COMPAT_BIGALLOC | RO_COMPAT_READONLY) == 0,
+            "ext4: filesystem features {:#x} prevent writing",
+            self.ro_compat() & (RO_COMPAT_BIGALLOC | RO_COMPAT_READONLY)
+        );
+        anyhow::ensure!(
+            self.incompat() & INCOMPAT_EXTENTS != 0,
+            "ext4: writing to filesystems without extents is not supported"
+        );
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Returns the number of blocks the filesystem would have after growing it
+    /// into `max_blocks` blocks. A trailing group which would be too small to
+    /// hold data is left out.
+    pub fn grow_target(&self, max_blocks: u64) -> Result<u64> {
+        self.check_writable()?;
+        anyhow::ensure!(
+            self.incompat() & INCOMPAT_META_BG == 0 && self.compat() & COMPAT_SPARSE_SUPER2 == 0,
+            "ext4: growing filesystems with meta_bg or sparse_super2 is not supported"
+        );
+
+        let mut blocks = if self.is_64bit() {
+            max_blocks
+        } else {
+            max_blocks.min(u32::MAX as u64)
+        };
+        anyhow::ensure!(
+            blocks > self.blocks_count(),
+            "ext4: filesystem already has {} blocks, cannot grow to {blocks} blocks",
+            self.blocks_count()
+        );
+
+        let last = ((blocks - 1 - self.first_data_block()) / self.blocks_per_group()) as u32;
+        let metadata = self.group_overhead(last) + 2 + self.inode_table_blocks();
+        if last >= self.group_count()
+            && blocks - self.group_first_block(last) < metadata + MIN_GROUP_DATA_BLOCKS
+        {
+            blocks = self.group_first_block(last);
+        }
+
+        // additional descriptor blocks can only take the place of reserved ones
+        let groups = (blocks - self.first_data_block()).div_ceil(self.blocks_per_group());
+        let per_block = self.block_size / self.desc_size() as u64;
+        let gdt_blocks =
+            (self.group_count() as u64).div_ceil(per_block) + le16(&self.sb, 0xCE) as u64;
+        anyhow::ensure!(
+            groups.div_ceil(per_block) <= gdt_blocks,
+            "ext4: growing beyond {} block groups needs more group descriptor blocks than reserved",
+            gdt_blocks * per_block
+        );
+        anyhow::ensure!(
+            groups * self.inodes_per_group() as u64 <= u32::MAX as u64,
+            "ext4: too many inodes for {groups} block groups"
+        );
+
+        Ok(blocks)
+    }
+
+    /// Grows the filesystem to `blocks` blocks as returned by `grow_target()`
+    /// by extending the last group and appending new ones. The device must
+    /// already have the new size. All changes including the superblock and
+    /// descriptor backups are written immediately.
+    pub fn grow(&mut self, blocks: u64) -> Result<()> {
+        anyhow::ensure!(
+            self.grow_target(blocks)? == blocks,
+            "ext4: cannot grow to {blocks} blocks"
+        );
+
+        let old_blocks = self.blocks_count();
+        let old_groups = self.group_count();
+        let last = old_groups - 1;
+        let ipg = self.inodes_per_group();
+        let itable_blocks = self.inode_table_blocks();
+
+        // the bitmap of the last group has to be loaded with the old geometry
+        self.load_block_bitmap(last)?;
+        let old_len = self.blocks_in_group(last);
+
+        set_le32(&mut self.sb, 0x4, blocks as u32);
+        if self.is_64bit() {
+            set_le32(&mut self.sb, 0x150, (blocks >> 32) as u32);
+        }
+        self.sb_dirty = true;
+
+        // 1. turn reserved descriptor blocks into used ones where needed
+        let per_block = self.block_size / self.desc_size() as u64;
+        for i in
+            (old_groups as u64).div_ceil(per_block)..(self.group_count() as u64).div_ceil(per_block)
+        {
+            self.use_reserved_gdt_block(i as u32)?;
+        }
+
+        // 2. extend the last group, if it was a partial one
+        let new_len = self.blocks_in_group(last);
+        if new_len > old_len {
+            let bitmap = self.block_bitmaps.get_mut(&last).unwrap();
+            for bit in old_len..new_len {
+                clear_bit(bitmap, bit);
+            }
+            self.adjust_free_blocks(last, (new_len - old_len) as i64);
+            self.dirty_block_bitmaps.insert(last);
+        }
+
+        // 3. append new groups, each holding its own bitmaps and inode table
+        let mut free_blocks = 0;
+        for g in old_groups..self.group_count() {
+            let first = self.group_first_block(g);
+            let len = self.blocks_in_group(g);
+            let block_bitmap = first + self.group_overhead(g);
+            let inode_bitmap = block_bitmap + 1;
+            let inode_table = block_bitmap + 2;
+            let used = inode_table + itable_blocks - first;
+
+            let mut gd = vec![0u8; 64];
+            set_le32(&mut gd, 0x0, block_bitmap as u32);
+            set_le32(&mut gd, 0x20, (block_bitmap >> 32) as u32);
+            set_le32(&mut gd, 0x4, inode_bitmap as u32);
+            set_le32(&mut gd, 0x24, (inode_bitmap >> 32) as u32);
+            set_le32(&mut gd, 0x8, inode_table as u32);
+            set_le32(&mut gd, 0x28, (inode_table >> 32) as u32);
+            set_le16(&mut gd, 0xC, (len - used) as u16);
+            set_le16(&mut gd, 0x2C, ((len - used) >> 16) as u16);
+            set_le16(&mut gd, 0xE, ipg as u16);
+            set_le16(&mut gd, 0x2E, (ipg >> 16) as u16);
+
+            if self.has_group_csum() {
+                set_le16(&mut gd, 0x12, BG_INODE_UNINIT | BG_INODE_ZEROED);
+                set_le16(&mut gd, 0x1C, ipg as u16);
+                set_le16(&mut gd, 0x32, (ipg >> 16) as u16);
+            }
+
+            // the area might hold data of moved partitions, which must not be
+            // mistaken for inodes (e.g. by e2fsck using a backup superblock)
+            let itable_len = itable_blocks * self.block_size;
+            let zero = vec![0u8; IO_CHUNK_SIZE.min(itable_len as usize)];
+            let mut offset = 0;
+            while offset < itable_len {
+                let len = (itable_len - offset).min(zero.len() as u64);
+                self.write_at(
+                    inode_table * self.block_size + offset,
+                    &zero[..len as usize],
+                )?;
+                offset += len;
+            }
+
+            self.groups.push(gd);
+            self.dirty_groups.insert(g);
+
+            let mut bitmap = vec![0u8; self.block_size as usize];
+            for bit in (0..used).chain(len..self.block_size * 8) {
+                set_bit(&mut bitmap, bit);
+            }
+            self.block_bitmaps.insert(g, bitmap);
+            self.dirty_block_bitmaps.insert(g);
+
+            let mut bitmap = vec![0u8; self.block_size as usize];
+            for bit in ipg as u64..self.block_size * 8 {
+                set_bit(&mut bitmap, bit);
+            }
+            self.inode_bitmaps.insert(g, bitmap);
+            self.dirty_inode_bitmaps.insert(g);
+
+            if self.has_super(g) {
+                self.add_reserved_gdt_backups(g)?;
+            }
+
+            free_blocks += len - used;
+        }
+
+        // 4. update the totals of the superblock
+        let new_inodes = (self.group_count() - old_groups) * ipg;
+        let inodes = le32(&self.sb, 0x0) + new_inodes;
+        let free_inodes = le32(&self.sb, 0x10) + new_inodes;
+        let free = self.free_blocks_count() + free_blocks;
+        set_le32(&mut self.sb, 0x0, inodes);
+        set_le32(&mut self.sb, 0x10, free_inodes);
+        set_le32(&mut self.sb, 0xC, free as u32);
+
+        // keep the share of blocks reserved for root
+        let reserved = self.reserved_blocks_count() as u128 * blocks as u128 / old_blocks as u128;
+        set_le32(&mut self.sb, 0x8, reserved as u32);
+        if self.is_64bit() {
+            set_le32(&mut self.sb, 0x158, (free >> 32) as u32);
+            set_le32(&mut self.sb, 0x154, (reserved >> 32) as u32);
+        }
+
+        self.flush()?;
+        self.write_backups()?;
+
+        debug!(
+            "ext4: grown from {old_blocks} to {blocks} blocks, {old_groups} to {} groups",
+            self.group_count()
+        );
+
+        Ok(())
+    }
+
+    // Turns the first reserved descriptor block into descriptor block `i`. Its
+    // backups become descriptor backups, so the resize inode must not map any
+    // of them anymore. The block bitmaps stay the same as all of them are in
+    // use either way.
+    fn use_reserved_gdt_block(&mut self, i: u32) -> Result<()> {
+        let reserved = le16(&self.sb, 0xCE);
+        anyhow::ensure!(
+            reserved > 0,
+            "ext4: no reserved group descriptor blocks left"
+        );
+        let block = self.gdt_block(i);
+
+        if self.compat() & COMPAT_RESIZE_INODE != 0 {
+            let mut inode = self.read_inode(RESIZE_INO)?;
+            let dind = le32(inode.i_block(), 13 * 4) as u64;
+            let mut data = self.read_meta_block(dind)?;
+            let index = (i as usize % (self.block_size / 4) as usize) * 4;
+            anyhow::ensure!(
+                le32(&data, index) as u64 == block,
+                "ext4: resize inode doesn't map reserved group descriptor block {block}"
+            );
+            set_le32(&mut data, index, 0);
+            self.write_meta_block(dind, data);
+
+            let backups = self
+                .read_meta_block(block)?
+                .chunks(4)
+                .filter(|b| le32(b, 0) != 0)
+                .count() as u64;
+            inode.set_sectors(inode.sectors() - (backups + 1) * (self.block_size / 512));
+            self.write_inode(&mut inode)?;
+        }
+
+        // descriptors of groups which don't exist yet are zero
+        self.write_meta_block(block, vec![0u8; self.block_size as usize]);
+        set_le16(&mut self.sb, 0xCE, reserved - 1);
+
+        debug!("ext4: using reserved block {block} for group descriptors");
+
+        Ok(())
+    }
+
+    // The reserved descriptor blocks of the primary group double as indirect
+    // blocks of the resize inode, which list their copies in all backup
+    // groups. Adds the copies of the new backup group `g`.
+    fn add_reserved_gdt_backups(&mut self, g: u32) -> Result<()> {
+        let reserved = le16(&self.sb, 0xCE) as u64;
+        if self.compat() & COMPAT_RESIZE_INODE == 0 || reserved == 0 {
+            return Ok(());
+        }
+
+        let per_block = (self.block_size / self.desc_size() as u64) as u32;
+        let first = self.gdt_block(self.group_count().div_ceil(per_block));
+        let index = (1..g).filter(|h| self.has_super(*h)).count();
+        anyhow::ensure!(
+            index < (self.block_size / 4) as usize,
+            "ext4: too many backup groups for the resize inode"
+        );
+
+        for block in first..first + reserved {
+            let mut data = self.read_meta_block(block)?;
+            let backup = block + g as u64 * self.blocks_per_group();
+            set_le32(&mut data, index * 4, backup as u32);
+            self.write_meta_block(block, data);
+        }
+
+        let mut inode = self.read_inode(RESIZE_INO)?;
+        inode.set_sectors(inode.sectors() + reserved * (self.block_size / 512));
+        self.write_inode(&mut inode)
+    }
+
+    // Copies superblock and group descriptors to all groups holding backups.
+    fn write_backups(&mut self) -> Result<()> {
+        let per_block = (self.block_size / self.desc_size() as u64) as u32;
+        let mut gdt = vec![];
+        for i in 0..self.group_count().div_ceil(per_block) {
+            gdt.extend(self.read_block(self.gdt_block(i))?);
+        }
+
+        for g in 1..self.group_count() {
+            if !self.has_super(g) {
+                continue;
+            }
+
+            let first = self.group_first_block(g);
+            let mut sb = self.sb.clone();
+            set_le16(&mut sb, 0x5A, g as u16);
+            if self.has_metadata_csum() {
+                let csum = crc32c(!0, &sb[..0x3FC]);
+                set_le32(&mut sb, 0x3FC, csum);
+            }
+
+            self.write_at(first * self.block_size, &sb)?;
+            self.write_at((first + 1) * self.block_size, &gdt)?;
+        }
+
+        self.dev.flush().context("ext4: cannot flush device")
+    }
+
+    fn mkdir(&mut self, parent: &mut Inode, name: &[u8]) -> Result<Inode> {
+        let ino = self.alloc_inode(self.inode_group(parent.ino), true)?;
+        let mut inode = self.new_inode(ino, S_IFDIR | 0o755)?;
+        let goal = self.group_first_block(self.inode_group(ino));
+        let block = self.alloc_blocks(goal, 1)?[0].0;
+
+        let bs = self.block_size as usize;
+        let usable = self.dir_usable_len();
+        let mut data = vec![0u8; bs];
+        write_dirent(&mut data, 0, ino, 12, b".", FT_DIR);
+        write_dirent(&mut data, 12, parent.ino, usable - 12, b"..", FT_DIR);
+
+        let extents = [Extent {
+            logical: 0,
+            start: block,
+            len: 1,
+            uninit: false,
+        }];
+        self.store_extents(&mut inode, &extents)?;
+        inode.set_size(self.block_size);
+        inode.set_links(2);
+        inode.touch(self.inode_size());
+        self.write_dir_block(&inode, block, &mut data);
+        self.write_inode(&mut inode)?;
+
+        self.add_dir_entry(parent, name, ino, FT_DIR)?;
+        if parent.links() < 65000 {
+            parent.set_links(parent.links() + 1);
+        } else if self.ro_compat() & RO_COMPAT_DIR_NLINK != 0 {
+            parent.set_links(1);
+        }
+        self.write_inode(parent)?;
+
+        debug!(
+            "ext4: created directory {} (inode {ino})",
+            String::from_utf8_lossy(name)
+        );
+
+        Ok(inode)
+    }
+
+    fn new_inode(&mut self, ino: u32, mode: u16) -> Result<Inode> {
+        let inode_size = self.inode_size();
+        let mut inode = Inode {
+            ino,
+            raw: vec![0u8; inode_size],
+        };
+
+        set_le16(&mut inode.raw, 0x0, mode);
+        set_le16(&mut inode.raw, 0x1A, 1);
+
+        if inode_size > 128 {
+            let want = le16(&self.sb, 0x15E).max(le16(&self.sb, 0x15C));
+            let extra = if want == 0 { 32 } else { want };
+            set_le16(&mut inode.raw, 0x80, extra.min(inode_size as u16 - 128));
+        }
+
+        inode.touch(inode_size);
+        if inode.has_extra(0x98) {
+            let (secs, extra) = timestamp_now();
+            set_le32(&mut inode.raw, 0x90, secs);
+            set_le32(&mut inode.raw, 0x94, extra);
+        }
+
+        Ok(inode)
+    }
+
+    fn lookup_path(&mut self, path: &Path) -> Result<u32> {
+        let mut ino = ROOT_INO;
+
+        for name in path_names(path)? {
+            let dir = self.read_inode(ino)?;
+            if !dir.is_dir() {
+                return Err(io_error(
+                    io::ErrorKind::NotADirectory,
+                    format!("ext4: {} is not a directory", path.display()),
+                ));
+            }
+            ino = self.lookup(&dir, name)?.ok_or_else(|| {
+                io_error(
+                    io::ErrorKind::NotFound,
+                    format!("ext4: {} not found", path.display()),
+                )
+            })?;
+        }
+
+        Ok(ino)
+    }
+
+    fn lookup_dir(&mut self, path: &Path) -> Result<Inode> {
+        let ino = self.lookup_path(path)?;
+        let inode = self.read_inode(ino)?;
+        if !inode.is_dir() {
+            return Err(io_error(
+                io::ErrorKind::NotADirectory,
+                format!("ext4: {} is not a directory", path.display()),
+            ));
+        }
+        Ok(inode)
+    }
+
+    fn lookup(&mut self, dir: &Inode, name: &[u8]) -> Result<Option<u32>> {
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .map(|e| e.ino))
+    }
+
+    fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        anyhow::ensure!(
+            dir.flags() & INODE_INLINE_DATA_FL == 0,
+            "ext4: inline directories are not supported (inode {})",
+            dir.ino
+        );
+
+        let bs = self.block_size as usize;
+        let mut entries = vec![];
+
+        for (_, block) in self.block_map(dir)?.blocks() {
+            let data = self.read_meta_block(block)?;
+            let mut off = 0;
+            while off + 8 <= bs {
+                let rec_len = le16(&data, off + 4) as usize;
+                let name_len = data[off + 6] as usize;
+                anyhow::ensure!(
+                    rec_len >= 8 && off + rec_len <= bs && 8 + name_len <= rec_len,
+                    "ext4: corrupted directory entry in inode {}",
+                    dir.ino
+                );
+                let ino = le32(&data, off);
+                if ino != 0 {
+                    entries.push(DirEntry {
+                        ino,
+                        name: data[off + 8..off + 8 + name_len].to_vec(),
+                    });
+                }
+                off += rec_len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn add_dir_entry(&mut self, dir: &mut Inode, name: &[u8], ino: u32, ft: u8) -> Result<()> {
+        anyhow::ensure!(
+            !name.is_empty() && name.len() <= MAX_NAME_LEN,
+            "ext4: invalid file name length {}",
+            name.len()
+        );
+
+        if dir.flags() & INODE_INDEX_FL != 0 {
+            self.unindex_dir(dir)?;
+        }
+
+        let needed = dirent_len(name.len());
+        let usable = self.dir_usable_len();
+
+        for (_, block) in self.block_map(dir)?.blocks() {
+            let mut data = self.read_meta_block(block)?;
+            let mut off = 0;
+            while off < usable {
+                let rec_len = le16(&data, off + 4) as usize;
+                anyhow::ensure!(
+                    rec_len >= 8 && off + rec_len <= usable,
+                    "ext4: corrupted directory entry in inode {}",
+                    dir.ino
+                );
+                let used = if le32(&data, off) == 0 {
+                    0
+                } else {
+                    dirent_len(data[off + 6] as usize)
+                };
+                if rec_len >= used + needed {
+                    if used > 0 {
+                        set_le16(&mut data, off + 4, used as u16);
+                    }
+                    write_dirent(&mut data, off + used, ino, rec_len - used, name, ft);
+                    self.write_dir_block(dir, block, &mut data);
+                    return Ok(());
+                }
+                off += rec_len;
+            }
+        }
+
+        // no space left in the existing blocks, so we append a new one
+        let mut map = self.block_map(dir)?;
+        let next = map.extents.last().map_or(0, |e| e.logical + e.len);
+        let goal = map
+            .extents
+            .last()
+            .map_or(self.group_first_block(self.inode_group(dir.ino)), |e| {
+                e.start + e.len
+            });
+        let block = self.alloc_blocks(goal, 1)?[0].0;
+        let mut data = vec![0u8; self.block_size as usize];
+        write_dirent(&mut data, 0, ino, usable, name, ft);
+        self.write_dir_block(dir, block, &mut data);
+
+        for b in std::mem::take(&mut map.meta_blocks) {
+            self.free_blocks(b, 1)?;
+        }
+        push_extent(&mut map.extents, next, block, 1);
+        self.store_extents(dir, &map.extents)?;
+        dir.set_size((next + 1) * self.block_size);
+        dir.touch(self.inode_size());
+        self.write_inode(dir)
+    }
+
+    // Hashed (htree) directories are converted into linear ones before they are
+    // modified. All entries are stored in leaf blocks anyway, so only the index
+    // blocks have to be turned into regular (empty) directory blocks.
+    fn unindex_dir(&mut self, dir: &mut Inode) -> Result<()> {
+        let bs = self.block_size as usize;
+        let usable = self.dir_usable_len();
+
+        for (logical, block) in self.block_map(dir)?.blocks() {
+            let mut data = self.read_meta_block(block)?;
+            if logical == 0 {
+                let dot_len = le16(&data, 4) as usize;
+                set_le16(&mut data, dot_len + 4, (usable - dot_len) as u16);
+            } else if le32(&data, 0) == 0 && le16(&data, 4) as usize == bs {
+                set_le16(&mut data, 4, usable as u16);
+            } else {
+                continue;
+            }
+            self.write_dir_block(dir, block, &mut data);
+        }
+
+        dir.set_flags(dir.flags() & !INODE_INDEX_FL);
+        debug!("ext4: converted hashed directory {} to linear", dir.ino);
+        self.write_inode(dir)
+    }
+
+    fn write_dir_block(&mut self, dir: &Inode, block: u64, data: &mut [u8]) {
+        if self.has_metadata_csum() {
+            let usable = self.dir_usable_len();
+            let tail = &mut data[usable..];
+            tail.fill(0);
+            set_le16(tail, 4, DIRENT_TAIL_LEN as u16);
+            tail[7] = 0xDE;
+            let csum = crc32c(self.inode_csum_seed(dir), &data[..usable]);
+            set_le32(data, usable + 8, csum);
+        }
+        self.write_meta_block(block, data.to_vec());
+    }
+
+    fn remove_dir_entry(&mut self, dir: &mut Inode, name: &[u8]) -> Result<()> {
+        if dir.flags() & INODE_INDEX_FL != 0 {
+            self.unindex_dir(dir)?;
+        }
+
+        let usable = self.dir_usable_len();
+
+        for (_, block) in self.block_map(dir)?.blocks() {
+            let mut data = self.read_meta_block(block)?;
+            let mut off = 0;
+            let mut prev = None;
+            while off < usable {
+                let rec_len = le16(&data, off + 4) as usize;
+                anyhow::ensure!(
+                    rec_len >= 8 && off + rec_len <= usable,
+                    "ext4: corrupted directory entry in inode {}",
+                    dir.ino
+                );
+                let name_len = data[off + 6] as usize;
+                if le32(&data, off) != 0 && &data[off + 8..off + 8 + name_len] == name {
+                    // the space of the entry is handed to its predecessor, the
+                    // first entry of a block is just marked as unused instead
+                    match prev {
+                        Some(p) => {
+                            let prev_len = le16(&data, p + 4) as usize;
+                            set_le16(&mut data, p + 4, (prev_len + rec_len) as u16);
+                        }
+                        None => set_le32(&mut data, off, 0),
+                    }
+                    self.write_dir_block(dir, block, &mut data);
+                    dir.touch(self.inode_size());
+                    return self.write_inode(dir);
+                }
+                prev = Some(off);
+                off += rec_len;
+            }
+        }
+
+        Err(io_error(
+            io::ErrorKind::NotFound,
+            format!(
+                "ext4: {} not found in directory {}",
+                String::from_utf8_lossy(name),
+                dir.ino
+            ),
+        ))
+    }
+
+    // points the ".." entry of a moved directory to its new parent
+    fn set_parent(&mut self, dir: &Inode, parent: u32) -> Result<()> {
+        let usable = self.dir_usable_len();
+        let Some((_, block)) = self.block_map(dir)?.blocks().into_iter().next() else {
+            anyhow::bail!("ext4: directory {} has no blocks", dir.ino);
+        };
+        let mut data = self.read_meta_block(block)?;
+        let dot_len = le16(&data, 4) as usize;
+        anyhow::ensure!(
+            dot_len + 10 <= usable && &data[dot_len + 8..dot_len + 10] == b"..",
+            "ext4: directory {} has no \"..\" entry",
+            dir.ino
+        );
+        set_le32(&mut data, dot_len, parent);
+        self.write_dir_block(dir, block, &mut data);
+        Ok(())
+    }
+
+    // Removes the entry `name` of `inode` from `dir` and releases the inode
+    // once its last link is gone.
+    fn unlink(&mut self, dir: &mut Inode, name: &[u8], mut inode: Inode) -> Result<()> {
+        self.remove_dir_entry(dir, name)?;
+
+        if inode.is_dir() {
+            // a directory with more than 65000 subdirectories keeps a link
+            // count of 1, which must not be decremented
+            if dir.links() > 2 {
+                dir.set_links(dir.links() - 1);
+                self.write_inode(dir)?;
+            }
+            inode.set_links(0);
+        } else {
+            inode.set_links(inode.links().saturating_sub(1));
+        }
+
+        if inode.links() > 0 {
+            inode.touch(self.inode_size());
+            return self.write_inode(&mut inode);
+        }
+
+        self.release_inode(&mut inode)
+    }
+
+    fn release_inode(&mut self, inode: &mut Inode) -> Result<()> {
+        if !inode.is_fast_symlink() {
+            self.free_block_map(inode)?;
+        }
+        self.release_xattr_block(inode)?;
+
+        inode.set_sectors(0);
+        inode.touch(self.inode_size());
+        let (secs, _) = timestamp_now();
+        set_le32(&mut inode.raw, 0x14, secs);
+        self.write_inode(inode)?;
+
+        let g = self.inode_group(inode.ino);
+        let index = (inode.ino - 1) % self.inodes_per_group();
+        self.load_inode_bitmap(g)?;
+        let bitmap = self.inode_bitmaps.get_mut(&g).unwrap();
+        if !get_bit(bitmap, index as u64) {
+            warn!("ext4: inode {} is already free", inode.ino);
+            return Ok(());
+        }
+        clear_bit(bitmap, index as u64);
+        self.dirty_inode_bitmaps.insert(g);
+
+        let gd = &mut self.groups[g as usize];
+        let free = combine32(le16(gd, 0xE), le16(gd, 0x2E)) + 1;
+        set_le16(gd, 0xE, free as u16);
+        set_le16(gd, 0x2E, (free >> 16) as u16);
+        if inode.is_dir() {
+            let dirs = combine32(le16(gd, 0x10), le16(gd, 0x30)).saturating_sub(1);
+            set_le16(gd, 0x10, dirs as u16);
+            set_le16(gd, 0x30, (dirs >> 16) as u16);
+        }
+        self.dirty_groups.insert(g);
+
+        let free = le32(&self.sb, 0x10) + 1;
+        set_le32(&mut self.sb, 0x10, free);
+        self.sb_dirty = true;
+
+        debug!("ext4: released inode {}", inode.ino);
+
+        Ok(())
+    }
+
+    // drops the reference of an inode to its extended attribute block
+    fn release_xattr_block(&mut self, inode: &mut Inode) -> Result<()> {
+        let block = (le16(&inode.raw, 0x76) as u64) << 32 | le32(&inode.raw, 0x68) as u64;
+        if block == 0 {
+            return Ok(());
+        }
+
+        let mut data = self.read_meta_block(block)?;
+        anyhow::ensure!(
+            le32(&data, 0) == XATTR_MAGIC,
+            "ext4: corrupted extended attribute block of inode {}",
+            inode.ino
+        );
+
+        let refcount = le32(&data, 4);
+        if refcount <= 1 {
+            self.free_blocks(block, 1)?;
+        } else {
+            set_le32(&mut data, 4, refcount - 1);
+            if self.has_metadata_csum() {
+                set_le32(&mut data, 0x10, 0);
+                let crc = crc32c(self.csum_seed, &block.to_le_bytes());
+                let csum = crc32c(crc, &data);
+                set_le32(&mut data, 0x10, csum);
+            }
+            self.write_meta_block(block, data);
+        }
+
+        set_le32(&mut inode.raw, 0x68, 0);
+        set_le16(&mut inode.raw, 0x76, 0);
+        Ok(())
+    }
+
+    fn dir_usable_len(&self) -> usize {
+        if self.has_metadata_csum() {
+            self.block_size as usize - DIRENT_TAIL_LEN
+        } else {
+            self.block_size as usize
+        }
+    }
+
+    fn read_data(&mut self, inode: &Inode, out: &mut dyn Write) -> Result<u64> {
+        anyhow::ensure!(
+            inode.flags() & INODE_INLINE_DATA_FL == 0,
+            "ext4: inline data is not supported (inode {})",
+            inode.ino
+        );
+        anyhow::ensure!(
+            inode.flags() & INODE_ENCRYPT_FL == 0,
+            "ext4: encrypted files are not supported (inode {})",
+            inode.ino
+        );
+
+        let size = inode.size();
+        let bs = self.block_size;
+        let zeros = vec![0u8; IO_CHUNK_SIZE];
+        let mut buf = vec![0u8; IO_CHUNK_SIZE];
+        let mut pos = 0u64;
+
+        let mut extents = self.block_map(inode)?.extents;
+        extents.sort_by_key(|e| e.logical);
+
+        for e in extents {
+            let start = e.logical * bs;
+            if start >= size {
+                break;
+            }
+            let end = ((e.logical + e.len) * bs).min(size);
+
+            // holes in between extents are read as zeros
+            while pos < start {
+                let n = (start - pos).min(zeros.len() as u64) as usize;
+                out.write_all(&zeros[..n])?;
+                pos += n as u64;
+            }
+
+            while pos < end {
+                let n = (end - pos).min(buf.len() as u64) as usize;
+                if e.uninit {
+                    out.write_all(&zeros[..n])?;
+                } else {
+                    let offset = e.start * bs + (pos - start);
+                    self.read_at(offset, &mut buf[..n])?;
+                    out.write_all(&buf[..n])?;
+                }
+                pos += n as u64;
+            }
+        }
+
+        while pos < size {
+            let n = (size - pos).min(zeros.len() as u64) as usize;
+            out.write_all(&zeros[..n])?;
+            pos += n as u64;
+        }
+
+        Ok(size)
+    }
+
+    fn write_data(&mut self, goal: u64, data: &mut dyn Read, len: u64) -> Result<Vec<Extent>> {
+        let mut extents = vec![];
+        if let Err(e) = self.write_extents(goal, data, len, &mut extents) {
+            // the blocks of a failing write are not left allocated
+            for extent in extents {
+                self.free_blocks(extent.start, extent.len)?;
+            }
+            return Err(e);
+        }
+        Ok(extents)
+    }
+
+    fn write_extents(
+        &mut self,
+        mut goal: u64,
+        data: &mut dyn Read,
+        len: u64,
+        extents: &mut Vec<Extent>,
+    ) -> Result<()> {
+        let bs = self.block_size as usize;
+        let mut buf = vec![0u8; IO_CHUNK_SIZE.max(bs)];
+        let mut logical = 0u64;
+        let mut remaining = len;
+
+        while remaining > 0 {
+            let chunk = remaining.min(buf.len() as u64) as usize;
+            data.read_exact(&mut buf[..chunk])?;
+            let blocks = chunk.div_ceil(bs);
+            buf[chunk..blocks * bs].fill(0);
+
+            // blocks only containing zeros are not allocated but left as holes
+            let is_zero = |i: usize| buf[i * bs..(i + 1) * bs].iter().all(|b| *b == 0);
+            let mut i = 0;
+            while i < blocks {
+                if is_zero(i) {
+                    i += 1;
+                    continue;
+                }
+                let mut j = i + 1;
+                while j < blocks && !is_zero(j) {
+                    j += 1;
+                }
+                let runs = self.alloc_blocks(goal, (j - i) as u64)?;
+                let mut k = i;
+                for (start, n) in &runs {
+                    push_extent(extents, logical + k as u64, *start, *n);
+                    k += *n as usize;
+                }
+                for (start, n) in runs {
+                    let range = i * bs..(i + n as usize) * bs;
+                    self.write_at(start * self.block_size, &buf[range])?;
+                    i += n as usize;
+                    goal = start + n;
+                }
+            }
+
+            logical += blocks as u64;
+            remaining -= chunk as u64;
+        }
+
+        Ok(())
+    }
+
+    fn block_map(&mut self, inode: &Inode) -> Result<BlockMap> {
+        let mut map = BlockMap::default();
+
+        if inode.flags() & INODE_INLINE_DATA_FL != 0 {
+            return Ok(map);
+        }
+
+        if inode.flags() & INODE_EXTENTS_FL != 0 {
+            let root = inode.i_block().to_vec();
+            self.read_extent_node(inode.ino, &root, &mut map)?;
+        } else {
+            let total = inode.size().div_ceil(self.block_size);
+            let per_block = self.block_size / 4;
+            let mut logical = 0u64;
+            for i in 0..15 {
+                if logical >= total {
+                    break;
+                }
+                let level = (i as u32).saturating_sub(11);
+                let ptr = le32(inode.i_block(), i * 4) as u64;
+                if ptr != 0 {
+                    self.read_indirect(ptr, level, logical, total, &mut map)?;
+                }
+                logical += per_block.pow(level);
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn read_extent_node(&mut self, ino: u32, node: &[u8], map: &mut BlockMap) -> Result<()> {
+        anyhow::ensure!(
+            le16(node, 0) == EXTENT_MAGIC,
+            "ext4: corrupted extent tree in inode {ino}"
+        );
+
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        anyhow::ensure!(
+            12 + entries * 12 <= node.len(),
+            "ext4: corrupted extent tree in inode {ino}"
+        );
+
+        for i in 0..entries {
+            let e = &node[12 + i * 12..24 + i * 12];
+            if depth == 0 {
+                let raw_len = le16(e, 4) as u64;
+                let (len, uninit) = if raw_len > MAX_EXTENT_LEN {
+                    (raw_len - MAX_EXTENT_LEN, true)
+                } else {
+                    (raw_len, false)
+                };
+                map.extents.push(Extent {
+                    logical: le32(e, 0) as u64,
+                    start: (le16(e, 6) as u64) << 32 | le32(e, 8) as u64,
+                    len,
+                    uninit,
+                });
+            } else {
+                let leaf = (le16(e, 8) as u64) << 32 | le32(e, 4) as u64;
+                map.meta_blocks.push(leaf);
+                let data = self.read_meta_block(leaf)?;
+                self.read_extent_node(ino, &data, map)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_indirect(
+        &mut self,
+        ptr: u64,
+        level: u32,
+        logical: u64,
+        total: u64,
+        map: &mut BlockMap,
+    ) -> Result<()> {
+        if level == 0 {
+            push_extent(&mut map.extents, logical, ptr, 1);
+            return Ok(());
+        }
+
+        map.meta_blocks.push(ptr);
+        let data = self.read_meta_block(ptr)?;
+        let span = (self.block_size / 4).pow(level - 1);
+
+        for (i, entry) in data.chunks(4).enumerate() {
+            let l = logical + i as u64 * span;
+            if l >= total {
+                break;
+            }
+            let p = le32(entry, 0) as u64;
+            if p != 0 {
+                self.read_indirect(p, level - 1, l, total, map)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes a new extent tree for the given extents into the inode. Blocks of
+    // a previous mapping are not released.
+    fn store_extents(&mut self, inode: &mut Inode, extents: &[Extent]) -> Result<()> {
+        let bs = self.block_size as usize;
+        let per_block = (bs - 12) / 12;
+        let goal = extents
+            .last()
+            .map_or(self.group_first_block(self.inode_group(inode.ino)), |e| {
+                e.start + e.len
+            });
+
+        let mut level: Vec<(u32, [u8; 12])> = extents
+            .iter()
+            .map(|e| {
+                let mut entry = [0u8; 12];
+                set_le32(&mut entry, 0, e.logical as u32);
+                let len = if e.uninit {
+                    e.len + MAX_EXTENT_LEN
+                } else {
+                    e.len
+                };
+                set_le16(&mut entry, 4, len as u16);
+                set_le16(&mut entry, 6, (e.start >> 32) as u16);
+                set_le32(&mut entry, 8, e.start as u32);
+                (e.logical as u32, entry)
+            })
+            .collect();
+        let mut depth = 0u16;
+        let mut meta_blocks = 0u64;
+
+        while level.len() > 4 {
+            let mut next = vec![];
+            for chunk in level.chunks(per_block) {
+                let block = self.alloc_blocks(goal, 1)?[0].0;
+                let mut data = vec![0u8; bs];
+                write_extent_header(&mut data, chunk.len(), per_block, depth);
+                for (i, (_, entry)) in chunk.iter().enumerate() {
+                    data[12 + i * 12..24 + i * 12].copy_from_slice(entry);
+                }
+                if self.has_metadata_csum() {
+                    let tail = 12 + per_block * 12;
+                    let csum = crc32c(self.inode_csum_seed(inode), &data[..tail]);
+                    set_le32(&mut data, tail, csum);
+                }
+                self.write_meta_block(block, data);
+                meta_blocks += 1;
+
+                let mut entry = [0u8; 12];
+                set_le32(&mut entry, 0, chunk[0].0);
+                set_le32(&mut entry, 4, block as u32);
+                set_le16(&mut entry, 8, (block >> 32) as u16);
+                next.push((chunk[0].0, entry));
+            }
+            level = next;
+            depth += 1;
+        }
+
+        let root = inode.i_block_mut();
+        root.fill(0);
+        write_extent_header(root, level.len(), 4, depth);
+        for (i, (_, entry)) in level.iter().enumerate() {
+            root[12 + i * 12..24 + i * 12].copy_from_slice(entry);
+        }
+
+        let data_blocks: u64 = extents.iter().map(|e| e.len).sum();
+        inode.set_sectors((data_blocks + meta_blocks) * (self.block_size / 512));
+        inode.set_flags((inode.flags() | INODE_EXTENTS_FL) & !INODE_INLINE_DATA_FL);
+
+        Ok(())
+    }
+
+    fn free_block_map(&mut self, inode: &Inode) -> Result<()> {
+        let map = self.block_map(inode)?;
+        self.free_mapped_blocks(map)
+    }
+
+    fn free_mapped_blocks(&mut self, map: BlockMap) -> Result<()> {
+        for e in map.extents {
+            self.free_blocks(e.start, e.len)?;
+        }
+        for b in map.meta_blocks {
+            self.free_blocks(b, 1)?;
+        }
+        Ok(())
+    }
+
+    fn alloc_inode(&mut self, goal: u32, is_dir: bool) -> Result<u32> {
+        let group_count = self.group_count();
+        let ipg = self.inodes_per_group();
+        let uninit_bg = self.has_group_csum();
+
+        for g in (0..group_count).map(|i| (goal + i) % group_count) {
+            if self.gd_free_inodes(g) == 0 {
+                continue;
+            }
+
+            self.load_inode_bitmap(g)?;
+            let bitmap = self.inode_bitmaps.get_mut(&g).unwrap();
+            let Some(index) = (0..ipg).find(|i| !get_bit(bitmap, *i as u64)) else {
+                warn!("ext4: group {g} has no free inodes although its descriptor says so");
+                continue;
+            };
+            set_bit(bitmap, index as u64);
+            self.dirty_inode_bitmaps.insert(g);
+
+            let gd = &mut self.groups[g as usize];
+            let flags = le16(gd, 0x12);
+            set_le16(gd, 0x12, flags & !BG_INODE_UNINIT);
+            let free = combine32(le16(gd, 0xE), le16(gd, 0x2E)) - 1;
+            set_le16(gd, 0xE, free as u16);
+            set_le16(gd, 0x2E, (free >> 16) as u16);
+            if is_dir {
+                let dirs = combine32(le16(gd, 0x10), le16(gd, 0x30)) + 1;
+                set_le16(gd, 0x10, dirs as u16);
+                set_le16(gd, 0x30, (dirs >> 16) as u16);
+            }
+            if uninit_bg {
+                let unused = combine32(le16(gd, 0x1C), le16(gd, 0x32));
+                if index >= ipg - unused {
+                    let unused = ipg - index - 1;
+                    set_le16(gd, 0x1C, unused as u16);
+                    set_le16(gd, 0x32, (unused >> 16) as u16);
+                }
+            }
+            self.dirty_groups.insert(g);
+
+            let free = le32(&self.sb, 0x10) - 1;
+            set_le32(&mut self.sb, 0x10, free);
+            self.sb_dirty = true;
+
+            return Ok(g * ipg + index + 1);
+        }
+
+        Err(io_error(
+            io::ErrorKind::StorageFull,
+            "ext4: no free inodes left".to_string(),
+        ))
+    }
+
+    // Allocates `count` blocks as few contiguous runs as possible, starting the
+    // search at `goal`. Returns (start, len) tuples.
+    fn alloc_blocks(&mut self, goal: u64, count: u64) -> Result<Vec<(u64, u64)>> {
+        let group_count = self.group_count();
+        let first_data_block = self.first_data_block();
+        let bpg = self.blocks_per_group();
+        let goal = goal.clamp(first_data_block, self.blocks_count() - 1);
+        let goal_group = ((goal - first_data_block) / bpg) as u32;
+        let mut runs = vec![];
+        let mut remaining = count;
+
+        for i in 0..=group_count {
+            if remaining == 0 {
+                break;
+            }
+            let g = (goal_group + i) % group_count;
+            if self.gd_free_blocks(g) == 0 {
+                continue;
+            }
+
+            self.load_block_bitmap(g)?;
+            let first = self.group_first_block(g);
+            let len = self.blocks_in_group(g);
+            let mut bit = if i == 0 { goal - first } else { 0 };
+            let mut allocated = 0;
+
+            while bit < len && remaining > 0 {
+                let bitmap = &self.block_bitmaps[&g];
+                if get_bit(bitmap, bit) {
+                    bit += 1;
+                    continue;
+                }
+                let mut end = bit + 1;
+                while end < len && end - bit < remaining && !get_bit(bitmap, end) {
+                    end += 1;
+                }
+                let bitmap = self.block_bitmaps.get_mut(&g).unwrap();
+                for b in bit..end {
+                    set_bit(bitmap, b);
+                }
+                runs.push((first + bit, end - bit));
+                allocated += end - bit;
+                remaining -= end - bit;
+                bit = end;
+            }
+
+            if allocated > 0 {
+                self.adjust_free_blocks(g, -(allocated as i64));
+                self.dirty_block_bitmaps.insert(g);
+            }
+        }
+
+        if remaining > 0 {
+            for (start, n) in runs {
+                self.free_blocks(start, n)?;
+            }
+            return Err(io_error(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "ext4: not enough free space, {} bytes missing",
+                    remaining * self.block_size
+                ),
+            ));
+        }
+
+        // merge runs which happen to be adjacent (e.g. when wrapping around)
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, len) in runs {
+            match merged.last_mut() {
+                Some((s, l)) if *s + *l == start => *l += len,
+                _ => merged.push((start, len)),
+            }
+        }
+
+        Ok(merged)
+    }
+
+    fn free_blocks(&mut self, start: u64, len: u64) -> Result<()> {
+        let first_data_block = self.first_data_block();
+        let bpg = self.blocks_per_group();
+
+        anyhow::ensure!(
+            start >= first_data_block && start + len <= self.blocks_count(),
+            "ext4: invalid block range {start}+{len}"
+        );
+
+        let mut block = start;
+        while block < start + len {
+            let g = ((block - first_data_block) / bpg) as u32;
+            let group_end = (self.group_first_block(g) + bpg).min(start + len);
+            self.load_block_bitmap(g)?;
+            let first = self.group_first_block(g);
+            let bitmap = self.block_bitmaps.get_mut(&g).unwrap();
+            let mut freed = 0;
+            for b in block..group_end {
+                if get_bit(bitmap, b - first) {
+                    clear_bit(bitmap, b - first);
+                    freed += 1;
+                } else {
+                    warn!("ext4: block {b} is already free");
+                }
+                self.dirty_blocks.remove(&b);
+            }
+            self.adjust_free_blocks(g, freed);
+            self.dirty_block_bitmaps.insert(g);
+            block = group_end;
+        }
+
+        Ok(())
+    }
+
+    fn adjust_free_blocks(&mut self, g: u32, delta: i64) {
+        let gd = &mut self.groups[g as usize];
+        let free = (combine32(le16(gd, 0xC), le16(gd, 0x2C)) as i64 + delta) as u32;
+        set_le16(gd, 0xC, free as u16);
+        set_le16(gd, 0x2C, (free >> 16) as u16);
+        let flags = le16(gd, 0x12);
+        set_le16(gd, 0x12, flags & !BG_BLOCK_UNINIT);
+        self.dirty_groups.insert(g);
+
+        let free = (self.free_blocks_count() as i64 + delta) as u64;
+        set_le32(&mut self.sb, 0xC, free as u32);
+        if self.is_64bit() {
+            set_le32(&mut self.sb, 0x158, (free >> 32) as u32);
+        }
+        self.sb_dirty = true;
+    }
+
+    fn load_block_bitmap(&mut self, g: u32) -> Result<()> {
+        if self.block_bitmaps.contains_key(&g) {
+            return Ok(());
+        }
+
+        let bitmap = if le16(&self.groups[g as usize], 0x12) & BG_BLOCK_UNINIT != 0 {
+            self.init_block_bitmap(g)
+        } else {
+            self.read_block(self.gd_block_bitmap(g))
+                .with_context(|| format!("ext4: cannot read block bitmap of group {g}"))?
+        };
+
+        self.block_bitmaps.insert(g, bitmap);
+        Ok(())
+    }
+
+    fn load_inode_bitmap(&mut self, g: u32) -> Result<()> {
+        if self.inode_bitmaps.contains_key(&g) {
+            return Ok(());
+        }
+
+        let bitmap = if le16(&self.groups[g as usize], 0x12) & BG_INODE_UNINIT != 0 {
+            let mut bitmap = vec![0u8; self.block_size as usize];
+            for bit in self.inodes_per_group() as u64..self.block_size * 8 {
+                set_bit(&mut bitmap, bit);
+            }
+            bitmap
+        } else {
+            self.read_block(self.gd_inode_bitmap(g))
+                .with_context(|| format!("ext4: cannot read inode bitmap of group {g}"))?
+        };
+
+        self.inode_bitmaps.insert(g, bitmap);
+        Ok(())
+    }
+
+    // Builds the bitmap of a group which has never been used so far: only the
+    // superblock and descriptor backups and metadata of groups which were
+    // placed here (flex_bg) are in use.
+    fn init_block_bitmap(&self, g: u32) -> Vec<u8> {
+        let mut bitmap = vec![0u8; self.block_size as usize];
+        let first = self.group_first_block(g);
+        let bpg = self.blocks_per_group();
+
+        for bit in 0..self.group_overhead(g) {
+            set_bit(&mut bitmap, bit);
+        }
+
+        let itable_blocks = self.inode_table_blocks();
+        for h in 0..self.group_count() {
+            let itable = self.gd_inode_table(h);
+            let blocks = [self.gd_block_bitmap(h), self.gd_inode_bitmap(h)]
+                .into_iter()
+                .chain(itable..itable + itable_blocks);
+            for block in blocks {
+                if block >= first && block < first + bpg {
+                    set_bit(&mut bitmap, block - first);
+                }
+            }
+        }
+
+        for bit in self.blocks_in_group(g)..self.block_size * 8 {
+            set_bit(&mut bitmap, bit);
+        }
+
+        bitmap
+    }
+
+    // number of blocks at the start of a group which are occupied by the
+    // superblock, group descriptors and reserved descriptor blocks
+    fn group_overhead(&self, g: u32) -> u64 {
+        let per_block = (self.block_size / self.desc_size() as u64) as u32;
+        let mut overhead = 0;
+
+        if self.has_super(g) {
+            overhead += 1;
+            if self.incompat() & INCOMPAT_META_BG != 0 {
+                overhead += le32(&self.sb, 0x104) as u64;
+            } else {
+                overhead +=
+                    self.group_count().div_ceil(per_block) as u64 + le16(&self.sb, 0xCE) as u64;
+            }
+        }
+
+        if self.incompat() & INCOMPAT_META_BG != 0
+            && g / per_block >= le32(&self.sb, 0x104)
+            && [0, 1, per_block - 1].contains(&(g % per_block))
+        {
+            overhead += 1;
+        }
+
+        overhead
+    }
+
+    fn has_super(&self, g: u32) -> bool {
+        if g == 0 {
+            return true;
+        }
+        if self.compat() & COMPAT_SPARSE_SUPER2 != 0 {
+            return g == le32(&self.sb, 0x24C) || g == le32(&self.sb, 0x250);
+        }
+        if g <= 1 || self.ro_compat() & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        [3u32, 5, 7].iter().any(|base| {
+            let mut n = *base;
+            while n < g {
+                n *= base;
+            }
+            n == g
+        })
+    }
+
+    fn gdt_block(&self, i: u32) -> u64 {
+        let first = self.first_data_block() + 1;
+        if self.incompat() & INCOMPAT_META_BG == 0 || i < le32(&self.sb, 0x104) {
+            return first + i as u64;
+        }
+        let g = i * (self.block_size / self.desc_size() as u64) as u32;
+        self.group_first_block(g) + u64::from(self.has_super(g))
+    }
+
+    fn gd_checksum(&self, g: u32) -> u16 {
+        let desc_size = self.desc_size();
+        let gd = &self.groups[g as usize][..desc_size];
+
+        if self.has_metadata_csum() {
+            let mut copy = gd.to_vec();
+            set_le16(&mut copy, 0x1E, 0);
+            let crc = crc32c(self.csum_seed, &g.to_le_bytes());
+            (crc32c(crc, &copy) & 0xFFFF) as u16
+        } else if self.ro_compat() & RO_COMPAT_GDT_CSUM != 0 {
+            let mut crc = crc16(!0, &self.sb[0x68..0x78]);
+            crc = crc16(crc, &g.to_le_bytes());
+            crc = crc16(crc, &gd[..0x1E]);
+            if desc_size > 0x20 {
+                crc = crc16(crc, &gd[0x20..]);
+            }
+            crc
+        } else {
+            0
+        }
+    }
+
+    fn read_inode(&mut self, ino: u32) -> Result<Inode> {
+        let (block, offset) = self.inode_location(ino)?;
+        let data = self.read_meta_block(block)?;
+        Ok(Inode {
+            ino,
+            raw: data[offset..offset + self.inode_size()].to_vec(),
+        })
+    }
+
+    fn write_inode(&mut self, inode: &mut Inode) -> Result<()> {
+        if self.has_metadata_csum() {
+            let has_hi = inode.has_extra(0x84);
+            set_le16(&mut inode.raw, 0x7C, 0);
+            if has_hi {
+                set_le16(&mut inode.raw, 0x82, 0);
+            }
+            let csum = crc32c(self.inode_csum_seed(inode), &inode.raw);
+            set_le16(&mut inode.raw, 0x7C, csum as u16);
+            if has_hi {
+                set_le16(&mut inode.raw, 0x82, (csum >> 16) as u16);
+            }
+        }
+
+        let (block, offset) = self.inode_location(inode.ino)?;
+        let mut data = self.read_meta_block(block)?;
+        data[offset..offset + inode.raw.len()].copy_from_slice(&inode.raw);
+        self.write_meta_block(block, data);
+        Ok(())
+    }
+
+    fn inode_location(&self, ino: u32) -> Result<(u64, usize)> {
+        anyhow::ensure!(
+            ino >= 1 && ino <= le32(&self.sb, 0x0),
+            "ext4: invalid inode number {ino}"
+        );
+        let g = self.inode_group(ino);
+        let index = ((ino - 1) % self.inodes_per_group()) as u64;
+        let offset = index * self.inode_size() as u64;
+        Ok((
+            self.gd_inode_table(g) + offset / self.block_size,
+            (offset % self.block_size) as usize,
+        ))
+    }
+
+    fn inode_csum_seed(&self, inode: &Inode) -> u32 {
+        let crc = crc32c(self.csum_seed, &inode.ino.to_le_bytes());
+        crc32c(crc, &le32(&inode.raw, 0x64).to_le_bytes())
+    }
+
+    fn read_meta_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        match self.dirty_blocks.get(&block) {
+            Some(data) => Ok(data.clone()),
+            None => self.read_block(block),
+        }
+    }
+
+    fn write_meta_block(&mut self, block: u64, data: Vec<u8>) {
+        self.dirty_blocks.insert(block, data);
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.block_size as usize];
+        self.read_at(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.dev
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.dev.read_exact(buf))
+            .with_context(|| format!("ext4: cannot read {} bytes at {offset}", buf.len()))
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.dev
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.dev.write_all(buf))
+            .with_context(|| format!("ext4: cannot write {} bytes at {offset}", buf.len()))
+    }
+
+    fn compat(&self) -> u32 {
+        le32(&self.sb, 0x5C)
+    }
+
+    fn incompat(&self) -> u32 {
+        le32(&self.sb, 0x60)
+    }
+
+    fn ro_compat(&self) -> u32 {
+        le32(&self.sb, 0x64)
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.incompat() & INCOMPAT_64BIT != 0
+    }
+
+    fn has_metadata_csum(&self) -> bool {
+        self.ro_compat() & RO_COMPAT_METADATA_CSUM != 0
+    }
+
+    fn has_group_csum(&self) -> bool {
+        self.ro_compat() & (RO_COMPAT_METADATA_CSUM | RO_COMPAT_GDT_CSUM) != 0
+    }
+
+    fn blocks_count(&self) -> u64 {
+        let hi = if self.is_64bit() {
+            le32(&self.sb, 0x150)
+        } else {
+            0
+        };
+        (hi as u64) << 32 | le32(&self.sb, 0x4) as u64
+    }
+
+    fn free_blocks_count(&self) -> u64 {
+        let hi = if self.is_64bit() {
+            le32(&self.sb, 0x158)
+        } else {
+            0
+        };
+        (hi as u64) << 32 | le32(&self.sb, 0xC) as u64
+    }
+
+    fn reserved_blocks_count(&self) -> u64 {
+        let hi = if self.is_64bit() {
+            le32(&self.sb, 0x154)
+        } else {
+            0
+        };
+        (hi as u64) << 32 | le32(&self.sb, 0x8) as u64
+    }
+
+    fn first_data_block(&self) -> u64 {
+        le32(&self.sb, 0x14) as u64
+    }
+
+    fn blocks_per_group(&self) -> u64 {
+        le32(&self.sb, 0x20) as u64
+    }
+
+    fn clusters_per_group(&self) -> u64 {
+        le32(&self.sb, 0x24) as u64
+    }
+
+    fn inodes_per_group(&self) -> u32 {
+        le32(&self.sb, 0x28)
+    }
+
+    fn inode_size(&self) -> usize {
+        if le32(&self.sb, 0x4C) == 0 {
+            128
+        } else {
+            le16(&self.sb, 0x58) as usize
+        }
+    }
+
+    fn desc_size(&self) -> usize {
+        if self.is_64bit() {
+            le16(&self.sb, 0xFE).max(32) as usize
+        } else {
+            32
+        }
+    }
+
+    fn group_count(&self) -> u32 {
+        (self.blocks_count() - self.first_data_block()).div_ceil(self.blocks_per_group()) as u32
+    }
+
+    fn group_first_block(&self, g: u32) -> u64 {
+        self.first_data_block() + g as u64 * self.blocks_per_group()
+    }
+
+    fn blocks_in_group(&self, g: u32) -> u64 {
+        (self.blocks_count() - self.group_first_block(g)).min(self.blocks_per_group())
+    }
+
+    fn inode_group(&self, ino: u32) -> u32 {
+        (ino - 1) / self.inodes_per_group()
+    }
+
+    fn inode_table_blocks(&self) -> u64 {
+        (self.inodes_per_group() as u64 * self.inode_size() as u64).div_ceil(self.block_size)
+    }
+
+    fn gd_block_bitmap(&self, g: u32) -> u64 {
+        let gd = &self.groups[g as usize];
+        (le32(gd, 0x20) as u64) << 32 | le32(gd, 0x0) as u64
+    }
+
+    fn gd_inode_bitmap(&self, g: u32) -> u64 {
+        let gd = &self.groups[g as usize];
+        (le32(gd, 0x24) as u64) << 32 | le32(gd, 0x4) as u64
+    }
+
+    fn gd_inode_table(&self, g: u32) -> u64 {
+        let gd = &self.groups[g as usize];
+        (le32(gd, 0x28) as u64) << 32 | le32(gd, 0x8) as u64
+    }
+
+    fn gd_free_blocks(&self, g: u32) -> u32 {
+        let gd = &self.groups[g as usize];
+        combine32(le16(gd, 0xC), le16(gd, 0x2C))
+    }
+
+    fn gd_free_inodes(&self, g: u32) -> u32 {
+        let gd = &self.groups[g as usize];
+        combine32(le16(gd, 0xE), le16(gd, 0x2E))
+    }
+}
+
+impl<D: Read + Write + Seek> ReadLink for Ext4<D> {
+    fn read_link(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        let ino = self.lookup_path(path)?;
+        let inode = self.read_inode(ino)?;
+
+        if !inode.is_symlink() {
+            return Ok(None);
+        }
+        // targets of encrypted symbolic links are encrypted as well
+        anyhow::ensure!(
+            inode.flags() & INODE_ENCRYPT_FL == 0,
+            "ext4: encrypted symbolic links are not supported (inode {})",
+            inode.ino
+        );
+
+        let size = inode.size() as usize;
+        let target = if inode.is_fast_symlink() {
+            inode.i_block()[..size].to_vec()
+        } else {
+            let mut target = vec![];
+            self.read_data(&inode, &mut target)?;
+            target
+        };
+
+        anyhow::ensure!(
+            !target.is_empty(),
+            "ext4: {} is an empty symbolic link",
+            path.display()
+        );
+
+        Ok(Some(PathBuf::from(OsStr::from_bytes(&target))))
+    }
+}
+
+impl<D: Read + Write + Seek> Filesystem for Ext4<D> {
+    fn create_dir(&mut self, path: &Path) -> Result<()> {
+        self.check_writable()?;
+
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.lookup_dir(parent)?;
+
+        if let Some(ino) = self.lookup(&dir, name)? {
+            let inode = self.read_inode(ino)?;
+            let (kind, msg) = if inode.is_dir() {
+                (io::ErrorKind::AlreadyExists, "already exists")
+            } else {
+                (
+                    io::ErrorKind::NotADirectory,
+                    "exists and is not a directory",
+                )
+            };
+            return Err(io_error(kind, format!("ext4: {} {msg}", path.display())));
+        }
+
+        self.mkdir(&mut dir, name)?;
+        Ok(())
+    }
+
+    fn write_file(&mut self, path: &Path, data: &mut dyn Read, len: u64) -> Result<()> {
+        self.check_writable()?;
+
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.lookup_dir(parent)?;
+
+        let mut inode = match self.lookup(&dir, name)? {
+            Some(ino) => {
+                let inode = self.read_inode(ino)?;
+                if inode.is_dir() {
+                    return Err(io_error(
+                        io::ErrorKind::IsADirectory,
+                        format!("ext4: {} is a directory", path.display()),
+                    ));
+                }
+                anyhow::ensure!(
+                    inode.is_reg(),
+                    "ext4: {} is not a regular file",
+                    path.display()
+                );
+                inode
+            }
+            None => {
+                let goal = self.inode_group(dir.ino);
+                let ino = self.alloc_inode(goal, false)?;
+                let inode = self.new_inode(ino, S_IFREG | 0o644)?;
+                self.add_dir_entry(&mut dir, name, ino, FT_REG_FILE)?;
+                inode
+            }
+        };
+
+        // The old blocks are only released up front if the new content
+        // doesn't fit beside them. Otherwise they are kept until the new
+        // content is written, so that a failing write leaves them untouched.
+        let blocks = len.div_ceil(self.block_size);
+        let per_block = (self.block_size - 12) / 12;
+        let mut old_map = Some(self.block_map(&inode)?);
+        if self.free_blocks_count() < blocks + blocks.div_ceil(per_block) {
+            self.free_block_map(&inode)?;
+            old_map = None;
+        }
+
+        let goal = self.group_first_block(self.inode_group(inode.ino));
+        let extents = self
+            .write_data(goal, data, len)
+            .with_context(|| format!("ext4: cannot write data of {}", path.display()))?;
+        self.store_extents(&mut inode, &extents)?;
+
+        if let Some(map) = old_map {
+            self.free_mapped_blocks(map)?;
+        }
+
+        if len >= 1 << 31 && self.ro_compat() & RO_COMPAT_LARGE_FILE == 0 {
+            let ro_compat = self.ro_compat() | RO_COMPAT_LARGE_FILE;
+            set_le32(&mut self.sb, 0x64, ro_compat);
+            self.sb_dirty = true;
+        }
+
+        inode.set_size(len);
+        inode.touch(self.inode_size());
+        self.write_inode(&mut inode)
+    }
+
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> Result<u64> {
+        let ino = self.lookup_path(path)?;
+        let inode = self.read_inode(ino)?;
+
+        if inode.is_dir() {
+            return Err(io_error(
+                io::ErrorKind::IsADirectory,
+                format!("ext4: {} is a directory", path.display()),
+            ));
+        }
+        anyhow::ensure!(
+            inode.is_reg(),
+            "ext4: {} is not a regular file",
+            path.display()
+        );
+
+        self.read_data(&inode, out)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.check_writable()?;
+
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.lookup_dir(parent)?;
+        let ino = self.lookup_path(path)?;
+        let inode = self.read_inode(ino)?;
+
+        if inode.is_dir() {
+            return Err(io_error(
+                io::ErrorKind::IsADirectory,
+                format!("ext4: {} is a directory", path.display()),
+            ));
+        }
+
+        self.unlink(&mut dir, name, inode)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<()> {
+        self.check_writable()?;
+
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.lookup_dir(parent)?;
+        let inode = self.lookup_dir(path)?;
+
+        if self
+            .read_dir(&inode)?
+            .iter()
+            .any(|e| e.name != b"." && e.name != b"..")
+        {
+            return Err(io_error(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("ext4: {} is not empty", path.display()),
+            ));
+        }
+
+        self.unlink(&mut dir, name, inode)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        self.check_writable()?;
+
+        let (from_parent, from_name) = split_path(from)?;
+        let (to_parent, to_name) = split_path(to)?;
+        let from_dir = self.lookup_dir(from_parent)?;
+        let mut to_dir = self.lookup_dir(to_parent)?;
+        let ino = self.lookup_path(from)?;
+        let mut inode = self.read_inode(ino)?;
+
+        if inode.is_dir() && to.starts_with(from) {
+            return Err(io_error(
+                io::ErrorKind::InvalidInput,
+                format!("ext4: cannot move {} into itself", from.display()),
+            ));
+        }
+
+        if let Some(ino) = self.lookup(&to_dir, to_name)? {
+            if ino == inode.ino {
+                return Ok(());
+            }
+            let existing = self.read_inode(ino)?;
+            match (inode.is_dir(), existing.is_dir()) {
+                (true, true) => self.remove_dir(to)?,
+                (false, false) => self.unlink(&mut to_dir, to_name, existing)?,
+                (true, false) => {
+                    return Err(io_error(
+                        io::ErrorKind::NotADirectory,
+                        format!("ext4: {} is not a directory", to.display()),
+                    ));
+                }
+                (false, true) => {
+                    return Err(io_error(
+                        io::ErrorKind::IsADirectory,
+                        format!("ext4: {} is a directory", to.display()),
+                    ));
+                }
+            }
+            to_dir = self.read_inode(to_dir.ino)?;
+        }
+
+        self.add_dir_entry(&mut to_dir, to_name, inode.ino, inode.file_type_code())?;
+
+        // both directories may be the same inode, so we read it again
+        let mut from_dir = self.read_inode(from_dir.ino)?;
+        self.remove_dir_entry(&mut from_dir, from_name)?;
+
+        if inode.is_dir() && from_dir.ino != to_dir.ino {
+            self.set_parent(&inode, to_dir.ino)?;
+            if from_dir.links() > 2 {
+                from_dir.set_links(from_dir.links() - 1);
+                self.write_inode(&mut from_dir)?;
+            }
+            let mut to_dir = self.read_inode(to_dir.ino)?;
+            match to_dir.links() {
+                // link count of 1 means too many subdirectories to count
+                1 => {}
+                links if links < 65000 => to_dir.set_links(links + 1),
+                _ if self.ro_compat() & RO_COMPAT_DIR_NLINK != 0 => to_dir.set_links(1),
+                _ => {}
+            }
+            to_dir.touch(self.inode_size());
+            self.write_inode(&mut to_dir)?;
+        }
+
+        inode.touch(self.inode_size());
+        self.write_inode(&mut inode)?;
+
+        debug!("ext4: renamed {} to {}", from.display(), to.display());
+
+        Ok(())
+    }
+
+    fn metadata(&mut self, path: &Path) -> Result<Metadata> {
+        let ino = self.lookup_path(path)?;
+        self.read_inode(ino)?.metadata()
+    }
+
+    fn usage(&mut self) -> Result<Usage> {
+        Ok(Usage {
+            total: self.blocks_count() * self.block_size,
+            free: self.free_blocks_count() * self.block_size,
+            block_size: self.block_size,
+        })
+    }
+
+    fn set_attributes(&mut self, path: &Path, attributes: &FileAttributes) -> Result<()> {
+        self.check_writable()?;
+
+        let ino = self.lookup_path(path)?;
+        let mut inode = self.read_inode(ino)?;
+        inode.set_attributes(attributes);
+        self.write_inode(&mut inode)?;
+
+        debug!("ext4: set {attributes:?} on {}", path.display());
+
+        Ok(())
+    }
+
+    fn list_dir(&mut self, path: &Path) -> Result<Vec<(OsString, Metadata)>> {
+        let dir = self.lookup_dir(path)?;
+        let mut entries = vec![];
+
+        for e in self.read_dir(&dir)? {
+            if e.name == b"." || e.name == b".." {
+                continue;
+            }
+            let metadata = self.read_inode(e.ino)?.metadata()?;
+            entries.push((OsStr::from_bytes(&e.name).to_os_string(), metadata));
+        }
+
+        Ok(entries)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let metadata_csum = self.has_metadata_csum();
+
+        for g in std::mem::take(&mut self.dirty_block_bitmaps) {
+            let bitmap = self.block_bitmaps[&g].clone();
+            let location = self.gd_block_bitmap(g);
+            self.write_at(location * self.block_size, &bitmap)?;
+            if metadata_csum {
+                let len = (self.clusters_per_group() / 8) as usize;
+                let csum = crc32c(self.csum_seed, &bitmap[..len]);
+                let gd = &mut self.groups[g as usize];
+                set_le16(gd, 0x18, csum as u16);
+                set_le16(gd, 0x38, (csum >> 16) as u16);
+            }
+            self.dirty_groups.insert(g);
+        }
+
+        for g in std::mem::take(&mut self.dirty_inode_bitmaps) {
+            let bitmap = self.inode_bitmaps[&g].clone();
+            let location = self.gd_inode_bitmap(g);
+            self.write_at(location * self.block_size, &bitmap)?;
+            if metadata_csum {
+                let len = (self.inodes_per_group() / 8) as usize;
+                let csum = crc32c(self.csum_seed, &bitmap[..len]);
+                let gd = &mut self.groups[g as usize];
+                set_le16(gd, 0x1A, csum as u16);
+                set_le16(gd, 0x3A, (csum >> 16) as u16);
+            }
+            self.dirty_groups.insert(g);
+        }
+
+        let mut blocks: Vec<_> = self.dirty_blocks.drain().collect();
+        blocks.sort_by_key(|(block, _)| *block);
+        for (block, data) in blocks {
+            self.write_at(block * self.block_size, &data)?;
+        }
+
+        let desc_size = self.desc_size();
+        let per_block = self.block_size / desc_size as u64;
+        for g in std::mem::take(&mut self.dirty_groups) {
+            let csum = self.gd_checksum(g);
+            let gd = &mut self.groups[g as usize];
+            set_le16(gd, 0x1E, csum);
+            let desc = gd[..desc_size].to_vec();
+            let offset = self.gdt_block(g / per_block as u32) * self.block_size
+                + (g as u64 % per_block) * desc_size as u64;
+            self.write_at(offset, &desc)?;
+        }
+
+        if std::mem::take(&mut self.sb_dirty) {
+            if metadata_csum {
+                let csum = crc32c(!0, &self.sb[..0x3FC]);
+                set_le32(&mut self.sb, 0x3FC, csum);
+            }
+            let sb = self.sb.clone();
+            self.write_at(SUPERBLOCK_OFFSET, &sb)?;
+        }
+
+        self.dev.flush().context("ext4: cannot flush device")
+    }
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        le16(&self.raw, 0x0)
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    fn is_reg(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        let file_type = match self.mode() & S_IFMT {
+            S_IFREG => FileType::File,
+            S_IFDIR => FileType::Dir,
+            S_IFLNK => FileType::Symlink,
+            S_IFCHR => FileType::CharDevice,
+            S_IFBLK => FileType::BlockDevice,
+            S_IFIFO => FileType::Fifo,
+            S_IFSOCK => FileType::Socket,
+            mode => anyhow::bail!("ext4: invalid file mode {mode:#o} of inode {}", self.ino),
+        };
+
+        // the low 2 bits of the extra field extend the signed 32 bit seconds
+        let mut mtime = le32(&self.raw, 0x10) as i32 as i64;
+        if self.has_extra(0x8C) {
+            mtime += ((le32(&self.raw, 0x88) & 0x3) as i64) << 32;
+        }
+
+        Ok(Metadata {
+            file_type,
+            size: self.size(),
+            mode: (self.mode() & !S_IFMT) as u32,
+            uid: combine32(le16(&self.raw, 0x2), le16(&self.raw, 0x78)),
+            gid: combine32(le16(&self.raw, 0x18), le16(&self.raw, 0x7A)),
+            mtime,
+        })
+    }
+
+    fn set_attributes(&mut self, attributes: &FileAttributes) {
+        if let Some(mode) = attributes.mode {
+            let mode = (self.mode() & S_IFMT) | (mode as u16 & !S_IFMT);
+            set_le16(&mut self.raw, 0x0, mode);
+        }
+        if let Some(uid) = attributes.uid {
+            set_le16(&mut self.raw, 0x2, uid as u16);
+            set_le16(&mut self.raw, 0x78, (uid >> 16) as u16);
+        }
+        if let Some(gid) = attributes.gid {
+            set_le16(&mut self.raw, 0x18, gid as u16);
+            set_le16(&mut self.raw, 0x7A, (gid >> 16) as u16);
+        }
+        if let Some(mtime) = attributes.mtime {
+            set_le32(&mut self.raw, 0x10, mtime as u32);
+            // epoch bits extending the signed seconds, nanoseconds are zero
+            if self.has_extra(0x8C) {
+                let epoch = (mtime - (mtime as i32 as i64)) >> 32;
+                set_le32(&mut self.raw, 0x88, (epoch & 0x3) as u32);
+            }
+        }
+    }
+
+    // short targets are stored in place of the block map ("fast" symlinks)
+    fn is_fast_symlink(&self) -> bool {
+        self.is_symlink()
+            && (self.size() as usize) < self.i_block().len()
+            && self.flags() & INODE_EXTENTS_FL == 0
+    }
+
+    fn file_type_code(&self) -> u8 {
+        match self.mode() & S_IFMT {
+            S_IFDIR => FT_DIR,
+            S_IFLNK => FT_SYMLINK,
+            S_IFCHR => FT_CHRDEV,
+            S_IFBLK => FT_BLKDEV,
+            S_IFIFO => FT_FIFO,
+            S_IFSOCK => FT_SOCK,
+            _ => FT_REG_FILE,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        (le32(&self.raw, 0x6C) as u64) << 32 | le32(&self.raw, 0x4) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        set_le32(&mut self.raw, 0x4, size as u32);
+        set_le32(&mut self.raw, 0x6C, (size >> 32) as u32);
+    }
+
+    fn links(&self) -> u16 {
+        le16(&self.raw, 0x1A)
+    }
+
+    fn set_links(&mut self, links: u16) {
+        set_le16(&mut self.raw, 0x1A, links);
+    }
+
+    fn sectors(&self) -> u64 {
+        (le16(&self.raw, 0x74) as u64) << 32 | le32(&self.raw, 0x1C) as u64
+    }
+
+    fn set_sectors(&mut self, sectors: u64) {
+        set_le32(&mut self.raw, 0x1C, sectors as u32);
+        set_le16(&mut self.raw, 0x74, (sectors >> 32) as u16);
+    }
+
+    fn flags(&self) -> u32 {
+        le32(&self.raw, 0x20)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        set_le32(&mut self.raw, 0x20, flags);
+    }
+
+    fn i_block(&self) -> &[u8] {
+        &self.raw[0x28..0x64]
+    }
+
+    fn i_block_mut(&mut self) -> &mut [u8] {
+        &mut self.raw[0x28..0x64]
+    }
+
+    // true if the inode has extra space covering all fields up to `end`
+    fn has_extra(&self, end: usize) -> bool {
+        self.raw.len() > 128 && 128 + le16(&self.raw, 0x80) as usize >= end
+    }
+
+    fn touch(&mut self, inode_size: usize) {
+        let (secs, extra) = timestamp_now();
+        for (offset, extra_offset) in [(0x8, 0x8C), (0xC, 0x84), (0x10, 0x88)] {
+            set_le32(&mut self.raw, offset, secs);
+            if inode_size > 128 && self.has_extra(extra_offset + 4) {
+                set_le32(&mut self.raw, extra_offset, extra);
+            }
+        }
+    }
+}
+
+impl BlockMap {
+    // all mapped (logical, physical) block pairs
+    fn blocks(&self) -> Vec<(u64, u64)> {
+        self.extents
+            .iter()
+            .flat_map(|e| (0..e.len).map(move |i| (e.logical + i, e.start + i)))
+            .collect()
+    }
+}
+
+fn push_extent(extents: &mut Vec<Extent>, logical: u64, start: u64, len: u64) {
+    let (mut logical, mut start, mut len) = (logical, start, len);
+
+    if let Some(last) = extents.last_mut()
+        && !last.uninit
+        && last.logical + last.len == logical
+        && last.start + last.len == start
+        && last.len < MAX_EXTENT_LEN
+    {
+        let n = len.min(MAX_EXTENT_LEN - last.len);
+        last.len += n;
+        logical += n;
+        start += n;
+        len -= n;
+    }
+
+    while len > 0 {
+        let n = len.min(MAX_EXTENT_LEN);
+        extents.push(Extent {
+            logical,
+            start,
+            len: n,
+            uninit: false,
+        });
+        logical += n;
+        start += n;
+        len -= n;
+    }
+}
+
+fn write_extent_header(data: &mut [u8], entries: usize, max: usize, depth: u16) {
+    set_le16(data, 0, EXTENT_MAGIC);
+    set_le16(data, 2, entries as u16);
+    set_le16(data, 4, max as u16);
+    set_le16(data, 6, depth);
+    set_le32(data, 8, 0);
+}
+
+fn write_dirent(data: &mut [u8], off: usize, ino: u32, rec_len: usize, name: &[u8], ft: u8) {
+    set_le32(data, off, ino);
+    set_le16(data, off + 4, rec_len as u16);
+    data[off + 6] = name.len() as u8;
+    data[off + 7] = ft;
+    data[off + 8..off + 8 + name.len()].copy_from_slice(name);
+}
+
+fn dirent_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+fn path_names(path: &Path) -> Result<Vec<&[u8]>> {
+    let mut names = vec![];
+    for c in path.components() {
+        match c {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => names.push(name.as_bytes()),
+            _ => anyhow::bail!("ext4: unsupported path {}", path.display()),
+        }
+    }
+    Ok(names)
+}
+
+fn split_path(path: &Path) -> Result<(&Path, &[u8])> {
+    let parent = path
+        .parent()
+        .with_context(|| format!("ext4: invalid path {}", path.display()))?;
+    let name = path
+        .file_name()
+        .map(OsStr::as_bytes)
+        .with_context(|| format!("ext4: invalid path {}", path.display()))?;
+    Ok((parent, name))
+}
+
+fn timestamp_now() -> (u32, u32) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    (
+        secs as u32,
+        now.subsec_nanos() << 2 | ((secs >> 32) & 0x3) as u32,
+    )
+}
+
+fn combine32(lo: u16, hi: u16) -> u32 {
+    (hi as u32) << 16 | lo as u32
+}
+
+fn get_bit(bitmap: &[u8], bit: u64) -> bool {
+    bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+}
+
+fn set_bit(bitmap: &mut [u8], bit: u64) {
+    bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+}
+
+fn clear_bit(bitmap: &mut [u8], bit: u64) {
+    bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+}
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn set_le16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn set_le32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// raw crc32c as used by ext4, i.e. without final inversion
+fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::process::Command;
+
+    // Creates a filesystem of `size` KiB with 1 KiB blocks, i.e. 8 MiB block
+    // groups and 32 or 16 (64bit) group descriptors per block.
+    fn mkfs(dir: &Path, size: u64, args: &[&str]) -> PathBuf {
+        let image = dir.join("ext4.img");
+        let output = Command::new("mkfs.ext4")
+            .args(["-q", "-F", "-b", "1024"])
+            .args(args)
+            .arg(&image)
+            .arg(size.to_string())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        image
+    }
+
+    fn open(image: &Path) -> Ext4<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .unwrap();
+        Ext4::open(file).unwrap()
+    }
+
+    fn fsck(image: &Path) {
+        let output = Command::new("e2fsck")
+            .arg("-fn")
+            .arg(image)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    fn debugfs(image: &Path, request: &str) {
+        let output = Command::new("debugfs")
+            .args(["-w", "-R", request])
+            .arg(image)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+
+    fn write(fs: &mut Ext4<File>, path: &str, data: &[u8]) {
+        fs.write_file(Path::new(path), &mut &data[..], data.len() as u64)
+            .unwrap();
+    }
+
+    fn read(fs: &mut Ext4<File>, path: &str) -> Vec<u8> {
+        let mut data = vec![];
+        fs.read_file(Path::new(path), &mut data).unwrap();
+        data
+    }
+
+    // every other block is a hole, so each written block is an extent of its own
+    fn sparse_data(blocks: usize, fill: u8) -> Vec<u8> {
+        (0..blocks)
+            .flat_map(|i| vec![if i % 2 == 0 { fill } else { 0 }; 1024])
+            .collect()
+    }
+
+    #[test]
+    fn extent_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = mkfs(tmp.path(), 24 * 1024, &[]);
+        let mut fs = open(&image);
+        let free = fs.usage().unwrap().free;
+
+        // 1000 extents need two levels of index and leaf blocks
+        let data = sparse_data(2000, 0xa5);
+        write(&mut fs, "/sparse", &data);
+        fs.flush().unwrap();
+        let ino = fs.lookup_path(Path::new("/sparse")).unwrap();
+        let inode = fs.read_inode(ino).unwrap();
+        assert_eq!(le16(inode.i_block(), 6), 2);
+        assert_eq!(fs.block_map(&inode).unwrap().extents.len(), 1000);
+        assert_eq!(read(&mut fs, "/sparse"), data);
+        fsck(&image);
+
+        // overwriting releases the old extent tree
+        let data = sparse_data(20, 0x5a);
+        write(&mut fs, "/sparse", &data);
+        fs.flush().unwrap();
+        assert_eq!(read(&mut fs, "/sparse"), data);
+        assert_eq!(fs.usage().unwrap().free, free - 10 * 1024 - 1024);
+        fsck(&image);
+
+        fs.remove_file(Path::new("/sparse")).unwrap();
+        fs.flush().unwrap();
+        assert_eq!(fs.usage().unwrap().free, free);
+        fsck(&image);
+    }
+
+    #[test]
+    fn overwrite() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = mkfs(tmp.path(), 4 * 1024, &["-m", "0"]);
+        let mut fs = open(&image);
+        let old = vec![0x11u8; 512 * 1024];
+        write(&mut fs, "/file", &old);
+        fs.flush().unwrap();
+
+        // the old content survives a failing overwrite, which doesn't keep
+        // the blocks written up to the failure
+        let free = fs.usage().unwrap().free;
+        let short = vec![0x22u8; 1536 * 1024];
+        assert!(
+            fs.write_file(Path::new("/file"), &mut &short[..], 2048 * 1024)
+                .is_err()
+        );
+        assert_eq!(read(&mut fs, "/file"), old);
+        assert_eq!(fs.usage().unwrap().free, free);
+        fs.flush().unwrap();
+        fsck(&image);
+
+        // with room for both the new content goes to other blocks
+        let block = |fs: &mut Ext4<File>| {
+            let ino = fs.lookup_path(Path::new("/file")).unwrap();
+            let inode = fs.read_inode(ino).unwrap();
+            fs.block_map(&inode).unwrap().extents[0].start
+        };
+        let start = block(&mut fs);
+        let new = vec![0x22u8; 512 * 1024];
+        write(&mut fs, "/file", &new);
+        fs.flush().unwrap();
+        assert_ne!(block(&mut fs), start);
+        assert_eq!(read(&mut fs, "/file"), new);
+        fsck(&image);
+
+        // without room for both the old blocks are reused
+        let free = fs.usage().unwrap().free;
+        write(&mut fs, "/fill", &vec![0x33u8; free as usize - 256 * 1024]);
+        let new = vec![0x44u8; 512 * 1024];
+        write(&mut fs, "/file", &new);
+        fs.flush().unwrap();
+        assert_eq!(read(&mut fs, "/file"), new);
+        fsck(&image);
+    }
+
+    #[test]
+    fn group_descriptor_checksums() {
+        for features in [
+            "metadata_csum,64bit",
+            "^metadata_csum,uninit_bg,^64bit",
+            "^metadata_csum,^uninit_bg,^64bit",
+        ] {
+            let tmp = tempfile::tempdir().unwrap();
+            let image = mkfs(tmp.path(), 24 * 1024, &["-O", features]);
+            let mut fs = open(&image);
+
+            // the file is larger than a group, so it spans several of them
+            let data: Vec<u8> = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            fs.create_dir(Path::new("/dir")).unwrap();
+            write(&mut fs, "/dir/big", &data);
+            write(&mut fs, "/small", b"small");
+            fs.rename(Path::new("/small"), Path::new("/dir/small"))
+                .unwrap();
+            fs.flush().unwrap();
+            fsck(&image);
+
+            let mut fs = open(&image);
+            assert_eq!(read(&mut fs, "/dir/big"), data, "{features}");
+            assert_eq!(read(&mut fs, "/dir/small"), b"small", "{features}");
+            fs.remove_file(Path::new("/dir/big")).unwrap();
+            fs.flush().unwrap();
+            fsck(&image);
+        }
+    }
+
+    #[test]
+    fn htree_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        std::fs::create_dir_all(root.join("dir")).unwrap();
+        for i in 0..300 {
+            std::fs::write(
+                root.join(format!("dir/a-rather-long-file-name-{i:04}")),
+                "x",
+            )
+            .unwrap();
+        }
+        let image = mkfs(tmp.path(), 4 * 1024, &["-d", root.to_str().unwrap()]);
+        // index all directories with more than one block
+        let output = Command::new("e2fsck")
+            .arg("-fyD")
+            .arg(&image)
+            .output()
+            .unwrap();
+        assert!(output.status.code().is_some_and(|code| code <= 1));
+
+        let mut fs = open(&image);
+        let dir = fs.lookup_dir(Path::new("/dir")).unwrap();
+        assert_ne!(dir.flags() & INODE_INDEX_FL, 0);
+        assert_eq!(read(&mut fs, "/dir/a-rather-long-file-name-0299"), b"x");
+
+        write(&mut fs, "/dir/new", b"new");
+        fs.remove_file(Path::new("/dir/a-rather-long-file-name-0000"))
+            .unwrap();
+        fs.rename(
+            Path::new("/dir/a-rather-long-file-name-0001"),
+            Path::new("/dir/renamed"),
+        )
+        .unwrap();
+        fs.flush().unwrap();
+        fsck(&image);
+
+        let mut fs = open(&image);
+        assert_eq!(fs.list_dir(Path::new("/dir")).unwrap().len(), 300);
+        assert_eq!(read(&mut fs, "/dir/new"), b"new");
+        assert_eq!(read(&mut fs, "/dir/renamed"), b"x");
+    }
+
+    #[test]
+    fn grow() {
+        // 32 and 16 descriptors per block, so both grow beyond the first
+        // descriptor block and add groups with backup superblocks
+        for (features, size) in [("64bit", 136), ("^64bit", 264)] {
+            let tmp = tempfile::tempdir().unwrap();
+            let image = mkfs(tmp.path(), 4 * 1024, &["-O", features]);
+            let mut fs = open(&image);
+            write(&mut fs, "/file", b"file");
+            fs.flush().unwrap();
+            let reserved = le16(&fs.sb, 0xCE);
+
+            let file = OpenOptions::new().write(true).open(&image).unwrap();
+            file.set_len(size * 1024 * 1024).unwrap();
+            let blocks = fs.grow_target(size * 1024).unwrap();
+            assert_eq!(blocks, size * 1024);
+            fs.grow(blocks).unwrap();
+            assert_eq!(le16(&fs.sb, 0xCE), reserved - 1, "{features}");
+            fsck(&image);
+
+            // the new groups are usable
+            let mut fs = open(&image);
+            assert_eq!(fs.group_count() as u64, (size * 1024 - 1).div_ceil(8192));
+            let data: Vec<u8> = (0..100 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+            write(&mut fs, "/big", &data);
+            fs.flush().unwrap();
+            assert_eq!(read(&mut fs, "/big"), data);
+            assert_eq!(read(&mut fs, "/file"), b"file");
+            fsck(&image);
+        }
+    }
+
+    #[test]
+    fn unsupported_features() {
+        let tmp = tempfile::tempdir().unwrap();
+        let hostname = tmp.path().join("hostname");
+        std::fs::write(&hostname, "hostname").unwrap();
+
+        let image = mkfs(tmp.path(), 1024, &["-O", "encrypt"]);
+        debugfs(&image, &format!("write {} file", hostname.display()));
+        debugfs(&image, "set_inode_field file flags 0x80800");
+        let mut fs = open(&image);
+        let err = fs.read_file(Path::new("/file"), &mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("encrypted"));
+        assert!(
+            fs.write_file(Path::new("/other"), &mut &b""[..], 0)
+                .is_err()
+        );
+
+        let image = mkfs(tmp.path(), 1024, &["-O", "casefold"]);
+        let mut fs = open(&image);
+        assert!(fs.list_dir(Path::new("/")).is_ok());
+        assert!(fs.create_dir(Path::new("/dir")).is_err());
+    }
+}
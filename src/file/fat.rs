@@ -0,0 +1,1565 @@
+use super::filesystem::{
+    FileAttributes, FileType, Filesystem, Metadata, ReadLink, Usage, io_error,
+};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DIR_ENTRY_SIZE: usize = 32;
+const MAX_LFN_LEN: usize = 255;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+// byte offsets of the 13 UCS-2 characters stored in a long file name entry
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LFN: u8 = 0x0F;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+// NT reserved byte flags marking lower case short names
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Dir {
+    // fixed size root directory region of FAT12/16
+    Root,
+    Cluster(u32),
+}
+
+pub struct Fat<D> {
+    dev: D,
+    fat_type: FatType,
+    bytes_per_sector: u64,
+    cluster_size: u64,
+    fat_offset: u64,
+    fat_size: u64,
+    num_fats: u64,
+    root_dir_offset: u64,
+    root_dir_size: u64,
+    root_cluster: u32,
+    data_offset: u64,
+    cluster_count: u32,
+    fsinfo_sector: Option<u64>,
+    fat: Vec<u8>,
+    fat_dirty: bool,
+    next_free: u32,
+    // directory regions are written back on flush() only, so that a failing
+    // operation never leaves a half updated filesystem behind, file data is
+    // written to free clusters right away (see write_file() for overwrites)
+    dirty_regions: HashMap<u64, Vec<u8>>,
+}
+
+struct DirContent {
+    // device offsets of the clusters (or the fixed root region) of a directory
+    regions: Vec<u64>,
+    region_size: u64,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+struct DirEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    mtime: i64,
+    // long file name entries precede the short entry in `slot`
+    first_slot: usize,
+    slot: usize,
+}
+
+impl<D: Read + Write + Seek> Fat<D> {
+    pub fn open(mut dev: D) -> Result<Self> {
+        let mut bpb = [0u8; 512];
+        dev.seek(SeekFrom::Start(0))
+            .and_then(|_| dev.read_exact(&mut bpb))
+            .context("fat: cannot read boot sector")?;
+
+        let bytes_per_sector = le16(&bpb, 0x0B) as u64;
+        let sectors_per_cluster = bpb[0x0D] as u64;
+        let reserved_sectors = le16(&bpb, 0x0E) as u64;
+        let num_fats = bpb[0x10] as u64;
+        let root_entries = le16(&bpb, 0x11) as u64;
+        let total_sectors = match le16(&bpb, 0x13) {
+            0 => le32(&bpb, 0x20) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match le16(&bpb, 0x16) {
+            0 => le32(&bpb, 0x24) as u64,
+            n => n as u64,
+        };
+
+        anyhow::ensure!(
+            [512, 1024, 2048, 4096].contains(&bytes_per_sector)
+                && sectors_per_cluster.is_power_of_two()
+                && reserved_sectors > 0
+                && num_fats > 0
+                && fat_sectors > 0,
+            "fat: invalid BIOS parameter block"
+        );
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let data_sector = reserved_sectors + num_fats * fat_sectors + root_dir_sectors;
+        anyhow::ensure!(
+            total_sectors > data_sector,
+            "fat: invalid BIOS parameter block"
+        );
+        let cluster_count = ((total_sectors - data_sector) / sectors_per_cluster) as u32;
+
+        let fat_type = if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        let (root_cluster, fsinfo_sector) = if fat_type == FatType::Fat32 {
+            let fsinfo = le16(&bpb, 0x30) as u64;
+            (
+                le32(&bpb, 0x2C),
+                (fsinfo != 0 && fsinfo != 0xFFFF).then_some(fsinfo),
+            )
+        } else {
+            (0, None)
+        };
+
+        let fat_offset = reserved_sectors * bytes_per_sector;
+        let fat_size = fat_sectors * bytes_per_sector;
+        let mut fat = vec![0u8; fat_size as usize];
+        dev.seek(SeekFrom::Start(fat_offset))
+            .and_then(|_| dev.read_exact(&mut fat))
+            .context("fat: cannot read allocation table")?;
+
+        let fs = Fat {
+            dev,
+            fat_type,
+            bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            fat_offset,
+            fat_size,
+            num_fats,
+            root_dir_offset: (reserved_sectors + num_fats * fat_sectors) * bytes_per_sector,
+            root_dir_size: root_entries * DIR_ENTRY_SIZE as u64,
+            root_cluster,
+            data_offset: data_sector * bytes_per_sector,
+            cluster_count,
+            fsinfo_sector,
+            fat,
+            fat_dirty: false,
+            next_free: 2,
+            dirty_regions: HashMap::new(),
+        };
+
+        anyhow::ensure!(
+            fs.fat.len() as u64 >= fs.fat_entry_offset(cluster_count + 1) + 2,
+            "fat: allocation table too small for {cluster_count} clusters"
+        );
+
+        debug!(
+            "fat: type={:?} cluster_size={} clusters={}",
+            fat_type, fs.cluster_size, cluster_count
+        );
+
+        Ok(fs)
+    }
+
+    fn root(&self) -> Dir {
+        match self.fat_type {
+            FatType::Fat32 => Dir::Cluster(self.root_cluster),
+            _ => Dir::Root,
+        }
+    }
+
+    fn lookup_path(&mut self, path: &Path) -> Result<Option<DirEntry>> {
+        let names = path_names(path)?;
+        let mut dir = self.root();
+        let mut entry = None;
+
+        for (i, name) in names.iter().enumerate() {
+            let found = self.lookup(dir, name)?;
+            match &found {
+                Some(e) if e.attr & ATTR_DIRECTORY != 0 => dir = dir_of(e, self.root()),
+                Some(_) if i + 1 < names.len() => {
+                    return Err(io_error(
+                        io::ErrorKind::NotADirectory,
+                        format!("fat: {} is not a directory", path.display()),
+                    ));
+                }
+                Some(_) => {}
+                None => return Ok(None),
+            }
+            entry = found;
+        }
+
+        Ok(entry)
+    }
+
+    fn lookup_dir(&mut self, path: &Path) -> Result<Dir> {
+        if path_names(path)?.is_empty() {
+            return Ok(self.root());
+        }
+        match self.lookup_path(path)? {
+            Some(e) if e.attr & ATTR_DIRECTORY != 0 => Ok(dir_of(&e, self.root())),
+            Some(_) => Err(io_error(
+                io::ErrorKind::NotADirectory,
+                format!("fat: {} is not a directory", path.display()),
+            )),
+            None => Err(io_error(
+                io::ErrorKind::NotFound,
+                format!("fat: {} not found", path.display()),
+            )),
+        }
+    }
+
+    fn lookup(&mut self, dir: Dir, name: &str) -> Result<Option<DirEntry>> {
+        let content = self.read_dir(dir)?;
+        let upper = name.to_uppercase();
+        Ok(parse_entries(&content.data)
+            .into_iter()
+            .find(|e| e.name.to_uppercase() == upper || short_name_string(&e.short_name) == upper))
+    }
+
+    fn read_dir(&mut self, dir: Dir) -> Result<DirContent> {
+        let (regions, region_size) = match dir {
+            Dir::Root => (vec![self.root_dir_offset], self.root_dir_size),
+            Dir::Cluster(c) => (
+                self.chain(c)?
+                    .into_iter()
+                    .map(|c| self.cluster_offset(c))
+                    .collect(),
+                self.cluster_size,
+            ),
+        };
+
+        let mut data = Vec::with_capacity(regions.len() * region_size as usize);
+        for offset in &regions {
+            match self.dirty_regions.get(offset) {
+                Some(region) => data.extend_from_slice(region),
+                None => {
+                    let mut region = vec![0u8; region_size as usize];
+                    self.read_at(*offset, &mut region)?;
+                    data.extend_from_slice(&region);
+                }
+            }
+        }
+
+        Ok(DirContent {
+            regions,
+            region_size,
+            data,
+        })
+    }
+
+    fn write_dir(&mut self, content: &DirContent) {
+        for (i, offset) in content.regions.iter().enumerate() {
+            let size = content.region_size as usize;
+            let region = content.data[i * size..(i + 1) * size].to_vec();
+            self.dirty_regions.insert(*offset, region);
+        }
+    }
+
+    fn add_entry(&mut self, dir: Dir, name: &str, attr: u8, cluster: u32, size: u32) -> Result<()> {
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[11] = attr;
+        let (time, date) = dos_timestamp_now();
+        set_le16(&mut entry, 0x0E, time);
+        set_le16(&mut entry, 0x10, date);
+        set_le16(&mut entry, 0x12, date);
+        set_le16(&mut entry, 0x14, (cluster >> 16) as u16);
+        set_le16(&mut entry, 0x16, time);
+        set_le16(&mut entry, 0x18, date);
+        set_le16(&mut entry, 0x1A, cluster as u16);
+        set_le32(&mut entry, 0x1C, size);
+        self.insert_entry(dir, name, entry)
+    }
+
+    // Stores `entry` under `name` including long file name entries if the
+    // name cannot be represented as 8.3 short name. Name and case flags of
+    // `entry` are filled in here.
+    fn insert_entry(
+        &mut self,
+        dir: Dir,
+        name: &str,
+        mut entry: [u8; DIR_ENTRY_SIZE],
+    ) -> Result<()> {
+        anyhow::ensure!(
+            is_valid_long_name(name),
+            "fat: invalid file name \"{name}\""
+        );
+
+        let mut content = self.read_dir(dir)?;
+        let entries = parse_entries(&content.data);
+        let (short_name, case, needs_lfn) = make_short_name(name, &entries);
+
+        let mut slots = vec![];
+        if needs_lfn {
+            slots.extend(make_lfn_entries(name, &short_name));
+        }
+        entry[..11].copy_from_slice(&short_name);
+        entry[12] = case;
+        slots.push(entry);
+
+        let first = match find_free_slots(&content.data, slots.len()) {
+            Some(first) => first,
+            None => {
+                let Dir::Cluster(start) = dir else {
+                    return Err(io_error(
+                        io::ErrorKind::StorageFull,
+                        "fat: root directory is full".to_string(),
+                    ));
+                };
+                // grow the directory by as many zeroed clusters as needed
+                let used = content.data.len() / DIR_ENTRY_SIZE;
+                let first = (0..used)
+                    .rev()
+                    .take_while(|i| is_free_slot(&content.data, *i))
+                    .last()
+                    .unwrap_or(used);
+                let needed = (first + slots.len()) * DIR_ENTRY_SIZE - content.data.len();
+                let last = *self.chain(start)?.last().unwrap();
+                let clusters = self.alloc_clusters(needed.div_ceil(self.cluster_size as usize))?;
+                self.set_entry(last, clusters[0]);
+                for c in &clusters {
+                    content.regions.push(self.cluster_offset(*c));
+                    content
+                        .data
+                        .resize(content.data.len() + self.cluster_size as usize, 0);
+                }
+                first
+            }
+        };
+
+        for (i, slot) in slots.iter().enumerate() {
+            let offset = (first + i) * DIR_ENTRY_SIZE;
+            content.data[offset..offset + DIR_ENTRY_SIZE].copy_from_slice(slot);
+        }
+        self.write_dir(&content);
+
+        debug!(
+            "fat: added entry {name} ({})",
+            short_name_string(&short_name)
+        );
+
+        Ok(())
+    }
+
+    // marks the entry and its long file name entries as deleted
+    fn delete_entry(&mut self, dir: Dir, entry: &DirEntry) -> Result<()> {
+        let mut content = self.read_dir(dir)?;
+        for slot in entry.first_slot..=entry.slot {
+            content.data[slot * DIR_ENTRY_SIZE] = ENTRY_DELETED;
+        }
+        self.write_dir(&content);
+
+        debug!("fat: deleted entry {}", entry.name);
+
+        Ok(())
+    }
+
+    fn raw_entry(&mut self, dir: Dir, entry: &DirEntry) -> Result<[u8; DIR_ENTRY_SIZE]> {
+        let content = self.read_dir(dir)?;
+        let mut raw = [0u8; DIR_ENTRY_SIZE];
+        raw.copy_from_slice(
+            &content.data[entry.slot * DIR_ENTRY_SIZE..(entry.slot + 1) * DIR_ENTRY_SIZE],
+        );
+        Ok(raw)
+    }
+
+    fn lookup_entry(&mut self, path: &Path) -> Result<(Dir, DirEntry)> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.lookup_dir(parent)?;
+        let entry = self.lookup(dir, name)?.ok_or_else(|| {
+            io_error(
+                io::ErrorKind::NotFound,
+                format!("fat: {} not found", path.display()),
+            )
+        })?;
+        Ok((dir, entry))
+    }
+
+    // cluster stored in ".." entries, the root directory is always cluster 0
+    fn parent_cluster(&self, dir: Dir) -> u32 {
+        match dir {
+            Dir::Cluster(c) if c != self.root_cluster => c,
+            _ => 0,
+        }
+    }
+
+    fn update_entry(&mut self, dir: Dir, entry: &DirEntry, cluster: u32, size: u32) -> Result<()> {
+        let mut content = self.read_dir(dir)?;
+        let raw = &mut content.data[entry.slot * DIR_ENTRY_SIZE..(entry.slot + 1) * DIR_ENTRY_SIZE];
+        let (time, date) = dos_timestamp_now();
+        set_le16(raw, 0x12, date);
+        set_le16(raw, 0x14, (cluster >> 16) as u16);
+        set_le16(raw, 0x16, time);
+        set_le16(raw, 0x18, date);
+        set_le16(raw, 0x1A, cluster as u16);
+        set_le32(raw, 0x1C, size);
+        raw[11] |= ATTR_ARCHIVE;
+        self.write_dir(&content);
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &mut dyn Read, len: u64) -> Result<u32> {
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let clusters = self.alloc_clusters(len.div_ceil(self.cluster_size) as usize)?;
+        if let Err(e) = self.write_clusters(&clusters, data, len) {
+            // the clusters of a failing write are not left allocated
+            self.free_chain(clusters[0])?;
+            return Err(e);
+        }
+
+        Ok(clusters[0])
+    }
+
+    fn write_clusters(&mut self, clusters: &[u32], data: &mut dyn Read, len: u64) -> Result<()> {
+        let mut buf = vec![0u8; self.cluster_size as usize];
+        let mut remaining = len;
+
+        for c in clusters {
+            let n = remaining.min(self.cluster_size) as usize;
+            data.read_exact(&mut buf[..n])?;
+            buf[n..].fill(0);
+            self.write_at(self.cluster_offset(*c), &buf)?;
+            remaining -= n as u64;
+        }
+
+        Ok(())
+    }
+
+    // Allocates a chain of `count` clusters and returns its clusters in order.
+    fn alloc_clusters(&mut self, count: usize) -> Result<Vec<u32>> {
+        let mut clusters = Vec::with_capacity(count);
+        let end = self.cluster_count + 2;
+        let mut c = self.next_free.clamp(2, end - 1);
+
+        for _ in 0..self.cluster_count {
+            if clusters.len() == count {
+                break;
+            }
+            if self.get_entry(c) == 0 {
+                clusters.push(c);
+            }
+            c = if c + 1 >= end { 2 } else { c + 1 };
+        }
+
+        if clusters.len() < count {
+            return Err(io_error(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "fat: not enough free space, {} bytes missing",
+                    (count - clusters.len()) as u64 * self.cluster_size
+                ),
+            ));
+        }
+
+        for pair in clusters.windows(2) {
+            self.set_entry(pair[0], pair[1]);
+        }
+        let last = *clusters.last().unwrap();
+        self.set_entry(last, self.end_of_chain());
+        self.next_free = last + 1;
+
+        for c in &clusters {
+            let offset = self.cluster_offset(*c);
+            self.dirty_regions.remove(&offset);
+        }
+
+        Ok(clusters)
+    }
+
+    fn free_clusters(&self) -> u64 {
+        (2..self.cluster_count + 2)
+            .filter(|c| self.get_entry(*c) == 0)
+            .count() as u64
+    }
+
+    fn free_chain(&mut self, start: u32) -> Result<()> {
+        if start == 0 {
+            return Ok(());
+        }
+        for c in self.chain(start)? {
+            self.set_entry(c, 0);
+            let offset = self.cluster_offset(c);
+            self.dirty_regions.remove(&offset);
+        }
+        Ok(())
+    }
+
+    fn chain(&self, start: u32) -> Result<Vec<u32>> {
+        let mut chain = vec![];
+        let mut c = start;
+
+        loop {
+            anyhow::ensure!(
+                c >= 2 && c < self.cluster_count + 2 && chain.len() < self.cluster_count as usize,
+                "fat: corrupted cluster chain starting at {start}"
+            );
+            chain.push(c);
+            c = self.get_entry(c);
+            if c >= self.bad_cluster() {
+                break;
+            }
+        }
+
+        Ok(chain)
+    }
+
+    fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        match self.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        }
+    }
+
+    fn get_entry(&self, cluster: u32) -> u32 {
+        let offset = self.fat_entry_offset(cluster) as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let v = le16(&self.fat, offset) as u32;
+                if cluster & 1 == 1 { v >> 4 } else { v & 0xFFF }
+            }
+            FatType::Fat16 => le16(&self.fat, offset) as u32,
+            FatType::Fat32 => le32(&self.fat, offset) & 0x0FFF_FFFF,
+        }
+    }
+
+    fn set_entry(&mut self, cluster: u32, value: u32) {
+        let offset = self.fat_entry_offset(cluster) as usize;
+        match self.fat_type {
+            FatType::Fat12 => {
+                let old = le16(&self.fat, offset);
+                let new = if cluster & 1 == 1 {
+                    (old & 0x000F) | ((value as u16) << 4)
+                } else {
+                    (old & 0xF000) | (value as u16 & 0x0FFF)
+                };
+                set_le16(&mut self.fat, offset, new);
+            }
+            FatType::Fat16 => set_le16(&mut self.fat, offset, value as u16),
+            FatType::Fat32 => {
+                let old = le32(&self.fat, offset);
+                set_le32(
+                    &mut self.fat,
+                    offset,
+                    (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+                );
+            }
+        }
+        self.fat_dirty = true;
+    }
+
+    fn bad_cluster(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFF7,
+            FatType::Fat16 => 0xFFF7,
+            FatType::Fat32 => 0x0FFF_FFF7,
+        }
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.dev
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.dev.read_exact(buf))
+            .with_context(|| format!("fat: cannot read {} bytes at {offset}", buf.len()))
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.dev
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| self.dev.write_all(buf))
+            .with_context(|| format!("fat: cannot write {} bytes at {offset}", buf.len()))
+    }
+}
+
+impl<D: Read + Write + Seek> ReadLink for Fat<D> {
+    fn read_link(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+        // FAT doesn't know symbolic links, but we still report missing files
+        match self.lookup_path(path)? {
+            Some(_) => Ok(None),
+            None if path_names(path)?.is_empty() => Ok(None),
+            None => Err(io_error(
+                io::ErrorKind::NotFound,
+                format!("fat: {} not found", path.display()),
+            )),
+        }
+    }
+}
+
+impl<D: Read + Write + Seek> Filesystem for Fat<D> {
+    fn create_dir(&mut self, path: &Path) -> Result<()> {
+        let (parent, name) = split_path(path)?;
+        let dir = self.lookup_dir(parent)?;
+
+        if let Some(e) = self.lookup(dir, name)? {
+            let (kind, msg) = if e.attr & ATTR_DIRECTORY != 0 {
+                (io::ErrorKind::AlreadyExists, "already exists")
+            } else {
+                (
+                    io::ErrorKind::NotADirectory,
+                    "exists and is not a directory",
+                )
+            };
+            return Err(io_error(kind, format!("fat: {} {msg}", path.display())));
+        }
+
+        let cluster = self.alloc_clusters(1)?[0];
+        let parent_cluster = self.parent_cluster(dir);
+
+        let mut data = vec![0u8; self.cluster_size as usize];
+        let (time, date) = dos_timestamp_now();
+        for (i, (short_name, c)) in [
+            (*b".          ", cluster),
+            (*b"..         ", parent_cluster),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let entry = &mut data[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE];
+            entry[..11].copy_from_slice(short_name);
+            entry[11] = ATTR_DIRECTORY;
+            set_le16(entry, 0x0E, time);
+            set_le16(entry, 0x10, date);
+            set_le16(entry, 0x12, date);
+            set_le16(entry, 0x14, (c >> 16) as u16);
+            set_le16(entry, 0x16, time);
+            set_le16(entry, 0x18, date);
+            set_le16(entry, 0x1A, *c as u16);
+        }
+        let offset = self.cluster_offset(cluster);
+        self.dirty_regions.insert(offset, data);
+
+        self.add_entry(dir, name, ATTR_DIRECTORY, cluster, 0)
+    }
+
+    fn write_file(&mut self, path: &Path, data: &mut dyn Read, len: u64) -> Result<()> {
+        anyhow::ensure!(
+            len <= u32::MAX as u64,
+            "fat: {} exceeds the maximum file size",
+            path.display()
+        );
+
+        let (parent, name) = split_path(path)?;
+        let dir = self.lookup_dir(parent)?;
+        let existing = self.lookup(dir, name)?;
+
+        if existing
+            .as_ref()
+            .is_some_and(|e| e.attr & ATTR_DIRECTORY != 0)
+        {
+            return Err(io_error(
+                io::ErrorKind::IsADirectory,
+                format!("fat: {} is a directory", path.display()),
+            ));
+        }
+
+        // The old chain is only released up front if the new content doesn't
+        // fit beside it. Otherwise it is kept until the new content is
+        // written, so that a failing write leaves it untouched.
+        let mut old_chain = existing.as_ref().map_or(0, |e| e.cluster);
+        if old_chain != 0 && self.free_clusters() < len.div_ceil(self.cluster_size) {
+            self.free_chain(old_chain)?;
+            old_chain = 0;
+        }
+
+        let cluster = self
+            .write_data(data, len)
+            .with_context(|| format!("fat: cannot write data of {}", path.display()))?;
+        self.free_chain(old_chain)?;
+
+        match existing {
+            Some(e) => self.update_entry(dir, &e, cluster, len as u32),
+            None => self.add_entry(dir, name, ATTR_ARCHIVE, cluster, len as u32),
+        }
+    }
+
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> Result<u64> {
+        let entry = self.lookup_path(path)?.ok_or_else(|| {
+            io_error(
+                io::ErrorKind::NotFound,
+                format!("fat: {} not found", path.display()),
+            )
+        })?;
+
+        if entry.attr & ATTR_DIRECTORY != 0 {
+            return Err(io_error(
+                io::ErrorKind::IsADirectory,
+                format!("fat: {} is a directory", path.display()),
+            ));
+        }
+
+        let size = entry.size as u64;
+        if size == 0 {
+            return Ok(0);
+        }
+
+        let mut buf = vec![0u8; self.cluster_size as usize];
+        let mut remaining = size;
+        for c in self.chain(entry.cluster)? {
+            let n = remaining.min(self.cluster_size) as usize;
+            self.read_at(self.cluster_offset(c), &mut buf[..n])?;
+            out.write_all(&buf[..n])?;
+            remaining -= n as u64;
+            if remaining == 0 {
+                break;
+            }
+        }
+
+        anyhow::ensure!(
+            remaining == 0,
+            "fat: cluster chain of {} is shorter than its size",
+            path.display()
+        );
+
+        Ok(size)
+    }
+
+    fn remove_file(&mut self, path: &Path) -> Result<()> {
+        let (dir, entry) = self.lookup_entry(path)?;
+
+        if entry.attr & ATTR_DIRECTORY != 0 {
+            return Err(io_error(
+                io::ErrorKind::IsADirectory,
+                format!("fat: {} is a directory", path.display()),
+            ));
+        }
+
+        self.free_chain(entry.cluster)?;
+        self.delete_entry(dir, &entry)
+    }
+
+    fn remove_dir(&mut self, path: &Path) -> Result<()> {
+        let (dir, entry) = self.lookup_entry(path)?;
+
+        if entry.attr & ATTR_DIRECTORY == 0 {
+            return Err(io_error(
+                io::ErrorKind::NotADirectory,
+                format!("fat: {} is not a directory", path.display()),
+            ));
+        }
+
+        let content = self.read_dir(dir_of(&entry, self.root()))?;
+        if parse_entries(&content.data)
+            .iter()
+            .any(|e| e.name != "." && e.name != "..")
+        {
+            return Err(io_error(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("fat: {} is not empty", path.display()),
+            ));
+        }
+
+        self.free_chain(entry.cluster)?;
+        self.delete_entry(dir, &entry)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()> {
+        let (from_dir, entry) = self.lookup_entry(from)?;
+        let (to_parent, to_name) = split_path(to)?;
+        let to_dir = self.lookup_dir(to_parent)?;
+        let is_dir = entry.attr & ATTR_DIRECTORY != 0;
+
+        if is_dir && !self.is_same_path(from, to) && upper(to).starts_with(upper(from)) {
+            return Err(io_error(
+                io::ErrorKind::InvalidInput,
+                format!("fat: cannot move {} into itself", from.display()),
+            ));
+        }
+
+        if let Some(existing) = self.lookup(to_dir, to_name)? {
+            // a matching entry may be the source itself, e.g. if only the
+            // case of the name changes
+            if from_dir != to_dir || existing.slot != entry.slot {
+                match (is_dir, existing.attr & ATTR_DIRECTORY != 0) {
+                    (true, true) => self.remove_dir(to)?,
+                    (false, false) => self.remove_file(to)?,
+                    (true, false) => {
+                        return Err(io_error(
+                            io::ErrorKind::NotADirectory,
+                            format!("fat: {} is not a directory", to.display()),
+                        ));
+                    }
+                    (false, true) => {
+                        return Err(io_error(
+                            io::ErrorKind::IsADirectory,
+                            format!("fat: {} is a directory", to.display()),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let raw = self.raw_entry(from_dir, &entry)?;
+        self.delete_entry(from_dir, &entry)?;
+        self.insert_entry(to_dir, to_name, raw)?;
+
+        if is_dir && from_dir != to_dir {
+            let moved = dir_of(&entry, self.root());
+            let mut content = self.read_dir(moved)?;
+            let dotdot = parse_entries(&content.data)
+                .into_iter()
+                .find(|e| e.short_name == *b"..         ")
+                .with_context(|| format!("fat: {} has no \"..\" entry", from.display()))?;
+            let parent = self.parent_cluster(to_dir);
+            let raw = &mut content.data[dotdot.slot * DIR_ENTRY_SIZE..];
+            set_le16(raw, 0x14, (parent >> 16) as u16);
+            set_le16(raw, 0x1A, parent as u16);
+            self.write_dir(&content);
+        }
+
+        debug!("fat: renamed {} to {}", from.display(), to.display());
+
+        Ok(())
+    }
+
+    fn is_same_path(&self, a: &Path, b: &Path) -> bool {
+        upper(a) == upper(b)
+    }
+
+    fn metadata(&mut self, path: &Path) -> Result<Metadata> {
+        if path_names(path)?.is_empty() {
+            return Ok(root_metadata());
+        }
+        match self.lookup_path(path)? {
+            Some(e) => Ok(e.metadata()),
+            None => Err(io_error(
+                io::ErrorKind::NotFound,
+                format!("fat: {} not found", path.display()),
+            )),
+        }
+    }
+
+    fn usage(&mut self) -> Result<Usage> {
+        Ok(Usage {
+            total: self.cluster_count as u64 * self.cluster_size,
+            free: self.free_clusters() * self.cluster_size,
+            block_size: self.cluster_size,
+        })
+    }
+
+    // FAT has no owners and only knows a read-only flag instead of permissions
+    fn set_attributes(&mut self, path: &Path, attributes: &FileAttributes) -> Result<()> {
+        let (dir, entry) = self.lookup_entry(path)?;
+
+        if attributes.uid.is_some_and(|uid| uid != 0) || attributes.gid.is_some_and(|gid| gid != 0)
+        {
+            warn!("fat: ownership of {} is not supported", path.display());
+        }
+
+        let mut content = self.read_dir(dir)?;
+        let raw = &mut content.data[entry.slot * DIR_ENTRY_SIZE..(entry.slot + 1) * DIR_ENTRY_SIZE];
+        if let Some(mode) = attributes.mode {
+            if mode & 0o200 == 0 {
+                raw[11] |= ATTR_READ_ONLY;
+            } else {
+                raw[11] &= !ATTR_READ_ONLY;
+            }
+        }
+        if let Some(mtime) = attributes.mtime {
+            let (time, date) = dos_timestamp(mtime.max(0) as u64);
+            set_le16(raw, 0x16, time);
+            set_le16(raw, 0x18, date);
+        }
+        self.write_dir(&content);
+
+        debug!("fat: set {attributes:?} on {}", path.display());
+
+        Ok(())
+    }
+
+    fn list_dir(&mut self, path: &Path) -> Result<Vec<(OsString, Metadata)>> {
+        let dir = self.lookup_dir(path)?;
+        let content = self.read_dir(dir)?;
+
+        Ok(parse_entries(&content.data)
+            .into_iter()
+            .filter(|e| e.name != "." && e.name != "..")
+            .map(|e| (OsString::from(&e.name), e.metadata()))
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let mut regions: Vec<_> = self.dirty_regions.drain().collect();
+        regions.sort_by_key(|(offset, _)| *offset);
+        for (offset, data) in regions {
+            self.write_at(offset, &data)?;
+        }
+
+        if std::mem::take(&mut self.fat_dirty) {
+            let fat = std::mem::take(&mut self.fat);
+            for i in 0..self.num_fats {
+                self.write_at(self.fat_offset + i * self.fat_size, &fat)?;
+            }
+            self.fat = fat;
+
+            if let Some(sector) = self.fsinfo_sector {
+                let offset = sector * self.bytes_per_sector;
+                let mut fsinfo = [0u8; 512];
+                self.read_at(offset, &mut fsinfo)?;
+                if le32(&fsinfo, 0) == FSINFO_LEAD_SIG && le32(&fsinfo, 484) == FSINFO_STRUCT_SIG {
+                    let free = (2..self.cluster_count + 2)
+                        .filter(|c| self.get_entry(*c) == 0)
+                        .count() as u32;
+                    set_le32(&mut fsinfo, 488, free);
+                    set_le32(&mut fsinfo, 492, self.next_free);
+                    self.write_at(offset, &fsinfo)?;
+                }
+            }
+        }
+
+        self.dev.flush().context("fat: cannot flush device")
+    }
+}
+
+impl DirEntry {
+    // FAT has no notion of owners and permissions, so we report what a
+    // default vfat mount would show
+    fn metadata(&self) -> Metadata {
+        let is_dir = self.attr & ATTR_DIRECTORY != 0;
+        let mode = match (is_dir, self.attr & ATTR_READ_ONLY != 0) {
+            (true, _) => 0o755,
+            (false, false) => 0o644,
+            (false, true) => 0o444,
+        };
+
+        Metadata {
+            file_type: if is_dir {
+                FileType::Dir
+            } else {
+                FileType::File
+            },
+            size: self.size as u64,
+            mode,
+            uid: 0,
+            gid: 0,
+            mtime: self.mtime,
+        }
+    }
+}
+
+fn root_metadata() -> Metadata {
+    Metadata {
+        file_type: FileType::Dir,
+        size: 0,
+        mode: 0o755,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+    }
+}
+
+fn dir_of(entry: &DirEntry, root: Dir) -> Dir {
+    // ".." entries pointing to the root directory carry cluster 0
+    match entry.cluster {
+        0 => root,
+        c => Dir::Cluster(c),
+    }
+}
+
+fn parse_entries(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = vec![];
+    let mut lfn: Vec<u16> = vec![];
+    let mut lfn_checksum = 0;
+    let mut lfn_next = 0u8;
+    let mut lfn_start = 0;
+
+    for (slot, raw) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                lfn.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        if raw[11] & 0x3F == ATTR_LFN {
+            let seq = raw[0] & 0x1F;
+            if raw[0] & 0x40 != 0 {
+                lfn = vec![0xFFFF; seq as usize * LFN_CHARS_PER_ENTRY];
+                lfn_checksum = raw[13];
+                lfn_start = slot;
+            } else if seq != lfn_next || raw[13] != lfn_checksum {
+                lfn.clear();
+            }
+            if seq == 0 || lfn.is_empty() {
+                lfn.clear();
+                continue;
+            }
+            let base = (seq as usize - 1) * LFN_CHARS_PER_ENTRY;
+            for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+                lfn[base + i] = le16(raw, *offset);
+            }
+            lfn_next = seq - 1;
+            continue;
+        }
+
+        if raw[11] & ATTR_VOLUME_ID != 0 {
+            lfn.clear();
+            continue;
+        }
+
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&raw[..11]);
+        if short_name[0] == 0x05 {
+            short_name[0] = ENTRY_DELETED;
+        }
+
+        let long_name =
+            (!lfn.is_empty() && lfn_next == 0 && lfn_checksum == lfn_checksum_of(&short_name))
+                .then(|| {
+                    let end = lfn
+                        .iter()
+                        .position(|c| *c == 0 || *c == 0xFFFF)
+                        .unwrap_or(lfn.len());
+                    String::from_utf16_lossy(&lfn[..end])
+                });
+
+        let first_slot = if long_name.is_some() { lfn_start } else { slot };
+
+        entries.push(DirEntry {
+            name: long_name.unwrap_or_else(|| display_short_name(&short_name, raw[12])),
+            short_name,
+            attr: raw[11],
+            cluster: (le16(raw, 0x14) as u32) << 16 | le16(raw, 0x1A) as u32,
+            size: le32(raw, 0x1C),
+            mtime: dos_timestamp_to_unix(le16(raw, 0x16), le16(raw, 0x18)),
+            first_slot,
+            slot,
+        });
+        lfn.clear();
+    }
+
+    entries
+}
+
+fn is_free_slot(data: &[u8], slot: usize) -> bool {
+    matches!(data[slot * DIR_ENTRY_SIZE], ENTRY_END | ENTRY_DELETED)
+}
+
+fn find_free_slots(data: &[u8], count: usize) -> Option<usize> {
+    let slots = data.len() / DIR_ENTRY_SIZE;
+    let mut run = 0;
+
+    for slot in 0..slots {
+        if data[slot * DIR_ENTRY_SIZE] == ENTRY_END {
+            // everything behind the end marker is free
+            return (slots - slot + run >= count).then_some(slot - run);
+        }
+        if is_free_slot(data, slot) {
+            run += 1;
+            if run == count {
+                return Some(slot + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+
+    None
+}
+
+fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= MAX_LFN_LEN
+        && !name.ends_with(' ')
+        && !name.ends_with('.')
+        && name
+            .chars()
+            .all(|c| c as u32 >= 0x20 && !"\"*/:<>?\\|".contains(c))
+}
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+// Returns the 8.3 name for `name`, the case flags to store and whether long
+// file name entries are needed.
+fn make_short_name(name: &str, existing: &[DirEntry]) -> ([u8; 11], u8, bool) {
+    let (base, ext) = match name.rfind('.') {
+        Some(pos) if pos > 0 => (&name[..pos], &name[pos + 1..]),
+        _ => (name, ""),
+    };
+
+    let fits = !base.is_empty()
+        && base.len() <= 8
+        && ext.len() <= 3
+        && base.chars().chain(ext.chars()).all(is_short_name_char);
+
+    if fits {
+        let case_of = |s: &str| {
+            let lower = s.chars().any(|c| c.is_ascii_lowercase());
+            let upper = s.chars().any(|c| c.is_ascii_uppercase());
+            (lower, upper)
+        };
+        let (base_lower, base_upper) = case_of(base);
+        let (ext_lower, ext_upper) = case_of(ext);
+
+        if !(base_lower && base_upper || ext_lower && ext_upper) {
+            let mut short_name = [b' '; 11];
+            short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+            short_name[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+            let mut case = 0;
+            if base_lower {
+                case |= CASE_LOWER_BASE;
+            }
+            if ext_lower {
+                case |= CASE_LOWER_EXT;
+            }
+            if existing.iter().all(|e| e.short_name != short_name) {
+                return (short_name, case, false);
+            }
+        }
+    }
+
+    let convert = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if is_short_name_char(c) { c as u8 } else { b'_' }
+            })
+            .collect()
+    };
+    let mut basis = convert(base.trim_start_matches('.'));
+    if basis.is_empty() {
+        basis.push(b'_');
+    }
+    let ext = convert(ext);
+
+    for n in 1u32.. {
+        let tail = format!("~{n}");
+        let keep = basis.len().min(8 - tail.len());
+        let mut short_name = [b' '; 11];
+        short_name[..keep].copy_from_slice(&basis[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        short_name[8..8 + ext.len().min(3)].copy_from_slice(&ext[..ext.len().min(3)]);
+        if existing.iter().all(|e| e.short_name != short_name) {
+            return (short_name, 0, true);
+        }
+    }
+
+    unreachable!()
+}
+
+fn make_lfn_entries(name: &str, short_name: &[u8; 11]) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    if !chars.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        chars.push(0);
+    }
+    chars.resize(chars.len().next_multiple_of(LFN_CHARS_PER_ENTRY), 0xFFFF);
+
+    let count = chars.len() / LFN_CHARS_PER_ENTRY;
+    let checksum = lfn_checksum_of(short_name);
+
+    (1..=count)
+        .rev()
+        .map(|seq| {
+            let mut entry = [0u8; DIR_ENTRY_SIZE];
+            entry[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+            entry[11] = ATTR_LFN;
+            entry[13] = checksum;
+            let part = &chars[(seq - 1) * LFN_CHARS_PER_ENTRY..seq * LFN_CHARS_PER_ENTRY];
+            for (c, offset) in part.iter().zip(LFN_CHAR_OFFSETS) {
+                set_le16(&mut entry, offset, *c);
+            }
+            entry
+        })
+        .collect()
+}
+
+fn lfn_checksum_of(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+fn short_name_string(short_name: &[u8; 11]) -> String {
+    display_short_name(short_name, 0)
+}
+
+fn display_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let s = String::from_utf8_lossy(bytes).trim_end().to_string();
+        if lower { s.to_ascii_lowercase() } else { s }
+    };
+    let base = part(&short_name[..8], case & CASE_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{base}.{ext}")
+    }
+}
+
+fn path_names(path: &Path) -> Result<Vec<&str>> {
+    let mut names = vec![];
+    for c in path.components() {
+        match c {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(name) => names.push(
+                name.to_str()
+                    .with_context(|| format!("fat: invalid path {}", path.display()))?,
+            ),
+            _ => anyhow::bail!("fat: unsupported path {}", path.display()),
+        }
+    }
+    Ok(names)
+}
+
+fn split_path(path: &Path) -> Result<(&Path, &str)> {
+    let parent = path
+        .parent()
+        .with_context(|| format!("fat: invalid path {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .with_context(|| format!("fat: invalid path {}", path.display()))?;
+    Ok((parent, name))
+}
+
+// names are case insensitive, so paths are compared in upper case
+fn upper(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_uppercase())
+}
+
+// FAT stores local time, we use UTC since there is no sensible time zone for an image
+fn dos_timestamp_now() -> (u16, u16) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    dos_timestamp(secs)
+}
+
+fn dos_timestamp(secs: u64) -> (u16, u16) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil date from days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = ((rem / 3600) << 11) | ((rem % 3600 / 60) << 5) | ((rem % 60) / 2);
+    let date = ((year - 1980).clamp(0, 127) << 9) | (month << 5) | day;
+
+    (time as u16, date as u16)
+}
+
+fn dos_timestamp_to_unix(time: u16, date: u16) -> i64 {
+    if date == 0 {
+        return 0;
+    }
+
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xF).clamp(1, 12) as i64;
+    let day = (date & 0x1F).max(1) as i64;
+
+    // days since 1970-01-01 from civil date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs =
+        (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    days * 86400 + secs
+}
+
+fn le16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn le32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+fn set_le16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn set_le32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{File, OpenOptions};
+    use std::process::Command;
+
+    // Creates a filesystem of the given FAT type with 512 byte clusters, which
+    // needs a size matching the cluster count range of that type.
+    fn mkfs(dir: &Path, fat_type: u32) -> PathBuf {
+        let image = dir.join(format!("fat{fat_type}.img"));
+        let size = match fat_type {
+            12 => 1024,
+            16 => 4 * 1024,
+            _ => 40 * 1024,
+        };
+        let output = Command::new("mkfs.vfat")
+            .args(["-C", "-F", &fat_type.to_string(), "-s", "1"])
+            .arg(&image)
+            .arg(size.to_string())
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        image
+    }
+
+    fn open(image: &Path) -> Fat<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image)
+            .unwrap();
+        Fat::open(file).unwrap()
+    }
+
+    fn fsck(image: &Path) {
+        let output = Command::new("fsck.fat")
+            .arg("-n")
+            .arg(image)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    fn mtools(tool: &str, image: &Path, path: &str) -> Vec<u8> {
+        let output = Command::new(tool)
+            .env("MTOOLS_SKIP_CHECK", "1")
+            .arg("-i")
+            .arg(image)
+            .arg(format!("::{path}"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output.stdout
+    }
+
+    fn write(fs: &mut Fat<File>, path: &str, data: &[u8]) {
+        fs.write_file(Path::new(path), &mut &data[..], data.len() as u64)
+            .unwrap();
+    }
+
+    fn read(fs: &mut Fat<File>, path: &str) -> Vec<u8> {
+        let mut data = vec![];
+        fs.read_file(Path::new(path), &mut data).unwrap();
+        data
+    }
+
+    fn cluster(fs: &mut Fat<File>, path: &str) -> u32 {
+        fs.lookup_path(Path::new(path)).unwrap().unwrap().cluster
+    }
+
+    #[test]
+    fn roundtrip() {
+        for fat_type in [12, 16, 32] {
+            let tmp = tempfile::tempdir().unwrap();
+            let image = mkfs(tmp.path(), fat_type);
+            let mut fs = open(&image);
+            let free = fs.usage().unwrap().free;
+
+            fs.create_dir(Path::new("/dir")).unwrap();
+            fs.create_dir(Path::new("/dir/sub")).unwrap();
+            let data: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+            write(&mut fs, "/dir/sub/a long file name.txt", &data);
+            write(&mut fs, "/BOOT.SCR", b"boot");
+            write(&mut fs, "/empty", b"");
+
+            // 40 entries with long names need several clusters of 16 entries
+            for i in 0..40 {
+                write(&mut fs, &format!("/dir/config-file-{i}.json"), b"{}");
+            }
+            let dir = cluster(&mut fs, "/dir");
+            assert!(fs.chain(dir).unwrap().len() > 1);
+
+            write(&mut fs, "/BOOT.SCR", &data);
+            fs.rename(
+                Path::new("/dir/sub/a long file name.txt"),
+                Path::new("/renamed.txt"),
+            )
+            .unwrap();
+            fs.rename(Path::new("/dir/sub"), Path::new("/sub")).unwrap();
+            for i in 0..20 {
+                fs.remove_file(Path::new(&format!("/dir/config-file-{i}.json")))
+                    .unwrap();
+            }
+            fs.remove_dir(Path::new("/sub")).unwrap();
+            fs.flush().unwrap();
+            fsck(&image);
+
+            let mut fs = open(&image);
+            assert_eq!(read(&mut fs, "/renamed.txt"), data);
+            assert_eq!(read(&mut fs, "/boot.scr"), data);
+            assert_eq!(read(&mut fs, "/empty"), b"");
+            assert_eq!(read(&mut fs, "/dir/config-file-39.json"), b"{}");
+            assert!(fs.lookup_path(Path::new("/sub")).unwrap().is_none());
+
+            assert_eq!(mtools("mtype", &image, "/renamed.txt"), data);
+            assert_eq!(mtools("mtype", &image, "/BOOT.SCR"), data);
+            assert_eq!(mtools("mtype", &image, "/dir/config-file-20.json"), b"{}");
+            let root = String::from_utf8(mtools("mdir", &image, "/")).unwrap();
+            assert!(root.contains("renamed.txt") && !root.contains("sub"));
+            let dir = String::from_utf8(mtools("mdir", &image, "/dir")).unwrap();
+            assert!(dir.contains("config-file-39.json"));
+            assert!(!dir.contains("config-file-19.json"));
+
+            fs.remove_dir_all(Path::new("/dir")).unwrap();
+            for path in ["/renamed.txt", "/BOOT.SCR", "/empty"] {
+                fs.remove_file(Path::new(path)).unwrap();
+            }
+            fs.flush().unwrap();
+            assert_eq!(fs.usage().unwrap().free, free);
+            fsck(&image);
+        }
+    }
+
+    #[test]
+    fn overwrite() {
+        let tmp = tempfile::tempdir().unwrap();
+        let image = mkfs(tmp.path(), 16);
+        let mut fs = open(&image);
+        let old = vec![0x11u8; 1024 * 1024];
+        write(&mut fs, "/file", &old);
+        fs.flush().unwrap();
+
+        // the old content survives a failing overwrite
+        assert!(
+            fs.write_file(Path::new("/file"), &mut &b"short"[..], 1024)
+                .is_err()
+        );
+        assert_eq!(read(&mut fs, "/file"), old);
+
+        // with room for both the new content goes to other clusters
+        let start = cluster(&mut fs, "/file");
+        let new = vec![0x22u8; 1024 * 1024];
+        write(&mut fs, "/file", &new);
+        fs.flush().unwrap();
+        assert_ne!(cluster(&mut fs, "/file"), start);
+        assert_eq!(read(&mut fs, "/file"), new);
+        fsck(&image);
+
+        // without room for both the old clusters are reused
+        let free = fs.usage().unwrap().free;
+        write(&mut fs, "/fill", &vec![0x33u8; free as usize - 512 * 1024]);
+        let new = vec![0x44u8; 1024 * 1024];
+        write(&mut fs, "/file", &new);
+        fs.flush().unwrap();
+        assert_eq!(read(&mut fs, "/file"), new);
+        assert_eq!(mtools("mtype", &image, "/file"), new);
+        fsck(&image);
+    }
+
+    #[test]
+    fn short_names() {
+        assert_eq!(
+            make_short_name("BOOT.SCR", &[]),
+            (*b"BOOT    SCR", 0, false)
+        );
+        assert_eq!(
+            make_short_name("boot.scr", &[]),
+            (*b"BOOT    SCR", CASE_LOWER_BASE | CASE_LOWER_EXT, false)
+        );
+        assert_eq!(make_short_name("Boot.scr", &[]), (*b"BOOT~1  SCR", 0, true));
+        assert_eq!(
+            make_short_name("a long file name.conf", &[]),
+            (*b"ALONGF~1CON", 0, true)
+        );
+    }
+
+    #[test]
+    fn lfn_roundtrip() {
+        let name = "my-long-file-name.json";
+        let (short_name, _, _) = make_short_name(name, &[]);
+        let mut data = vec![0u8; 8 * DIR_ENTRY_SIZE];
+        let mut slots = make_lfn_entries(name, &short_name);
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[..11].copy_from_slice(&short_name);
+        slots.push(entry);
+        for (i, slot) in slots.iter().enumerate() {
+            data[i * DIR_ENTRY_SIZE..(i + 1) * DIR_ENTRY_SIZE].copy_from_slice(slot);
+        }
+
+        let entries = parse_entries(&data);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, name);
+        assert_eq!(entries[0].slot, slots.len() - 1);
+    }
+
+    #[test]
+    fn dos_timestamps() {
+        // 2024-02-29 13:37:42 UTC
+        assert_eq!(
+            dos_timestamp(1709213862),
+            (13 << 11 | 37 << 5 | 21, 44 << 9 | 2 << 5 | 29)
+        );
+        assert_eq!(
+            dos_timestamp_to_unix(13 << 11 | 37 << 5 | 21, 44 << 9 | 2 << 5 | 29),
+            1709213862
+        );
+        assert_eq!(dos_timestamp_to_unix(0, 0), 0);
+    }
+}
@@ -0,0 +1,267 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+// same limit as the linux kernel uses for path resolution
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum FileType {
+    File,
+    Dir,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    /// permission bits including setuid, setgid and sticky bit
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// seconds since the unix epoch
+    pub mtime: i64,
+}
+
+/// Ownership, permissions and modification time of a file written to an
+/// image. Fields which are not set keep the defaults of the filesystem, i.e.
+/// root ownership, mode 0644 and the time of writing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// permission bits including setuid, setgid and sticky bit
+    pub mode: Option<u32>,
+    /// seconds since the unix epoch
+    pub mtime: Option<i64>,
+}
+
+/// Size and free space of a filesystem in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Usage {
+    pub total: u64,
+    pub free: u64,
+    /// allocation unit, i.e. ext4 block or FAT cluster
+    pub block_size: u64,
+}
+
+/// Access to symbolic links, which is all that path resolution needs.
+pub(crate) trait ReadLink {
+    /// Returns the target of `path` if it is a symbolic link. The last path
+    /// component is not followed, all others must not be links.
+    fn read_link(&mut self, path: &Path) -> Result<Option<PathBuf>>;
+}
+
+/// Common interface of the in-process filesystem implementations used to
+/// access files inside of partitions.
+pub(crate) trait Filesystem: ReadLink {
+    /// Creates a single directory. Fails with `io::ErrorKind::AlreadyExists`
+    /// if a directory of that name already exists and with
+    /// `io::ErrorKind::NotADirectory` if another kind of file is in the way.
+    fn create_dir(&mut self, path: &Path) -> Result<()>;
+
+    /// Creates or overwrites a regular file with `len` bytes from `data`.
+    fn write_file(&mut self, path: &Path, data: &mut dyn Read, len: u64) -> Result<()>;
+
+    /// Copies the content of a regular file to `out` and returns its size.
+    fn read_file(&mut self, path: &Path, out: &mut dyn Write) -> Result<u64>;
+
+    /// Removes a regular file or symbolic link.
+    fn remove_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Removes an empty directory. Fails with
+    /// `io::ErrorKind::DirectoryNotEmpty` if the directory has entries.
+    fn remove_dir(&mut self, path: &Path) -> Result<()>;
+
+    /// Moves `from` to `to` within the filesystem. An existing file or empty
+    /// directory at `to` is replaced, if it is of the same kind as `from`.
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Returns the metadata of `path`. A symbolic link as last component is
+    /// not followed.
+    fn metadata(&mut self, path: &Path) -> Result<Metadata>;
+
+    /// Applies all fields set in `attributes` to `path`. A symbolic link as
+    /// last component is not followed.
+    fn set_attributes(&mut self, path: &Path, attributes: &FileAttributes) -> Result<()>;
+
+    /// Returns name and metadata of all entries of the directory `path`
+    /// except for "." and "..".
+    fn list_dir(&mut self, path: &Path) -> Result<Vec<(OsString, Metadata)>>;
+
+    /// Returns size and free space of the filesystem.
+    fn usage(&mut self) -> Result<Usage>;
+
+    /// Writes all pending metadata changes to the underlying device.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Returns whether both paths name the same entry, e.g. paths which only
+    /// differ in case on filesystems with case insensitive names.
+    fn is_same_path(&self, a: &Path, b: &Path) -> bool {
+        a == b
+    }
+
+    fn remove_dir_all(&mut self, path: &Path) -> Result<()> {
+        for (name, metadata) in self.list_dir(path)? {
+            let child = path.join(name);
+            if metadata.file_type == FileType::Dir {
+                self.remove_dir_all(&child)?;
+            } else {
+                self.remove_file(&child)?;
+            }
+        }
+        self.remove_dir(path)
+    }
+
+    fn create_dir_all(&mut self, path: &Path) -> Result<()> {
+        for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+            if dir.parent().is_none() {
+                continue;
+            }
+            if let Err(e) = self.create_dir(dir)
+                && !is_error_kind(&e, io::ErrorKind::AlreadyExists)
+            {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolves symbolic links in `path` relative to the root of `fs` and returns
+/// an absolute path without links. The last component is only resolved if
+/// `follow_last` is set. Components which don't exist are kept as they are, so
+/// that the result can be used to create new files as well.
+pub(crate) fn resolve_path<F: ReadLink + ?Sized>(
+    fs: &mut F,
+    path: &Path,
+    follow_last: bool,
+) -> Result<PathBuf> {
+    let mut pending = VecDeque::new();
+    push_components(&mut pending, path);
+
+    let mut resolved = PathBuf::from("/");
+    let mut follows = 0;
+    let mut missing = false;
+
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&name);
+
+        if missing || (pending.is_empty() && !follow_last) {
+            resolved = candidate;
+            continue;
+        }
+
+        match fs.read_link(&candidate) {
+            Ok(Some(target)) => {
+                follows += 1;
+                anyhow::ensure!(
+                    follows <= MAX_SYMLINK_FOLLOWS,
+                    "resolve_path: too many levels of symbolic links in {}",
+                    path.display()
+                );
+                if target.is_absolute() {
+                    resolved = PathBuf::from("/");
+                }
+                let mut target_components = VecDeque::new();
+                push_components(&mut target_components, &target);
+                target_components.extend(pending);
+                pending = target_components;
+            }
+            Ok(None) => resolved = candidate,
+            Err(e) if is_error_kind(&e, io::ErrorKind::NotFound) => {
+                missing = true;
+                resolved = candidate;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(resolved)
+}
+
+fn push_components(pending: &mut VecDeque<OsString>, path: &Path) {
+    for c in path.components() {
+        match c {
+            Component::Normal(name) => pending.push_back(name.to_os_string()),
+            Component::ParentDir => pending.push_back(OsString::from("..")),
+            _ => {}
+        }
+    }
+}
+
+pub(crate) fn io_error(kind: io::ErrorKind, msg: String) -> anyhow::Error {
+    io::Error::new(kind, msg).into()
+}
+
+pub(crate) fn is_error_kind(e: &anyhow::Error, kind: io::ErrorKind) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // minimal in-memory filesystem which only knows about symbolic links
+    struct Links(HashMap<PathBuf, PathBuf>);
+
+    impl ReadLink for Links {
+        fn read_link(&mut self, path: &Path) -> Result<Option<PathBuf>> {
+            Ok(self.0.get(path).cloned())
+        }
+    }
+
+    fn links(links: &[(&str, &str)]) -> Links {
+        Links(
+            links
+                .iter()
+                .map(|(l, t)| (PathBuf::from(l), PathBuf::from(t)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn resolve_links() {
+        let mut fs = links(&[
+            ("/etc/os-release", "../usr/lib/os-release"),
+            ("/etc/localtime", "/usr/share/zoneinfo/UTC"),
+            ("/usr/share/zoneinfo/UTC", "Etc/UTC"),
+            ("/var/run", "/run"),
+        ]);
+
+        for (path, follow_last, expected) in [
+            ("/etc/os-release", true, "/usr/lib/os-release"),
+            ("/etc/os-release", false, "/etc/os-release"),
+            ("/etc/localtime", true, "/usr/share/zoneinfo/Etc/UTC"),
+            ("/var/run/foo/../bar", true, "/run/bar"),
+            ("/etc/hosts", true, "/etc/hosts"),
+        ] {
+            assert_eq!(
+                resolve_path(&mut fs, Path::new(path), follow_last).unwrap(),
+                PathBuf::from(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn resolve_link_loop() {
+        let mut fs = links(&[("/a", "b"), ("/b", "/c/../a")]);
+        assert!(resolve_path(&mut fs, Path::new("/a/file"), true).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use super::bmap::{Bmap, to_hex};
+use super::compression::Compression;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+const IO_CHUNK_SIZE: usize = 1024 * 1024;
+const MOUNTS: &str = "/proc/self/mounts";
+const SWAPS: &str = "/proc/swaps";
+const SYSFS_BLOCK_DEVICES: &str = "/sys/dev/block";
+
+/// Writes a possibly compressed image to a block device or regular file.
+///
+/// If a bmap file is given or found next to the image, e.g. `image.wic.bmap`
+/// for `image.wic.xz`, only the mapped blocks are written and their checksums
+/// are verified. Otherwise the whole image is written. Block devices which are
+/// mounted or not removable are refused unless `force` is set.
+pub fn flash_image(
+    image_file: &Path,
+    bmap_file: Option<&Path>,
+    target: &Path,
+    force: bool,
+) -> Result<()> {
+    let source = File::open(image_file).context(format!(
+        "flash_image: cannot open image {}",
+        image_file.display()
+    ))?;
+    let compression = Compression::from_file(image_file)?;
+
+    let bmap = match bmap_file
+        .map(Path::to_path_buf)
+        .or_else(|| find_bmap(image_file, compression.is_some()))
+    {
+        Some(bmap_file) => {
+            debug!("flash_image: using bmap file {}", bmap_file.display());
+            Some(Bmap::from_file(&bmap_file)?)
+        }
+        None => {
+            warn!("flash_image: no bmap file found, writing the whole image without verification");
+            None
+        }
+    };
+
+    // the size of compressed images without bmap is unknown before writing
+    let image_size = match (&bmap, &compression) {
+        (Some(bmap), _) => Some(bmap.image_size),
+        (None, None) => Some(source.metadata()?.len()),
+        (None, Some(_)) => None,
+    };
+
+    if let (Some(bmap), None) = (&bmap, &compression) {
+        let len = source.metadata()?.len();
+        anyhow::ensure!(
+            len == bmap.image_size,
+            "flash_image: image has {len} bytes but bmap file expects {} bytes",
+            bmap.image_size
+        );
+    }
+
+    let (mut target_file, is_device) = open_target(image_file, target, force)?;
+
+    if is_device && let Some(image_size) = image_size {
+        let device_size = target_file.seek(SeekFrom::End(0))?;
+        anyhow::ensure!(
+            device_size >= image_size,
+            "flash_image: {} has {device_size} bytes, image needs {image_size} bytes",
+            target.display()
+        );
+    }
+
+    let mut source: Box<dyn Read> = match &compression {
+        Some(c) => c.decoder(source)?,
+        None => Box::new(source),
+    };
+
+    let written = match &bmap {
+        Some(bmap) => write_mapped(&mut source, &mut target_file, bmap)?,
+        None => {
+            target_file.rewind()?;
+            io::copy(&mut source, &mut target_file).context("flash_image: cannot write image")?
+        }
+    };
+
+    // unmapped blocks of regular files are holes
+    if !is_device {
+        target_file.set_len(image_size.unwrap_or(written))?;
+    }
+
+    target_file
+        .sync_all()
+        .context(format!("flash_image: cannot sync {}", target.display()))?;
+
+    info!(
+        "flash_image: wrote {written} bytes of {} to {}",
+        image_file.display(),
+        target.display()
+    );
+
+    Ok(())
+}
+
+// bmaptool's naming, i.e. next to the image with or without compression
+// extension
+fn find_bmap(image_file: &Path, compressed: bool) -> Option<PathBuf> {
+    let mut candidates = vec![];
+    if compressed {
+        candidates.push(image_file.with_extension("bmap"));
+    }
+    candidates.push(PathBuf::from(format!("{}.bmap", image_file.display())));
+
+    candidates
+        .into_iter()
+        .find(|c| c.try_exists().is_ok_and(|exists| exists))
+}
+
+// Returns the opened target and whether it is a block device. Regular files
+// are truncated, so that unmapped blocks become holes.
+fn open_target(image_file: &Path, target: &Path, force: bool) -> Result<(File, bool)> {
+    match fs::metadata(target) {
+        Ok(metadata) if metadata.file_type().is_block_device() => {
+            if !force {
+                check_device(target, metadata.rdev())?;
+            }
+            let file = OpenOptions::new()
+                .write(true)
+                .open(target)
+                .context(format!("open_target: cannot open {}", target.display()))?;
+            Ok((file, true))
+        }
+        Ok(metadata) => {
+            anyhow::ensure!(
+                metadata.is_file(),
+                "open_target: {} is neither a block device nor a regular file",
+                target.display()
+            );
+            let image = fs::metadata(image_file)?;
+            anyhow::ensure!(
+                (image.dev(), image.ino()) != (metadata.dev(), metadata.ino()),
+                "open_target: image and target are the same file"
+            );
+            Ok((create_target(target)?, false))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((create_target(target)?, false)),
+        Err(e) => Err(e).context(format!("open_target: cannot access {}", target.display())),
+    }
+}
+
+fn create_target(target: &Path) -> Result<File> {
+    File::create(target).context(format!("create_target: cannot create {}", target.display()))
+}
+
+// refuses disks which are not removable and disks of which the device itself or
+// one of its partitions is mounted or used as swap
+fn check_device(target: &Path, rdev: u64) -> Result<()> {
+    let device = sysfs_dir(rdev).context(format!(
+        "check_device: cannot find {} in sysfs",
+        target.display()
+    ))?;
+    let disk = if device.join("partition").exists() {
+        device.parent().context("check_device: cannot get disk")?
+    } else {
+        &device
+    };
+
+    for (source, used_as) in devices_in_use() {
+        let Ok(metadata) = fs::metadata(&source) else {
+            continue;
+        };
+        if !metadata.file_type().is_block_device() {
+            continue;
+        }
+        if sysfs_dir(metadata.rdev()).is_ok_and(|dir| dir.starts_with(&device)) {
+            anyhow::bail!(
+                "check_device: {} is in use as {used_as} by {source}, use --force to write anyway",
+                target.display()
+            );
+        }
+    }
+
+    let removable = fs::read_to_string(disk.join("removable")).unwrap_or_default();
+    anyhow::ensure!(
+        removable.trim() == "1",
+        "check_device: {} is not a removable device, use --force to write anyway",
+        target.display()
+    );
+
+    Ok(())
+}
+
+// sources of mounted filesystems and swap devices with their use
+fn devices_in_use() -> Vec<(String, String)> {
+    let mut devices = vec![];
+
+    for line in fs::read_to_string(MOUNTS).unwrap_or_default().lines() {
+        let mut fields = line.split_whitespace();
+        if let (Some(source), Some(mount_point)) = (fields.next(), fields.next()) {
+            devices.push((source.to_string(), format!("mount point {mount_point}")));
+        }
+    }
+
+    // skip the header line
+    for line in fs::read_to_string(SWAPS)
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+    {
+        if let Some(source) = line.split_whitespace().next() {
+            devices.push((source.to_string(), "swap".to_string()));
+        }
+    }
+
+    devices
+}
+
+// canonical sysfs directory of a block device, partitions are subdirectories
+// of their disk
+fn sysfs_dir(rdev: u64) -> io::Result<PathBuf> {
+    let major = ((rdev >> 32) & 0xffff_f000) | ((rdev >> 8) & 0x0000_0fff);
+    let minor = ((rdev >> 12) & 0xffff_ff00) | (rdev & 0x0000_00ff);
+
+    fs::canonicalize(Path::new(SYSFS_BLOCK_DEVICES).join(format!("{major}:{minor}")))
+}
+
+// Writes the mapped ranges of `source` to `target` and verifies their
+// checksums. Returns the number of bytes written.
+fn write_mapped(source: &mut dyn Read, target: &mut File, bmap: &Bmap) -> Result<u64> {
+    let mut buf = vec![0u8; IO_CHUNK_SIZE];
+    let mut pos = 0;
+    let mut written = 0;
+
+    for range in &bmap.ranges {
+        let start = range.first * bmap.block_size;
+        let end = ((range.last + 1) * bmap.block_size).min(bmap.image_size);
+
+        // skip unmapped data, compressed streams cannot seek
+        let skipped = io::copy(&mut Read::take(&mut *source, start - pos), &mut io::sink())
+            .context("write_mapped: cannot read image")?;
+        anyhow::ensure!(
+            skipped == start - pos,
+            "write_mapped: image is smaller than expected by bmap file"
+        );
+
+        target.seek(SeekFrom::Start(start))?;
+        let mut hasher = Sha256::new();
+        pos = start;
+
+        while pos < end {
+            let n = (end - pos).min(IO_CHUNK_SIZE as u64) as usize;
+            source.read_exact(&mut buf[..n]).context(
+                "write_mapped: cannot read image, it might be smaller than expected by bmap file",
+            )?;
+            hasher.update(&buf[..n]);
+            target
+                .write_all(&buf[..n])
+                .context("write_mapped: cannot write target")?;
+            pos += n as u64;
+        }
+
+        anyhow::ensure!(
+            to_hex(&hasher.finalize()) == range.checksum,
+            "write_mapped: checksum mismatch of blocks {}-{}, image and bmap file don't match",
+            range.first,
+            range.last
+        );
+
+        written += end - start;
+    }
+
+    Ok(written)
+}
@@ -1,29 +1,47 @@
+use super::bmap::Bmap;
+use super::ext4::Ext4;
+use super::fat::Fat;
+pub use super::filesystem::FileAttributes;
+use super::filesystem::{FileType, Filesystem, Metadata, resolve_path};
+use super::glob::CopyFilter;
+use super::partition::{FilesystemType, PartitionDevice, grow_partition};
 use anyhow::{Context, Result};
 use log::{debug, warn};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::{self, Display};
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::str::FromStr;
-use stdext::function_name;
-use uuid::Uuid;
+use time::format_description::well_known::Rfc3339;
 
-#[derive(clap::ValueEnum, Debug, Clone, Eq, Hash, PartialEq)]
-#[clap(rename_all = "verbatim")]
+#[derive(Debug, Clone, Eq, Hash, PartialEq)]
 #[allow(non_camel_case_types)]
 pub enum Partition {
     boot,
     rootA,
+    rootB,
     cert,
     factory,
+    etc,
+    data,
+    /// partition number as listed in the partition table
+    num(u32),
+    /// GPT partition name
+    name(String),
+    /// ext4 or FAT volume label
+    label(String),
 }
 
+// partition tables address partitions in units of 512 byte sectors
+const SECTOR_SIZE: u64 = 512;
+
 #[derive(Debug)]
 struct PartitionInfo {
     num: u32,
     start: u64,
     count: u64,
+    fs_type: FilesystemType,
 }
 
 impl Display for Partition {
@@ -31,8 +49,14 @@ impl Display for Partition {
         match self {
             Partition::boot => write!(f, "boot"),
             Partition::rootA => write!(f, "rootA"),
+            Partition::rootB => write!(f, "rootB"),
             Partition::cert => write!(f, "cert"),
             Partition::factory => write!(f, "factory"),
+            Partition::etc => write!(f, "etc"),
+            Partition::data => write!(f, "data"),
+            Partition::num(num) => write!(f, "{num}"),
+            Partition::name(name) => write!(f, "name={name}"),
+            Partition::label(label) => write!(f, "label={label}"),
         }
     }
 }
@@ -44,9 +68,28 @@ impl FromStr for Partition {
         match input {
             "boot" => Ok(Partition::boot),
             "rootA" => Ok(Partition::rootA),
+            "rootB" => Ok(Partition::rootB),
             "cert" => Ok(Partition::cert),
             "factory" => Ok(Partition::factory),
-            _ => anyhow::bail!("unknown partition: use either boot, rootA, cert or factory"),
+            "etc" => Ok(Partition::etc),
+            "data" => Ok(Partition::data),
+            _ => {
+                if let Ok(num) = input.parse::<u32>() {
+                    anyhow::ensure!(num > 0, "partition numbers start at 1");
+                    return Ok(Partition::num(num));
+                }
+                match input.split_once('=') {
+                    Some(("name", name)) if !name.is_empty() => {
+                        Ok(Partition::name(name.to_string()))
+                    }
+                    Some(("label", label)) if !label.is_empty() => {
+                        Ok(Partition::label(label.to_string()))
+                    }
+                    _ => anyhow::bail!(
+                        "unknown partition: use either boot, rootA, rootB, cert, factory, etc, data, a partition number, name=<GPT partition name> or label=<filesystem label>"
+                    ),
+                }
+            }
         }
     }
 }
@@ -57,6 +100,8 @@ pub struct FileCopyToParams {
     in_file: std::path::PathBuf,
     partition: Partition,
     out_file: std::path::PathBuf,
+    filter: CopyFilter,
+    attributes: FileAttributes,
 }
 
 impl FileCopyToParams {
@@ -69,28 +114,46 @@ impl FileCopyToParams {
             in_file: in_file.to_path_buf(),
             partition,
             out_file: out_file.to_path_buf(),
+            filter: CopyFilter::default(),
+            attributes: FileAttributes::default(),
         }
     }
+
+    /// Restricts which files are copied if `in_file` is a directory.
+    pub fn with_filter(mut self, filter: CopyFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Sets ownership, permissions and modification time of the copied
+    /// file, or of all files if `in_file` is a directory.
+    pub fn with_attributes(mut self, attributes: FileAttributes) -> Self {
+        self.attributes = attributes;
+        self
+    }
 }
 
 impl FromStr for FileCopyToParams {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self> {
-        let err_msg = "format not matched: in-file-path,out-partition:out-file-path";
+        let err_msg = "format not matched: in-file-path,out-partition:out-file-path[,attribute=value...] with attributes uid, gid, mode (octal) and mtime (unix seconds or RFC 3339)";
 
-        anyhow::ensure!(
-            s.matches(',').count() == 1 && s.matches(':').count() == 1,
-            err_msg
-        );
+        let (in_file, rest) = s.split_once(',').context(err_msg)?;
+        let (out, attributes) = rest.split_once(',').unwrap_or((rest, ""));
 
-        let v: Vec<&str> = s.split(&[',', ':']).collect();
+        anyhow::ensure!(out.matches(':').count() == 1, err_msg);
 
-        anyhow::ensure!(v.len() == 3, err_msg);
+        let (partition, out_file) = out.split_once(':').context(err_msg)?;
 
-        let in_file = std::path::PathBuf::from(v[0]);
-        let partition = Partition::from_str(v[1])?;
-        let out_file = std::path::PathBuf::from(v[2]);
+        let in_file = std::path::PathBuf::from(in_file);
+        let partition = Partition::from_str(partition)?;
+        let out_file = std::path::PathBuf::from(out_file);
+        let attributes = if attributes.is_empty() {
+            FileAttributes::default()
+        } else {
+            FileAttributes::from_str(attributes)?
+        };
 
         anyhow::ensure!(
             in_file.try_exists().is_ok_and(|exists| exists),
@@ -105,15 +168,64 @@ impl FromStr for FileCopyToParams {
             in_file,
             partition,
             out_file,
+            filter: CopyFilter::default(),
+            attributes,
         })
     }
 }
 
+// comma separated list of attribute=value pairs, e.g. "uid=0,gid=0,mode=0600"
+impl FromStr for FileAttributes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut attributes = FileAttributes::default();
+
+        for pair in s.split(',') {
+            let (key, value) = pair.split_once('=').context(format!(
+                "invalid attribute \"{pair}\": expected attribute=value"
+            ))?;
+
+            match key {
+                "uid" => {
+                    attributes.uid =
+                        Some(value.parse().context(format!("invalid uid \"{value}\""))?)
+                }
+                "gid" => {
+                    attributes.gid =
+                        Some(value.parse().context(format!("invalid gid \"{value}\""))?)
+                }
+                "mode" => {
+                    let mode = u32::from_str_radix(value, 8)
+                        .context(format!("invalid mode \"{value}\": expected octal number"))?;
+                    anyhow::ensure!(mode <= 0o7777, "invalid mode \"{value}\": out of range");
+                    attributes.mode = Some(mode);
+                }
+                "mtime" => {
+                    let mtime = match value.parse::<i64>() {
+                        Ok(secs) => secs,
+                        Err(_) => time::OffsetDateTime::parse(value, &Rfc3339)
+                            .context(format!("invalid mtime \"{value}\""))?
+                            .unix_timestamp(),
+                    };
+                    attributes.mtime = Some(mtime);
+                }
+                _ => {
+                    anyhow::bail!("unknown attribute \"{key}\": use either uid, gid, mode or mtime")
+                }
+            }
+        }
+
+        Ok(attributes)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FileCopyFromParams {
     in_file: std::path::PathBuf,
     partition: Partition,
     out_file: std::path::PathBuf,
+    filter: CopyFilter,
 }
 
 impl FileCopyFromParams {
@@ -126,8 +238,15 @@ impl FileCopyFromParams {
             in_file: in_file.to_path_buf(),
             partition,
             out_file: out_file.to_path_buf(),
+            filter: CopyFilter::default(),
         }
     }
+
+    /// Restricts which files are copied if `in_file` is a directory.
+    pub fn with_filter(mut self, filter: CopyFilter) -> Self {
+        self.filter = filter;
+        self
+    }
 }
 
 impl FromStr for FileCopyFromParams {
@@ -153,138 +272,470 @@ impl FromStr for FileCopyFromParams {
             in_file,
             partition,
             out_file,
+            filter: CopyFilter::default(),
         })
     }
 }
 
-macro_rules! exec_cmd {
-    ($cmd:ident) => {
-        anyhow::ensure!(
-            $cmd.status()
-                .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?
-                .success(),
-            format!("{}: cmd failed: {:?}", function_name!(), $cmd)
-        );
-        debug!("{}: {:?}", function_name!(), $cmd);
-    };
+#[derive(Clone, Debug)]
+pub struct PartitionPath {
+    partition: Partition,
+    path: std::path::PathBuf,
 }
 
-macro_rules! try_exec_cmd {
-    ($cmd:ident) => {
-        if $cmd
-            .status()
-            .context(format!("{}: status failed: {:?}", function_name!(), $cmd))?
-            .success()
-        {
-            debug!("{}: {:?}", function_name!(), $cmd);
+impl PartitionPath {
+    pub fn new(partition: Partition, path: &std::path::Path) -> Self {
+        PartitionPath {
+            partition,
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl FromStr for PartitionPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let err_msg = "format not matched: partition:path";
+
+        anyhow::ensure!(s.matches(':').count() == 1, err_msg);
+
+        let (partition, path) = s.split_once(':').context(err_msg)?;
+        let partition = Partition::from_str(partition)?;
+        let path = std::path::PathBuf::from(path);
+
+        anyhow::ensure!(path.is_absolute(), "path isn't an absolute path");
+
+        Ok(Self { partition, path })
+    }
+}
+
+/// New size of a partition, either absolute or relative to the current size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionSize {
+    Absolute(u64),
+    Grow(u64),
+}
+
+impl PartitionSize {
+    fn bytes(&self, current: u64) -> u64 {
+        match self {
+            PartitionSize::Absolute(size) => *size,
+            PartitionSize::Grow(size) => current.saturating_add(*size),
+        }
+    }
+}
+
+impl FromStr for PartitionSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (grow, size) = match s.strip_prefix('+') {
+            Some(size) => (true, size),
+            None => (false, s),
+        };
+
+        let split = size
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(size.len());
+        let (num, unit) = size.split_at(split);
+        let shift = match unit.trim_end_matches("iB").trim_end_matches('B') {
+            "" => 0,
+            "K" | "k" => 10,
+            "M" => 20,
+            "G" => 30,
+            "T" => 40,
+            _ => anyhow::bail!("invalid size unit \"{unit}\": use K, M, G or T"),
+        };
+        let size = num
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .context(format!("invalid size \"{s}\""))?;
+
+        Ok(if grow {
+            PartitionSize::Grow(size)
         } else {
-            warn!("{}: {:?}", function_name!(), $cmd)
+            PartitionSize::Absolute(size)
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileListEntry {
+    partition: String,
+    path: PathBuf,
+    #[serde(rename = "type")]
+    file_type: FileType,
+    size: u64,
+    #[serde(serialize_with = "serialize_mode")]
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+}
+
+impl Display for FileListEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let type_char = match self.file_type {
+            FileType::File => '-',
+            FileType::Dir => 'd',
+            FileType::Symlink => 'l',
+            FileType::CharDevice => 'c',
+            FileType::BlockDevice => 'b',
+            FileType::Fifo => 'p',
+            FileType::Socket => 's',
+        };
+        write!(
+            f,
+            "{type_char}{} {:>5} {:>5} {:>10} {} {}:{}",
+            permission_string(self.mode),
+            self.uid,
+            self.gid,
+            self.size,
+            self.mtime,
+            self.partition,
+            self.path.display()
+        )?;
+
+        if let Some(target) = &self.target {
+            write!(f, " -> {}", target.display())?;
         }
-    };
+
+        Ok(())
+    }
+}
+
+fn serialize_mode<S: serde::Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{mode:04o}"))
+}
+
+// permissions in the format used by ls, e.g. "rwxr-sr-t"
+fn permission_string(mode: u32) -> String {
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    let mut s = String::with_capacity(9);
+
+    for (i, (special_bit, special_char)) in special.iter().enumerate() {
+        let bits = mode >> (6 - 3 * i);
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => *special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    s
 }
 
 pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -> Result<()> {
-    // we use the folder the image is located in
-    // the caller is responsible to create a /tmp/ directory if needed
-    let working_dir = image_file
-        .parent()
-        .context("copy_to_image: cannot get directory of image")?
-        .to_path_buf();
     let image_file = image_file.to_str().unwrap();
-    let mut partition_map: HashMap<&Partition, Vec<(&PathBuf, &PathBuf)>> = HashMap::new();
+    let mut partition_map: HashMap<&Partition, Vec<&FileCopyToParams>> = HashMap::new();
 
     // create map with partition as key
     for params in file_copy_params.iter() {
-        let e = (&params.in_file, &params.out_file);
         partition_map
             .entry(&params.partition)
-            .and_modify(|v| v.push(e))
-            .or_insert(vec![e]);
+            .and_modify(|v| v.push(params))
+            .or_insert(vec![params]);
     }
 
-    // 1. for each involved partition
-    for partition in partition_map.keys() {
-        let mut partition_file = working_dir.clone();
+    // 1. resolve partitions, different selectors might denote the same one
+    let mut partitions: Vec<(PartitionInfo, Vec<&FileCopyToParams>)> = vec![];
+    for (partition, params) in partition_map.into_iter() {
         let partition_info = get_partition_info(image_file, partition)?;
+        match partitions
+            .iter_mut()
+            .find(|(info, _)| info.num == partition_info.num)
+        {
+            Some((_, p)) => p.extend(params),
+            None => partitions.push((partition_info, params)),
+        }
+    }
 
-        partition_file.push(Path::new(&format!("{}.img", partition_info.num)));
-        let partition_file = partition_file.to_str().unwrap();
+    // 2. make sure everything fits before the image is touched
+    for (partition_info, params) in partitions.iter() {
+        let mut partition_fs = open_filesystem(image_file, partition_info, false)?;
+        check_free_space(partition_fs.as_mut(), params, &params[0].partition)?;
+    }
 
-        // 2. read partition
-        read_partition(image_file, partition_file, &partition_info)?;
+    for (partition_info, params) in partitions.iter() {
+        // 3. open filesystem directly on the partition range of the image
+        let mut partition_fs = open_filesystem(image_file, partition_info, true)?;
+
+        // 4. copy files and directories
+        for params in params.iter() {
+            if params.in_file.is_dir() {
+                copy_dir_to_fs(
+                    partition_fs.as_mut(),
+                    &params.in_file,
+                    &params.out_file,
+                    &params.filter,
+                    &params.attributes,
+                )?;
+            } else {
+                copy_file_to_fs(
+                    partition_fs.as_mut(),
+                    &params.in_file,
+                    &params.out_file,
+                    &params.attributes,
+                )?;
+            }
+        }
 
-        // 3. copy files
-        for (in_file, out_file) in partition_map.get(partition).unwrap().iter() {
-            let dir_path = out_file.parent().context(format!(
-                "copy_to_image: invalid destination path {}",
-                out_file.to_str().unwrap()
-            ))?;
+        // 5. write back pending metadata
+        partition_fs.flush().context(format!(
+            "copy_to_image: cannot write partition {}",
+            partition_info.num
+        ))?;
+    }
 
-            let out_file = out_file.to_str().unwrap();
-
-            if **partition == Partition::boot {
-                let mut p = PathBuf::from("/");
-
-                for dir in dir_path.iter().skip(1).map(|d| d.to_str().unwrap()) {
-                    p.push(dir);
-                    let mut mmd = Command::new("mmd");
-                    mmd.arg("-D")
-                        .arg("sS")
-                        .arg("-i")
-                        .arg(partition_file)
-                        .arg(p.to_str().unwrap());
-                    // we ignore `mmd` errors in order to ignore potential name clashes when a dir already exists
-                    // in case mmd fails mcopy will fail respectively with a reasonable error output
-                    try_exec_cmd!(mmd);
-                }
+    Ok(())
+}
 
-                let mut mcopy = Command::new("mcopy");
-                mcopy
-                    .arg("-o")
-                    .arg("-i")
-                    .arg(partition_file)
-                    .arg(in_file)
-                    .arg(format!("::{out_file}"));
-                exec_cmd!(mcopy);
-            } else {
-                let mut e2mkdir = Command::new("e2mkdir");
-                e2mkdir.arg(format!("{partition_file}:{}", dir_path.to_str().unwrap()));
-                exec_cmd!(e2mkdir);
-
-                let mut e2cp = Command::new("e2cp");
-                e2cp.arg(in_file)
-                    .arg(format!("{partition_file}:{out_file}"));
-                exec_cmd!(e2cp);
+// Fails if the files of `params` don't fit into `fs`. The required space is
+// estimated as the file sizes rounded up to the allocation unit plus one unit
+// for each directory to be created. Space of overwritten files is credited
+// once per file.
+fn check_free_space(
+    fs: &mut dyn Filesystem,
+    params: &[&FileCopyToParams],
+    partition: &Partition,
+) -> Result<()> {
+    let usage = fs.usage().context(format!(
+        "check_free_space: cannot get free space of {partition}"
+    ))?;
+    let round_up = |len: u64| len.div_ceil(usage.block_size) * usage.block_size;
+
+    let mut files = vec![];
+    let mut dirs = BTreeSet::new();
+    for params in params.iter() {
+        if params.in_file.is_dir() {
+            let entries =
+                walk_host_dir(&params.in_file, Path::new(""), &params.filter).context(format!(
+                    "check_free_space: cannot read directory {}",
+                    params.in_file.to_str().unwrap()
+                ))?;
+            dirs.insert(params.out_file.clone());
+            for (rel_path, is_dir) in entries {
+                if is_dir {
+                    dirs.insert(params.out_file.join(rel_path));
+                } else {
+                    files.push((
+                        params.in_file.join(&rel_path),
+                        params.out_file.join(rel_path),
+                    ));
+                }
             }
+        } else {
+            files.push((params.in_file.clone(), params.out_file.clone()));
         }
+    }
 
-        // 4. write back partition
-        write_partition(image_file, partition_file, &partition_info)?;
+    let mut required = 0;
+    let mut released = 0;
+    let mut overwritten = BTreeSet::new();
+    for (in_file, out_file) in files.iter() {
+        let len = fs::metadata(in_file)
+            .context(format!(
+                "check_free_space: cannot open {}",
+                in_file.to_str().unwrap()
+            ))?
+            .len();
+        required += round_up(len);
+
+        if let Some(parent) = out_file.parent() {
+            dirs.extend(parent.ancestors().map(Path::to_path_buf));
+        }
+        // a destination given several times only releases its space once
+        if let Ok(resolved) = resolve_path(fs, out_file, true)
+            && !overwritten.contains(&resolved)
+            && let Ok(existing) = fs.metadata(&resolved)
+            && existing.file_type == FileType::File
+        {
+            released += round_up(existing.size);
+            overwritten.insert(resolved);
+        }
     }
 
+    for dir in dirs.iter() {
+        if existing_metadata(fs, dir).is_err() {
+            required += usage.block_size;
+        }
+    }
+
+    let required = required.saturating_sub(released);
+    debug!(
+        "check_free_space: {partition}: required={required} free={} total={}",
+        usage.free, usage.total
+    );
+
+    anyhow::ensure!(
+        required <= usage.free,
+        "check_free_space: not enough space on partition {partition}: {required} bytes required, {} bytes free, {} bytes missing",
+        usage.free,
+        required - usage.free
+    );
+
     Ok(())
 }
 
-pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Path) -> Result<()> {
-    // we use the folder the image is located in
-    // the caller is responsible to create a /tmp/ directory if needed
-    let working_dir = image_file
-        .parent()
-        .context("copy_to_image: cannot get directory of image")?
-        .to_path_buf();
+fn existing_metadata(fs: &mut dyn Filesystem, path: &Path) -> Result<Metadata> {
+    let resolved = resolve_path(fs, path, true)?;
+    fs.metadata(&resolved)
+}
+
+fn copy_file_to_fs(
+    fs: &mut dyn Filesystem,
+    in_file: &Path,
+    out_file: &Path,
+    attributes: &FileAttributes,
+) -> Result<()> {
+    // follow links in the destination path like cp does
+    let out_path = resolve_path(fs, out_file, true).context(format!(
+        "copy_to_image: cannot resolve destination path {}",
+        out_file.to_str().unwrap()
+    ))?;
+
+    let dir_path = out_path.parent().context(format!(
+        "copy_to_image: invalid destination path {}",
+        out_file.to_str().unwrap()
+    ))?;
+
+    fs.create_dir_all(dir_path).context(format!(
+        "copy_to_image: cannot create directory {}",
+        dir_path.to_str().unwrap()
+    ))?;
+
+    let mut input = fs::File::open(in_file).context(format!(
+        "copy_to_image: cannot open {}",
+        in_file.to_str().unwrap()
+    ))?;
+    let len = input.metadata()?.len();
+    fs.write_file(&out_path, &mut input, len).context(format!(
+        "copy_to_image: cannot copy to {}",
+        out_file.to_str().unwrap()
+    ))?;
+
+    if *attributes != FileAttributes::default() {
+        fs.set_attributes(&out_path, attributes).context(format!(
+            "copy_to_image: cannot set attributes of {}",
+            out_file.to_str().unwrap()
+        ))?;
+    }
+
+    Ok(())
+}
+
+fn copy_dir_to_fs(
+    fs: &mut dyn Filesystem,
+    in_dir: &Path,
+    out_dir: &Path,
+    filter: &CopyFilter,
+    attributes: &FileAttributes,
+) -> Result<()> {
+    let entries = walk_host_dir(in_dir, Path::new(""), filter).context(format!(
+        "copy_to_image: cannot read directory {}",
+        in_dir.to_str().unwrap()
+    ))?;
+
+    for dir in std::iter::once(out_dir).chain(
+        entries
+            .iter()
+            .filter(|(_, is_dir)| *is_dir)
+            .map(|(rel_path, _)| rel_path.as_path()),
+    ) {
+        let dir = out_dir.join(dir);
+        let out_path = resolve_path(fs, &dir, true).context(format!(
+            "copy_to_image: cannot resolve destination path {}",
+            dir.to_str().unwrap()
+        ))?;
+        fs.create_dir_all(&out_path).context(format!(
+            "copy_to_image: cannot create directory {}",
+            dir.to_str().unwrap()
+        ))?;
+    }
+
+    for (rel_path, _) in entries.iter().filter(|(_, is_dir)| !*is_dir) {
+        copy_file_to_fs(
+            fs,
+            &in_dir.join(rel_path),
+            &out_dir.join(rel_path),
+            attributes,
+        )?;
+    }
+
+    debug!(
+        "copy_to_image: copied directory {} to {}",
+        in_dir.to_str().unwrap(),
+        out_dir.to_str().unwrap()
+    );
+
+    Ok(())
+}
+
+// Returns paths relative to `root` of all files and directories below
+// `root/rel_dir` that pass `filter`. Directories are reported before their
+// content and flagged with `true`.
+fn walk_host_dir(root: &Path, rel_dir: &Path, filter: &CopyFilter) -> Result<Vec<(PathBuf, bool)>> {
+    let mut children = fs::read_dir(root.join(rel_dir))?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    children.sort();
+
+    let mut entries = vec![];
+    for name in children {
+        let rel_path = rel_dir.join(name);
+        if filter.is_excluded(&rel_path) {
+            continue;
+        }
+
+        let path = root.join(&rel_path);
+        let metadata = match fs::metadata(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("skip {}: {e}", path.to_str().unwrap());
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            // linked directories might introduce cycles, so they are not followed
+            if path.is_symlink() {
+                warn!("skip linked directory {}", path.to_str().unwrap());
+                continue;
+            }
+            if filter.keeps_empty_dirs() {
+                entries.push((rel_path.clone(), true));
+            }
+            entries.extend(walk_host_dir(root, &rel_path, filter)?);
+        } else if metadata.is_file() {
+            if filter.is_included(&rel_path) {
+                entries.push((rel_path, false));
+            }
+        } else {
+            warn!("skip special file {}", path.to_str().unwrap());
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn copy_from_image(
+    file_copy_params: &[FileCopyFromParams],
+    image_file: &Path,
+    dereference: bool,
+) -> Result<()> {
     let image_file = image_file.to_str().unwrap();
 
     for param in file_copy_params.iter() {
-        let mut partition_file = working_dir.clone();
-
         let partition_info = get_partition_info(image_file, &param.partition)?;
         let in_file = param.in_file.to_str().unwrap();
 
-        partition_file.push(Path::new(&format!("{}.img", partition_info.num)));
-        let partition_file = partition_file.to_str().unwrap();
-
-        read_partition(image_file, partition_file, &partition_info)?;
-
         anyhow::ensure!(
             param
                 .out_file
@@ -295,54 +746,392 @@ pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Pat
             "copy_from_image: output dir does not exist."
         );
 
-        // copy
-        if param.partition == Partition::boot {
-            let mut tmp_out_file = working_dir.clone();
-            // mcopy deadlocks when target file is not residing in workingdir so we copy to a temp file
-            tmp_out_file.push(format!(
-                "{}-{}",
-                Uuid::new_v4(),
-                param.out_file.file_name().unwrap().to_str().unwrap()
-            ));
-
-            let mut mcopy = Command::new("mcopy");
-            mcopy
-                .arg("-o")
-                .arg("-i")
-                .arg(partition_file)
-                .arg(format!("::{in_file}"))
-                .arg(&tmp_out_file);
-            exec_cmd!(mcopy);
-            // instead of rename we copy and delete to prevent "Invalid cross-device link" errors
-            let bytes_copied = fs::copy(&tmp_out_file, &param.out_file).context(format!(
-                "copy_from_image: couldn't copy temp file {} to destination {}",
-                tmp_out_file.to_str().unwrap(),
-                param.out_file.to_str().unwrap()
+        let mut partition_fs = open_filesystem(image_file, &partition_info, false)?;
+        let fs = partition_fs.as_mut();
+        let in_path = resolve_path(fs, &param.in_file, dereference)
+            .context(format!("copy_from_image: cannot resolve {in_file}"))?;
+        let metadata = fs
+            .metadata(&in_path)
+            .context(format!("copy_from_image: cannot access {in_file}"))?;
+
+        if metadata.file_type == FileType::Dir {
+            copy_dir_from_fs(fs, &in_path, &param.out_file, &param.filter, dereference)?;
+        } else {
+            copy_file_from_fs(fs, &in_path, &param.out_file, dereference)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_file_from_fs(
+    fs: &mut dyn Filesystem,
+    in_path: &Path,
+    out_file: &Path,
+    dereference: bool,
+) -> Result<()> {
+    let in_file = in_path.to_str().unwrap();
+
+    // copy the link itself if it shall not be dereferenced
+    if !dereference
+        && let Some(target) = fs
+            .read_link(in_path)
+            .context(format!("copy_from_image: cannot read {in_file}"))?
+    {
+        if out_file.symlink_metadata().is_ok() {
+            fs::remove_file(out_file).context(format!(
+                "copy_from_image: cannot replace {}",
+                out_file.to_str().unwrap()
             ))?;
-            anyhow::ensure!(
-                tmp_out_file.metadata().unwrap().len() == bytes_copied,
-                "copy_from_image: copy temp file failed"
-            );
-            fs::remove_file(&tmp_out_file).context(format!(
-                "copy_from_image: couldn't delete temp file {}",
-                tmp_out_file.to_str().unwrap()
+        }
+        std::os::unix::fs::symlink(&target, out_file).context(format!(
+            "copy_from_image: cannot create link {}",
+            out_file.to_str().unwrap()
+        ))?;
+        debug!(
+            "copy_from_image: copied link {in_file} -> {}",
+            target.to_str().unwrap()
+        );
+        return Ok(());
+    }
+
+    // copy
+    let mut output = fs::File::create(out_file).context(format!(
+        "copy_from_image: cannot create {}",
+        out_file.to_str().unwrap()
+    ))?;
+    fs.read_file(in_path, &mut output)
+        .context(format!("copy_from_image: cannot copy {in_file}"))?;
+
+    Ok(())
+}
+
+fn copy_dir_from_fs(
+    fs: &mut dyn Filesystem,
+    in_dir: &Path,
+    out_dir: &Path,
+    filter: &CopyFilter,
+    dereference: bool,
+) -> Result<()> {
+    let entries = walk_image_dir(fs, in_dir, Path::new(""), filter).context(format!(
+        "copy_from_image: cannot read directory {}",
+        in_dir.to_str().unwrap()
+    ))?;
+
+    fs::create_dir_all(out_dir).context(format!(
+        "copy_from_image: cannot create directory {}",
+        out_dir.to_str().unwrap()
+    ))?;
+
+    for (rel_path, file_type) in entries {
+        let in_path = in_dir.join(&rel_path);
+        let out_path = out_dir.join(&rel_path);
+
+        if file_type == FileType::Dir {
+            fs::create_dir_all(&out_path).context(format!(
+                "copy_from_image: cannot create directory {}",
+                out_path.to_str().unwrap()
+            ))?;
+            continue;
+        }
+
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent).context(format!(
+                "copy_from_image: cannot create directory {}",
+                parent.to_str().unwrap()
+            ))?;
+        }
+
+        let in_path = if file_type == FileType::Symlink && dereference {
+            let target = resolve_path(fs, &in_path, true).context(format!(
+                "copy_from_image: cannot resolve {}",
+                in_path.to_str().unwrap()
             ))?;
+            match fs.metadata(&target) {
+                Ok(m) if m.file_type == FileType::File => target,
+                _ => {
+                    warn!(
+                        "copy_from_image: skip link {} which doesn't point to a regular file",
+                        in_path.to_str().unwrap()
+                    );
+                    continue;
+                }
+            }
         } else {
-            let mut e2cp = Command::new("e2cp");
-            e2cp.arg(format!("{partition_file}:{in_file}"))
-                .arg(param.out_file.to_str().unwrap());
-            exec_cmd!(e2cp);
-            // since e2cp doesn't return errors in any case we check if output file exists
+            in_path
+        };
+
+        copy_file_from_fs(fs, &in_path, &out_path, dereference)?;
+    }
+
+    debug!(
+        "copy_from_image: copied directory {} to {}",
+        in_dir.to_str().unwrap(),
+        out_dir.to_str().unwrap()
+    );
+
+    Ok(())
+}
+
+// Same as walk_host_dir() for directories inside of an image. Links are
+// reported with their type and never followed.
+fn walk_image_dir(
+    fs: &mut dyn Filesystem,
+    root: &Path,
+    rel_dir: &Path,
+    filter: &CopyFilter,
+) -> Result<Vec<(PathBuf, FileType)>> {
+    let mut children = fs.list_dir(&root.join(rel_dir))?;
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut entries = vec![];
+    for (name, metadata) in children {
+        let rel_path = rel_dir.join(name);
+        if filter.is_excluded(&rel_path) {
+            continue;
+        }
+
+        match metadata.file_type {
+            FileType::Dir => {
+                if filter.keeps_empty_dirs() {
+                    entries.push((rel_path.clone(), FileType::Dir));
+                }
+                entries.extend(walk_image_dir(fs, root, &rel_path, filter)?);
+            }
+            FileType::File | FileType::Symlink => {
+                if filter.is_included(&rel_path) {
+                    entries.push((rel_path, metadata.file_type));
+                }
+            }
+            _ => warn!(
+                "skip special file {}",
+                root.join(&rel_path).to_str().unwrap()
+            ),
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn remove_from_image(
+    file_remove_params: &[PartitionPath],
+    image_file: &Path,
+    recursive: bool,
+) -> Result<()> {
+    let image_file = image_file.to_str().unwrap();
+
+    for param in file_remove_params.iter() {
+        let partition_info = get_partition_info(image_file, &param.partition)?;
+        let path = param.path.to_str().unwrap();
+
+        let mut partition_fs = open_filesystem(image_file, &partition_info, true)?;
+        let fs = partition_fs.as_mut();
+
+        // a link given as last path component is removed, not its target
+        let resolved = resolve_path(fs, &param.path, false)
+            .context(format!("remove_from_image: cannot resolve {path}"))?;
+        let metadata = fs
+            .metadata(&resolved)
+            .context(format!("remove_from_image: cannot access {path}"))?;
+
+        if metadata.file_type == FileType::Dir {
             anyhow::ensure!(
-                param.out_file.try_exists().is_ok_and(|exists| exists),
-                format!("copy_from_image: cmd failed: {:?}", e2cp)
-            )
+                recursive,
+                "remove_from_image: {path} is a directory, use --recursive to remove it"
+            );
+            fs.remove_dir_all(&resolved)
+        } else {
+            fs.remove_file(&resolved)
         }
+        .context(format!("remove_from_image: cannot remove {path}"))?;
+
+        fs.flush().context(format!(
+            "remove_from_image: cannot write partition {}",
+            partition_info.num
+        ))?;
+
+        debug!("remove_from_image: removed {}:{path}", param.partition);
     }
 
     Ok(())
 }
 
+pub fn move_in_image(from: &PartitionPath, to: &PartitionPath, image_file: &Path) -> Result<()> {
+    let image_file = image_file.to_str().unwrap();
+    let from_path = from.path.to_str().unwrap();
+    let to_path = to.path.to_str().unwrap();
+
+    // different selectors may still refer to the same partition
+    let partition_info = get_partition_info(image_file, &from.partition)?;
+    anyhow::ensure!(
+        get_partition_info(image_file, &to.partition)?.num == partition_info.num,
+        "move_in_image: files can only be moved within the same partition"
+    );
+
+    let mut partition_fs = open_filesystem(image_file, &partition_info, true)?;
+    let fs = partition_fs.as_mut();
+
+    let source = resolve_path(fs, &from.path, false)
+        .context(format!("move_in_image: cannot resolve {from_path}"))?;
+    let mut dest = resolve_path(fs, &to.path, true)
+        .context(format!("move_in_image: cannot resolve {to_path}"))?;
+
+    fs.metadata(&source)
+        .context(format!("move_in_image: cannot access {from_path}"))?;
+
+    // like mv we move into an existing destination directory
+    if let Ok(metadata) = fs.metadata(&dest)
+        && metadata.file_type == FileType::Dir
+        && !fs.is_same_path(&dest, &source)
+    {
+        dest.push(
+            source
+                .file_name()
+                .context(format!("move_in_image: invalid source path {from_path}"))?,
+        );
+    }
+
+    fs.rename(&source, &dest).context(format!(
+        "move_in_image: cannot move {from_path} to {to_path}"
+    ))?;
+
+    fs.flush().context(format!(
+        "move_in_image: cannot write partition {}",
+        partition_info.num
+    ))
+}
+
+pub fn list_image(
+    file_list_params: &[PartitionPath],
+    image_file: &Path,
+    recursive: bool,
+) -> Result<Vec<FileListEntry>> {
+    let image_file = image_file.to_str().unwrap();
+    let mut entries = vec![];
+
+    for param in file_list_params.iter() {
+        let partition_info = get_partition_info(image_file, &param.partition)?;
+        let path = param.path.to_str().unwrap();
+
+        let mut partition_fs = open_filesystem(image_file, &partition_info, false)?;
+        let fs = partition_fs.as_mut();
+
+        // like ls we don't follow a link given as last path component
+        let resolved = resolve_path(fs, &param.path, false)
+            .context(format!("list_image: cannot resolve {path}"))?;
+        let metadata = fs
+            .metadata(&resolved)
+            .context(format!("list_image: cannot access {path}"))?;
+
+        if metadata.file_type == FileType::Dir {
+            list_dir(fs, &param.partition, &resolved, recursive, &mut entries)
+                .context(format!("list_image: cannot list {path}"))?;
+        } else {
+            entries.push(list_entry(fs, &param.partition, resolved, metadata)?);
+        }
+    }
+
+    Ok(entries)
+}
+
+fn list_dir(
+    fs: &mut dyn Filesystem,
+    partition: &Partition,
+    dir: &Path,
+    recursive: bool,
+    entries: &mut Vec<FileListEntry>,
+) -> Result<()> {
+    let mut children = fs.list_dir(dir)?;
+    children.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (name, metadata) in children {
+        let path = dir.join(name);
+        let is_dir = metadata.file_type == FileType::Dir;
+        entries.push(list_entry(fs, partition, path.clone(), metadata)?);
+        if recursive && is_dir {
+            list_dir(fs, partition, &path, recursive, entries)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn list_entry(
+    fs: &mut dyn Filesystem,
+    partition: &Partition,
+    path: PathBuf,
+    metadata: Metadata,
+) -> Result<FileListEntry> {
+    let target = match metadata.file_type {
+        FileType::Symlink => fs.read_link(&path)?,
+        _ => None,
+    };
+
+    let mtime = time::OffsetDateTime::from_unix_timestamp(metadata.mtime)
+        .ok()
+        .and_then(|t| t.format(&Rfc3339).ok())
+        .unwrap_or_else(|| metadata.mtime.to_string());
+
+    Ok(FileListEntry {
+        partition: partition.to_string(),
+        path,
+        file_type: metadata.file_type,
+        size: metadata.size,
+        mode: metadata.mode,
+        uid: metadata.uid,
+        gid: metadata.gid,
+        mtime,
+        target,
+    })
+}
+
+pub fn resize_partition(
+    partition: &Partition,
+    size: &PartitionSize,
+    image_file: &Path,
+) -> Result<()> {
+    let image_file = image_file.to_str().unwrap();
+    let partition_info = get_partition_info(image_file, partition)?;
+    anyhow::ensure!(
+        partition_info.fs_type == FilesystemType::Ext4,
+        "resize_partition: {partition} has a {} filesystem, only ext4 can be resized",
+        partition_info.fs_type
+    );
+
+    let current = partition_info.count * SECTOR_SIZE;
+    let count = size.bytes(current).div_ceil(SECTOR_SIZE);
+    anyhow::ensure!(
+        count > partition_info.count,
+        "resize_partition: {partition} already has {current} bytes, shrinking is not supported"
+    );
+
+    // make sure the filesystem can be grown before the partition table is changed
+    let device = PartitionDevice::open(image_file, partition_info.start * SECTOR_SIZE, current)
+        .context("resize_partition: cannot open partition")?;
+    let fs = Ext4::open(device)?;
+    let blocks = fs
+        .grow_target(count * SECTOR_SIZE / fs.block_size())
+        .context(format!(
+            "resize_partition: cannot grow filesystem of {partition}"
+        ))?;
+    drop(fs);
+
+    grow_partition(image_file, partition_info.num, count).context(format!(
+        "resize_partition: cannot grow partition {}",
+        partition_info.num
+    ))?;
+
+    let device = PartitionDevice::open(
+        image_file,
+        partition_info.start * SECTOR_SIZE,
+        count * SECTOR_SIZE,
+    )
+    .context("resize_partition: cannot open resized partition")?;
+    Ext4::open(device)?.grow(blocks).context(format!(
+        "resize_partition: cannot grow filesystem of {partition}"
+    ))
+}
+
 pub fn read_file_from_image(
     path: impl AsRef<Path>,
     partition: Partition,
@@ -353,7 +1142,7 @@ pub fn read_file_from_image(
 
     let params = FileCopyFromParams::new(path.as_ref(), partition, tmp_file.path());
 
-    copy_from_image(&[params], image_file.as_ref())
+    copy_from_image(&[params], image_file.as_ref(), true)
         .context("read_file_from_image: could not copy file content")?;
 
     let content = std::fs::read_to_string(tmp_file.path())
@@ -362,40 +1151,194 @@ pub fn read_file_from_image(
     Ok(content)
 }
 
+#[derive(Debug, Serialize)]
+pub struct PartitionTable {
+    #[serde(rename = "type")]
+    table_type: &'static str,
+    partitions: Vec<PartitionEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionEntry {
+    num: u32,
+    /// offset in bytes
+    start: u64,
+    /// size in bytes
+    size: u64,
+    /// GPT type GUID or MBR system id
+    #[serde(rename = "type")]
+    partition_type: String,
+    name: Option<String>,
+    filesystem: Option<String>,
+    label: Option<String>,
+    /// free space of the filesystem in bytes
+    free: Option<u64>,
+}
+
+impl Display for PartitionTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "partition table: {}", self.table_type)?;
+        write!(
+            f,
+            "{:>3} {:>12} {:>12} {:<36} {:<12} {:<10} {:<12} {:>12}",
+            "num", "start", "size", "type", "name", "filesystem", "label", "free"
+        )?;
+
+        for p in self.partitions.iter() {
+            write!(
+                f,
+                "\n{:>3} {:>12} {:>12} {:<36} {:<12} {:<10} {:<12} {:>12}",
+                p.num,
+                p.start,
+                p.size,
+                p.partition_type,
+                p.name.as_deref().unwrap_or("-"),
+                p.filesystem.as_deref().unwrap_or("-"),
+                p.label.as_deref().unwrap_or("-"),
+                p.free.map_or("-".to_string(), |f| f.to_string())
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the partitions of the image together with their filesystems and
+/// the free space left on them.
+pub fn partition_table(image_file: &Path) -> Result<PartitionTable> {
+    use crate::file::partition::{get_partitions, is_gpt};
+
+    let image_file = image_file.to_str().unwrap();
+    let gpt =
+        is_gpt(image_file).context("partition_table: failed to detect partition table type")?;
+    let partitions =
+        get_partitions(image_file).context("partition_table: failed to read partition table")?;
+
+    let mut entries = vec![];
+    for data in partitions.iter() {
+        let fs_type = data.filesystem.as_ref().map(|fs| fs.fs_type);
+        let free = match fs_type {
+            Some(fs_type) => {
+                let partition_info = PartitionInfo {
+                    num: data.num,
+                    start: data.start,
+                    count: data.count,
+                    fs_type,
+                };
+                // a damaged filesystem shouldn't hide the rest of the table
+                open_filesystem(image_file, &partition_info, false)
+                    .and_then(|mut fs| fs.usage())
+                    .inspect_err(|e| {
+                        warn!(
+                            "partition_table: cannot get usage of partition {}: {e:#}",
+                            data.num
+                        )
+                    })
+                    .ok()
+                    .map(|usage| usage.free)
+            }
+            None => None,
+        };
+
+        entries.push(PartitionEntry {
+            num: data.num,
+            start: data.start * SECTOR_SIZE,
+            size: data.count * SECTOR_SIZE,
+            partition_type: data.partition_type.to_string(),
+            name: data.name.clone(),
+            filesystem: fs_type.map(|t| t.to_string()),
+            label: data.label().map(str::to_string),
+            free,
+        });
+    }
+
+    Ok(PartitionTable {
+        table_type: if gpt { "gpt" } else { "mbr" },
+        partitions: entries,
+    })
+}
+
+// Opens the filesystem of a partition. Without `writable` the image is opened
+// read-only, i.e. it isn't modified in any case.
+fn open_filesystem(
+    image_file: &str,
+    partition_info: &PartitionInfo,
+    writable: bool,
+) -> Result<Box<dyn Filesystem>> {
+    let offset = partition_info.start * SECTOR_SIZE;
+    let len = partition_info.count * SECTOR_SIZE;
+    let device = if writable {
+        PartitionDevice::open(image_file, offset, len)
+    } else {
+        PartitionDevice::open_read_only(image_file, offset, len)
+    }
+    .context(format!(
+        "open_filesystem: cannot open partition {} of {image_file}",
+        partition_info.num
+    ))?;
+
+    let fs: Box<dyn Filesystem> = match partition_info.fs_type {
+        FilesystemType::Fat => Box::new(Fat::open(device)?),
+        FilesystemType::Ext4 => Box::new(Ext4::open(device)?),
+    };
+
+    Ok(fs)
+}
+
 fn get_partition_info(image_file: &str, partition: &Partition) -> Result<PartitionInfo> {
-    use crate::file::partition::{get_partition_data, is_gpt};
+    use crate::file::partition::{
+        check_filesystem, find_partition, find_partition_by_label, find_partition_by_name,
+        get_partitions, is_gpt,
+    };
 
     let gpt =
         is_gpt(image_file).context("get_partition_info: failed to detect partition table type")?;
+    let partitions =
+        get_partitions(image_file).context("get_partition_info: failed to read partition table")?;
 
-    let partition_num: u32 = match partition {
-        Partition::boot => 1,
-        Partition::rootA => 2,
-        Partition::factory => {
-            if gpt {
-                4
-            } else {
-                5
-            }
+    let data = match partition {
+        Partition::num(num) => partitions.iter().find(|p| p.num == *num),
+        Partition::name(name) => {
+            anyhow::ensure!(
+                gpt,
+                "get_partition_info: partition names are only supported on GPT"
+            );
+            Some(find_partition_by_name(&partitions, name).context("get_partition_info")?)
         }
-        Partition::cert => {
-            if gpt {
-                5
-            } else {
-                6
-            }
+        Partition::label(label) => {
+            Some(find_partition_by_label(&partitions, label).context("get_partition_info")?)
         }
-    };
+        _ => match find_partition(&partitions, &partition.to_string())
+            .context("get_partition_info")?
+        {
+            Some(p) => Some(p),
+            None => {
+                let num = default_partition_num(partition, gpt);
+                warn!(
+                    "get_partition_info: image has no partition names or labels, assuming {partition} is partition {}",
+                    num.unwrap_or_default()
+                );
+                partitions.iter().find(|p| Some(p.num) == num)
+            }
+        },
+    }
+    .with_context(|| format!("get_partition_info: partition {partition} not found"))?;
 
-    debug!("get_partition_info: partition={partition}, num={partition_num}, gpt={gpt}");
+    debug!(
+        "get_partition_info: partition={partition}, num={}, gpt={gpt}",
+        data.num
+    );
 
-    let data = get_partition_data(image_file, partition_num)
-        .with_context(|| format!("get_partition_info: failed to read partition {partition_num}"))?;
+    // don't touch a partition which doesn't look like the expected one
+    let fs_type = check_filesystem(data, expected_filesystem(partition)).context(format!(
+        "get_partition_info: unexpected content of {partition}"
+    ))?;
 
     let info = PartitionInfo {
         num: data.num,
         start: data.start,
         count: data.count,
+        fs_type,
     };
 
     debug!("get_partition_info: {:?}", info);
@@ -403,64 +1346,109 @@ fn get_partition_info(image_file: &str, partition: &Partition) -> Result<Partiti
     Ok(info)
 }
 
-fn read_partition(
-    image_file: &str,
-    partition_file: &str,
-    partition_info: &PartitionInfo,
-) -> Result<()> {
-    if let Ok(true) = PathBuf::from(partition_file).try_exists() {
-        return Ok(());
+// filesystem of the omnect partitions, any supported one for other partitions
+fn expected_filesystem(partition: &Partition) -> Option<FilesystemType> {
+    match partition {
+        Partition::boot => Some(FilesystemType::Fat),
+        Partition::num(_) | Partition::name(_) | Partition::label(_) => None,
+        _ => Some(FilesystemType::Ext4),
     }
+}
 
-    let mut dd = Command::new("dd");
-    dd.arg(format!("if={image_file}"))
-        .arg(format!("of={partition_file}"))
-        .arg("bs=512")
-        .arg(format!("skip={}", partition_info.start))
-        .arg(format!("count={}", partition_info.count))
-        .arg("conv=sparse")
-        .arg("status=none");
-    exec_cmd!(dd);
-
-    let mut sync = Command::new("sync");
-    exec_cmd!(sync);
+// partition numbers of the omnect layouts for images without names and labels
+fn default_partition_num(partition: &Partition, gpt: bool) -> Option<u32> {
+    let num = match (partition, gpt) {
+        (Partition::boot, _) => 1,
+        (Partition::rootA, _) => 2,
+        (Partition::rootB, _) => 3,
+        (Partition::factory, true) => 4,
+        (Partition::factory, false) => 5,
+        (Partition::cert, true) => 5,
+        (Partition::cert, false) => 6,
+        (Partition::etc, true) => 6,
+        (Partition::etc, false) => 7,
+        (Partition::data, true) => 7,
+        (Partition::data, false) => 8,
+        (Partition::num(num), _) => *num,
+        (Partition::name(_) | Partition::label(_), _) => return None,
+    };
+    Some(num)
+}
 
-    Ok(())
+pub fn generate_bmap_file(image_file: &Path, bmap_file: &Path) -> Result<()> {
+    let bmap = Bmap::create(image_file)?;
+    debug!(
+        "generate_bmap_file: {} of {} blocks mapped",
+        bmap.mapped_blocks_count(),
+        bmap.blocks_count()
+    );
+    bmap.write_to(bmap_file)
 }
 
-fn write_partition(
-    image_file: &str,
-    partition_file: &str,
-    partition_info: &PartitionInfo,
-) -> Result<()> {
-    let mut dd = Command::new("dd");
-    dd.arg(format!("if={partition_file}"))
-        .arg(format!("of={image_file}"))
-        .arg("bs=512")
-        .arg(format!("seek={}", partition_info.start))
-        .arg(format!("count={}", partition_info.count))
-        .arg("conv=notrunc,sparse")
-        .arg("status=none");
-    exec_cmd!(dd);
-
-    let mut fallocate = Command::new("fallocate");
-    fallocate.arg("-d").arg(image_file);
-    exec_cmd!(fallocate);
-
-    let mut sync = Command::new("sync");
-    exec_cmd!(sync);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partition_selectors() {
+        for (s, partition) in [
+            ("rootB", Partition::rootB),
+            ("etc", Partition::etc),
+            ("data", Partition::data),
+            ("7", Partition::num(7)),
+            ("name=rootfs", Partition::name("rootfs".to_string())),
+            ("label=my data", Partition::label("my data".to_string())),
+        ] {
+            assert_eq!(Partition::from_str(s).unwrap(), partition);
+            assert_eq!(partition.to_string(), s);
+        }
+        assert!(Partition::from_str("0").is_err());
+        assert!(Partition::from_str("label=").is_err());
+        assert!(Partition::from_str("uuid=1234").is_err());
+        assert!(Partition::from_str("rootC").is_err());
+    }
 
-    Ok(())
-}
+    #[test]
+    fn file_attributes() {
+        assert_eq!(
+            FileAttributes::from_str("uid=1000,gid=100,mode=0640,mtime=1700000000").unwrap(),
+            FileAttributes {
+                uid: Some(1000),
+                gid: Some(100),
+                mode: Some(0o640),
+                mtime: Some(1700000000),
+            }
+        );
+        assert_eq!(
+            FileAttributes::from_str("mtime=2023-11-14T22:13:20Z")
+                .unwrap()
+                .mtime,
+            Some(1700000000)
+        );
+        assert!(FileAttributes::from_str("mode=0888").is_err());
+        assert!(FileAttributes::from_str("mode=10000").is_err());
+        assert!(FileAttributes::from_str("owner=root").is_err());
+        assert!(FileAttributes::from_str("uid").is_err());
+    }
 
-pub fn generate_bmap_file(image_file: &str) -> Result<()> {
-    let mut bmaptool = Command::new("bmaptool");
-    bmaptool
-        .arg("create")
-        .arg("-o")
-        .arg(format!("{image_file}.bmap"))
-        .arg(image_file);
-    exec_cmd!(bmaptool);
+    #[test]
+    fn partition_sizes() {
+        for (s, size) in [
+            ("1048576", PartitionSize::Absolute(1 << 20)),
+            ("512K", PartitionSize::Absolute(512 << 10)),
+            ("2G", PartitionSize::Absolute(2 << 30)),
+            ("2GiB", PartitionSize::Absolute(2 << 30)),
+            ("+256M", PartitionSize::Grow(256 << 20)),
+            ("+1MB", PartitionSize::Grow(1 << 20)),
+        ] {
+            assert_eq!(PartitionSize::from_str(s).unwrap(), size, "{s}");
+        }
 
-    Ok(())
+        for s in ["", "+", "M", "1X", "-1M", "1.5G", "99999999999T"] {
+            assert!(PartitionSize::from_str(s).is_err(), "{s}");
+        }
+
+        assert_eq!(PartitionSize::Grow(1 << 20).bytes(1 << 20), 2 << 20);
+        assert_eq!(PartitionSize::Absolute(1 << 20).bytes(4 << 20), 1 << 20);
+    }
 }
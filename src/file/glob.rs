@@ -0,0 +1,182 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Shell style wildcard pattern supporting `*`, `?`, `[...]` and `**`.
+///
+/// Patterns without a slash match the file name at any depth, all others
+/// match the path relative to the copied directory.
+#[derive(Clone, Debug)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+    name_only: bool,
+}
+
+impl Glob {
+    pub fn is_match(&self, rel_path: &Path) -> bool {
+        let candidate = if self.name_only {
+            rel_path.file_name().map(|n| n.to_string_lossy())
+        } else {
+            Some(rel_path.to_string_lossy())
+        };
+        candidate.is_some_and(|c| self.regex.is_match(&c))
+    }
+}
+
+impl Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+impl FromStr for Glob {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        anyhow::ensure!(!s.is_empty(), "glob pattern must not be empty");
+
+        let pattern = s.trim_start_matches('/');
+        let regex =
+            Regex::new(&to_regex(pattern)).context(format!("invalid glob pattern \"{s}\""))?;
+
+        Ok(Glob {
+            pattern: s.to_string(),
+            regex,
+            name_only: !s.contains('/'),
+        })
+    }
+}
+
+/// Include and exclude patterns applied when copying directories.
+#[derive(Clone, Debug, Default)]
+pub struct CopyFilter {
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+}
+
+impl CopyFilter {
+    pub fn new(include: Vec<Glob>, exclude: Vec<Glob>) -> Self {
+        CopyFilter { include, exclude }
+    }
+
+    /// true if the entry and, for directories, everything below is skipped
+    pub(crate) fn is_excluded(&self, rel_path: &Path) -> bool {
+        self.exclude.iter().any(|g| g.is_match(rel_path))
+    }
+
+    /// true if a file is copied
+    pub(crate) fn is_included(&self, rel_path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|g| g.is_match(rel_path)))
+            && !self.is_excluded(rel_path)
+    }
+
+    /// Empty directories are only recreated if no include pattern restricts
+    /// the copied files.
+    pub(crate) fn keeps_empty_dirs(&self) -> bool {
+        self.include.is_empty()
+    }
+}
+
+fn to_regex(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut re = String::from("^");
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                i += 1;
+                if chars.get(i + 1) == Some(&'/') {
+                    // "**/" also matches no directory at all
+                    i += 1;
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => match class_end(&chars, i) {
+                Some(end) => {
+                    let mut class = &chars[i + 1..end];
+                    re.push('[');
+                    if let Some('!' | '^') = class.first() {
+                        re.push('^');
+                        class = &class[1..];
+                    }
+                    for c in class {
+                        if matches!(c, '\\' | '[' | ']' | '&' | '~') {
+                            re.push('\\');
+                        }
+                        re.push(*c);
+                    }
+                    re.push(']');
+                    i = end;
+                }
+                None => re.push_str(r"\["),
+            },
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+        i += 1;
+    }
+
+    re.push('$');
+    re
+}
+
+// index of the "]" closing the class opened at `start`, a "]" directly after
+// the opening bracket (or negation) is part of the class
+fn class_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start + 1;
+    if let Some('!' | '^') = chars.get(i) {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    (i..chars.len()).find(|j| chars[*j] == ']')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_match(pattern: &str, path: &str) -> bool {
+        Glob::from_str(pattern).unwrap().is_match(Path::new(path))
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(is_match("*.conf", "a.conf"));
+        assert!(is_match("*.conf", "sub/dir/a.conf"));
+        assert!(!is_match("*.conf", "a.conf.bak"));
+        assert!(is_match("sub/*.conf", "sub/a.conf"));
+        assert!(!is_match("sub/*.conf", "sub/dir/a.conf"));
+        assert!(is_match("/sub/*.conf", "sub/a.conf"));
+        assert!(is_match("sub/**/*.conf", "sub/a.conf"));
+        assert!(is_match("sub/**/*.conf", "sub/dir/a.conf"));
+        assert!(is_match("**", "sub/dir/a.conf"));
+        assert!(is_match("file?.txt", "file1.txt"));
+        assert!(!is_match("file?.txt", "file10.txt"));
+        assert!(is_match("file[0-9].txt", "file1.txt"));
+        assert!(!is_match("file[!0-9].txt", "file1.txt"));
+        assert!(is_match("file[]].txt", "file].txt"));
+        assert!(is_match("file[.txt", "file[.txt"));
+        assert!(is_match("a+b.(1)", "a+b.(1)"));
+    }
+
+    #[test]
+    fn copy_filter() {
+        let glob = |s: &str| Glob::from_str(s).unwrap();
+        let filter = CopyFilter::new(vec![glob("*.service")], vec![glob("test")]);
+
+        assert!(filter.is_included(Path::new("units/a.service")));
+        assert!(!filter.is_included(Path::new("units/a.timer")));
+        assert!(filter.is_excluded(Path::new("units/test")));
+        assert!(!filter.keeps_empty_dirs());
+        assert!(CopyFilter::default().is_included(Path::new("anything")));
+    }
+}
@@ -1,20 +1,54 @@
+mod bmap;
 pub mod compression;
+mod ext4;
+mod fat;
+mod filesystem;
+pub mod flash;
 pub mod functions;
+pub mod glob;
 mod partition;
+pub mod seekable;
 use super::validators::{
     device_update,
     identity::{IdentityConfig, IdentityType, validate_identity},
     ssh::validate_ssh_pub_key,
 };
-use crate::file::functions::{FileCopyFromParams, FileCopyToParams, Partition};
+use crate::file::functions::{
+    FileAttributes, FileCopyFromParams, FileCopyToParams, FileListEntry, Partition, PartitionPath,
+    PartitionSize,
+};
 use anyhow::{Context, Result};
-use log::warn;
+use filesystem::is_error_kind;
+use log::{debug, warn};
 use regex::Regex;
 use std::{
-    fs,
+    fs, io,
+    os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
 };
 
+// user and group of the identity service's key daemon which loads the
+// private keys referenced in config.toml
+const KEY_OWNER: &str = "aziotks";
+
+// certificates and configuration without secrets
+const PUBLIC_FILE: FileAttributes = FileAttributes {
+    uid: Some(0),
+    gid: Some(0),
+    mode: Some(0o644),
+    // set by provisioned()
+    mtime: None,
+};
+
+// configuration which may contain secrets, e.g. connection strings
+const SECRET_FILE: FileAttributes = FileAttributes {
+    uid: Some(0),
+    gid: Some(0),
+    mode: Some(0o600),
+    // set by provisioned()
+    mtime: None,
+};
+
 pub fn set_iotedge_gateway_config(
     config_file: &Path,
     image_file: &Path,
@@ -32,22 +66,32 @@ pub fn set_iotedge_gateway_config(
             config_file,
             Partition::factory,
             Path::new("/etc/aziot/config.toml"),
-        ),
+        )
+        .with_attributes(provisioned(SECRET_FILE, config_file)?),
         FileCopyToParams::new(
             root_ca_file,
             Partition::cert,
             Path::new("/ca/trust-bundle.pem.crt"),
-        ),
+        )
+        .with_attributes(provisioned(PUBLIC_FILE, root_ca_file)?),
         FileCopyToParams::new(
             edge_device_identity_full_chain_file,
             Partition::cert,
             Path::new("/priv/edge-ca.pem"),
-        ),
+        )
+        .with_attributes(provisioned(
+            PUBLIC_FILE,
+            edge_device_identity_full_chain_file,
+        )?),
         FileCopyToParams::new(
             edge_device_identity_key_file,
             Partition::cert,
             Path::new("/priv/edge-ca.key.pem"),
-        ),
+        )
+        .with_attributes(provisioned(
+            private_key_attributes(image_file)?,
+            edge_device_identity_key_file,
+        )?),
     ]);
 
     copy_to_image(&file_copies, image_file)
@@ -72,8 +116,10 @@ pub fn set_iot_leaf_sas_config(
             config_file,
             Partition::factory,
             Path::new("/etc/aziot/config.toml"),
-        ),
-        FileCopyToParams::new(root_ca_file, Partition::cert, &root_ca_out_file),
+        )
+        .with_attributes(provisioned(SECRET_FILE, config_file)?),
+        FileCopyToParams::new(root_ca_file, Partition::cert, &root_ca_out_file)
+            .with_attributes(provisioned(PUBLIC_FILE, root_ca_file)?),
     ]);
 
     copy_to_image(&file_copies, image_file)
@@ -83,11 +129,10 @@ pub fn set_ssh_tunnel_certificate(image_file: &Path, root_ca_file: &Path) -> Res
     validate_ssh_pub_key(root_ca_file)?;
 
     copy_to_image(
-        &[FileCopyToParams::new(
-            root_ca_file,
-            Partition::cert,
-            Path::new("/ssh/root_ca"),
-        )],
+        &[
+            FileCopyToParams::new(root_ca_file, Partition::cert, Path::new("/ssh/root_ca"))
+                .with_attributes(provisioned(PUBLIC_FILE, root_ca_file)?),
+        ],
         image_file,
     )
 }
@@ -102,18 +147,25 @@ pub fn set_identity_config(
         .for_each(|x| warn!("{}", x));
 
     let mut file_copies = configure_hostname(config_file, image_file)?;
-    file_copies.append(&mut vec![FileCopyToParams::new(
-        config_file,
-        Partition::factory,
-        Path::new("/etc/aziot/config.toml"),
-    )]);
+    file_copies.append(&mut vec![
+        FileCopyToParams::new(
+            config_file,
+            Partition::factory,
+            Path::new("/etc/aziot/config.toml"),
+        )
+        .with_attributes(provisioned(SECRET_FILE, config_file)?),
+    ]);
 
+    // the payload is read by the identity service which doesn't run as root
     if let Some(p) = payload {
-        file_copies.push(FileCopyToParams::new(
-            p,
-            Partition::factory,
-            Path::new("/etc/omnect/dps-payload.json"),
-        ));
+        file_copies.push(
+            FileCopyToParams::new(
+                p,
+                Partition::factory,
+                Path::new("/etc/omnect/dps-payload.json"),
+            )
+            .with_attributes(provisioned(PUBLIC_FILE, p)?),
+        );
     }
     copy_to_image(&file_copies, image_file)
 }
@@ -135,8 +187,10 @@ fn set_cert(
     image_file: &Path,
 ) -> Result<()> {
     let mut copy_params = vec![
-        FileCopyToParams::new(cert.src, Partition::cert, cert.dest),
-        FileCopyToParams::new(key.src, Partition::cert, key.dest),
+        FileCopyToParams::new(cert.src, Partition::cert, cert.dest)
+            .with_attributes(provisioned(PUBLIC_FILE, cert.src)?),
+        FileCopyToParams::new(key.src, Partition::cert, key.dest)
+            .with_attributes(provisioned(private_key_attributes(image_file)?, key.src)?),
     ];
 
     if let Some(p) = intermediate_full_chain_cert {
@@ -145,12 +199,14 @@ fn set_cert(
                 p.src,
                 Partition::cert,
                 Path::new(&format!("/priv/{}.crt.pem", p.name)),
-            ),
+            )
+            .with_attributes(provisioned(PUBLIC_FILE, p.src)?),
             FileCopyToParams::new(
                 p.src,
                 Partition::cert,
                 Path::new(&format!("/ca/{}.crt", p.name)),
-            ),
+            )
+            .with_attributes(provisioned(PUBLIC_FILE, p.src)?),
         ])
     }
 
@@ -214,7 +270,8 @@ pub fn set_iot_hub_device_update_config(du_config_file: &Path, image_file: &Path
             du_config_file,
             Partition::factory,
             Path::new("/etc/adu/du-config.json"),
-        )],
+        )
+        .with_attributes(provisioned(PUBLIC_FILE, du_config_file)?)],
         image_file,
     )
 }
@@ -223,8 +280,40 @@ pub fn copy_to_image(file_copy_params: &[FileCopyToParams], image_file: &Path) -
     functions::copy_to_image(file_copy_params, image_file)
 }
 
-pub fn copy_from_image(file_copy_params: &[FileCopyFromParams], image_file: &Path) -> Result<()> {
-    functions::copy_from_image(file_copy_params, image_file)
+pub fn copy_from_image(
+    file_copy_params: &[FileCopyFromParams],
+    image_file: &Path,
+    dereference: bool,
+) -> Result<()> {
+    functions::copy_from_image(file_copy_params, image_file, dereference)
+}
+
+pub fn remove_from_image(
+    file_remove_params: &[PartitionPath],
+    image_file: &Path,
+    recursive: bool,
+) -> Result<()> {
+    functions::remove_from_image(file_remove_params, image_file, recursive)
+}
+
+pub fn move_in_image(from: &PartitionPath, to: &PartitionPath, image_file: &Path) -> Result<()> {
+    functions::move_in_image(from, to, image_file)
+}
+
+pub fn list_image(
+    file_list_params: &[PartitionPath],
+    image_file: &Path,
+    recursive: bool,
+) -> Result<Vec<FileListEntry>> {
+    functions::list_image(file_list_params, image_file, recursive)
+}
+
+pub fn resize_partition(
+    partition: &Partition,
+    size: &PartitionSize,
+    image_file: &Path,
+) -> Result<()> {
+    functions::resize_partition(partition, size, image_file)
 }
 
 fn configure_hostname(
@@ -256,6 +345,7 @@ fn configure_hostname(
             &hosts_file.to_path_buf(),
         )],
         image_file,
+        true,
     )
     .context("configure_hostname: couldn't read /etc/hosts from rootA")?;
 
@@ -276,15 +366,78 @@ fn configure_hostname(
             &hostname_file.to_path_buf(),
             Partition::factory,
             Path::new("/etc/hostname"),
-        ),
+        )
+        .with_attributes(provisioned(PUBLIC_FILE, identity_config_file)?),
         FileCopyToParams::new(
             &hosts_file.to_path_buf(),
             Partition::factory,
             Path::new("/etc/hosts"),
-        ),
+        )
+        .with_attributes(provisioned(PUBLIC_FILE, identity_config_file)?),
     ])
 }
 
+// Provisioned files get a reproducible modification time: SOURCE_DATE_EPOCH
+// if set, otherwise the one of the host file they are created from. Files
+// generated from the identity config, e.g. /etc/hostname, take the one of the
+// identity config.
+fn provisioned(attributes: FileAttributes, source: &Path) -> Result<FileAttributes> {
+    let mtime = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse::<i64>().context(format!(
+            "provisioned: invalid SOURCE_DATE_EPOCH \"{epoch}\""
+        ))?,
+        Err(_) => fs::metadata(source)
+            .context(format!(
+                "provisioned: cannot get modification time of {}",
+                source.display()
+            ))?
+            .mtime(),
+    };
+
+    Ok(FileAttributes {
+        mtime: Some(mtime),
+        ..attributes
+    })
+}
+
+// Private keys are only readable by the key service. Images without its
+// account fall back to root ownership.
+fn private_key_attributes(image_file: &Path) -> Result<FileAttributes> {
+    let uid = lookup_account_id(image_file, "/etc/passwd", KEY_OWNER)?;
+    let gid = lookup_account_id(image_file, "/etc/group", KEY_OWNER)?;
+
+    if uid.is_none() || gid.is_none() {
+        warn!("private_key_attributes: {KEY_OWNER} not found in image, keys are owned by root");
+    }
+
+    Ok(FileAttributes {
+        uid: uid.or(Some(0)),
+        gid: gid.or(Some(0)),
+        ..SECRET_FILE
+    })
+}
+
+// id of `name` in a passwd or group file of rootA
+fn lookup_account_id(image_file: &Path, file: &str, name: &str) -> Result<Option<u32>> {
+    let content = match functions::read_file_from_image(file, Partition::rootA, image_file) {
+        Ok(content) => content,
+        Err(e) if is_error_kind(&e, io::ErrorKind::NotFound) => {
+            debug!("lookup_account_id: {file} not found in image");
+            return Ok(None);
+        }
+        Err(e) => return Err(e).context(format!("lookup_account_id: cannot read {file}")),
+    };
+
+    // name:password:id:...
+    Ok(content.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next() != Some(name) {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    }))
+}
+
 pub(crate) fn get_file_path(image_path: &Path, file_name: &str) -> Result<PathBuf> {
     let mut file_path = image_path
         .parent()
@@ -1,39 +1,166 @@
+use super::seekable::SeekableImage;
 use anyhow::{Context, Result};
-use std::fs::File;
-use std::io::Seek;
-use std::io::SeekFrom;
+use log::debug;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
 use std::path::Path;
 
+// granularity used to find zeroed regions which are turned into holes
+const HOLE_BLOCK_SIZE: u64 = 4096;
+
+const SECTOR_SIZE: u64 = 512;
+// partitions are moved in steps of 1MiB to keep their alignment
+const PARTITION_ALIGNMENT: u64 = 2048;
+const MOVE_CHUNK_SIZE: u64 = 1024 * 1024;
+
+// GPT partition types which never hold an ext4 or FAT filesystem
+const FOREIGN_PARTITION_TYPES: &[(&str, &str)] = &[
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    ("A19D880F-05FC-4D3B-A006-743F0F84911E", "Linux RAID"),
+    ("CA7D7CCB-63ED-4C53-861C-1742536059CC", "Linux LUKS"),
+];
+
+// GPT partition types reserved for FAT filesystems
+const FAT_PARTITION_TYPES: &[(&str, &str)] = &[
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        "Microsoft basic data",
+    ),
+];
+
+#[derive(Debug)]
 pub struct PartitionData {
     pub num: u32,
     pub start: u64,
     pub count: u64,
+    /// partition name, only available on GPT
+    pub name: Option<String>,
+    pub partition_type: PartitionType,
+    /// detected filesystem, if any
+    pub filesystem: Option<FilesystemInfo>,
 }
 
-pub fn get_partition_data<P: AsRef<Path>>(path: P, partition_num: u32) -> Result<PartitionData> {
-    let path = path.as_ref();
-    let mut file =
-        File::open(path).with_context(|| format!("failed to open image: {}", path.display()))?;
+impl PartitionData {
+    pub fn label(&self) -> Option<&str> {
+        self.filesystem.as_ref().and_then(|fs| fs.label.as_deref())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    /// type GUID as stored in the GPT, i.e. mixed endian
+    Gpt([u8; 16]),
+    /// system id of a MBR partition
+    Mbr(u8),
+}
+
+impl PartitionType {
+    // well known name of GPT partition types, which are incompatible with
+    // `fs_type`
+    fn incompatible_with(&self, fs_type: FilesystemType) -> Option<&'static str> {
+        let PartitionType::Gpt(_) = self else {
+            return None;
+        };
+        let guid = self.to_string();
+        let lookup = |types: &[(&str, &'static str)]| {
+            types
+                .iter()
+                .find(|(g, _)| *g == guid)
+                .map(|(_, name)| *name)
+        };
+
+        lookup(FOREIGN_PARTITION_TYPES).or(match fs_type {
+            FilesystemType::Ext4 => lookup(FAT_PARTITION_TYPES),
+            FilesystemType::Fat => None,
+        })
+    }
+}
+
+impl Display for PartitionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionType::Gpt(g) => write!(
+                f,
+                "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+                u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+                u16::from_le_bytes([g[4], g[5]]),
+                u16::from_le_bytes([g[6], g[7]]),
+                g[8],
+                g[9],
+                g[10],
+                g[11],
+                g[12],
+                g[13],
+                g[14],
+                g[15]
+            ),
+            PartitionType::Mbr(id) => write!(f, "0x{id:02x}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilesystemType {
+    Ext4,
+    Fat,
+}
+
+impl Display for FilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilesystemType::Ext4 => write!(f, "ext4"),
+            FilesystemType::Fat => write!(f, "FAT"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilesystemInfo {
+    pub fs_type: FilesystemType,
+    pub label: Option<String>,
+}
 
+/// Returns all used partitions of the GPT or MBR, including logical
+/// partitions (5, 6, ...) of an MBR, together with their filesystems.
+pub fn get_partitions<P: AsRef<Path>>(path: P) -> Result<Vec<PartitionData>> {
+    let mut file = ImageFile::open(path.as_ref(), false)?;
+
+    let mut partitions = read_partition_table(&mut file)?;
+    for p in partitions.iter_mut() {
+        p.filesystem = probe_filesystem(&mut file, p.start, p.count)
+            .with_context(|| format!("failed to probe filesystem of partition {}", p.num))?;
+    }
+
+    Ok(partitions)
+}
+
+fn read_partition_table(file: &mut ImageFile) -> Result<Vec<PartitionData>> {
     // Try GPT first (validates CRC32 — more robust than signature check).
     // Capture any error so we can attach it as context if MBR also fails.
-    let gpt_err = match gptman::GPT::find_from(&mut file) {
+    let gpt_err = match gptman::GPT::find_from(file) {
         Ok(gpt) => {
-            let entry = gpt
-                .iter()
-                .find(|(i, _)| *i == partition_num)
-                .map(|(_, e)| e)
-                .with_context(|| format!("GPT partition {partition_num} out of range"))?;
-            anyhow::ensure!(entry.is_used(), "GPT partition {partition_num} is not used");
-            anyhow::ensure!(
-                entry.ending_lba >= entry.starting_lba,
-                "GPT partition {partition_num} has invalid LBA range (ending_lba < starting_lba)"
-            );
-            return Ok(PartitionData {
-                num: partition_num,
-                start: entry.starting_lba,
-                count: entry.ending_lba - entry.starting_lba + 1,
-            });
+            let mut partitions = vec![];
+            for (num, entry) in gpt.iter().filter(|(_, e)| e.is_used()) {
+                anyhow::ensure!(
+                    entry.ending_lba >= entry.starting_lba,
+                    "GPT partition {num} has invalid LBA range (ending_lba < starting_lba)"
+                );
+                let name = entry.partition_name.as_str().trim_end_matches('\0');
+                partitions.push(PartitionData {
+                    num,
+                    start: entry.starting_lba,
+                    count: entry.ending_lba - entry.starting_lba + 1,
+                    name: (!name.is_empty()).then(|| name.to_string()),
+                    partition_type: PartitionType::Gpt(entry.partition_type_guid),
+                    filesystem: None,
+                });
+            }
+            return Ok(partitions);
         }
         Err(e) => e,
     };
@@ -41,21 +168,755 @@ pub fn get_partition_data<P: AsRef<Path>>(path: P, partition_num: u32) -> Result
     // Try MBR — iter() includes logical partitions (5, 6, ...)
     file.seek(SeekFrom::Start(0))
         .context("failed to seek to start of image")?;
-    let mbr = mbrman::MBR::read_from(&mut file, 512)
+    let mbr = mbrman::MBR::read_from(file, 512)
         .with_context(|| format!("image is neither valid GPT nor MBR (GPT error: {gpt_err})"))?;
-    for (i, p) in mbr.iter() {
-        if u32::try_from(i).ok() == Some(partition_num) && p.is_used() {
-            return Ok(PartitionData {
-                num: partition_num,
+    Ok(mbr
+        .iter()
+        .filter(|(_, p)| p.is_used())
+        .filter_map(|(i, p)| {
+            Some(PartitionData {
+                num: u32::try_from(i).ok()?,
                 start: p.starting_lba as u64,
                 count: p.sectors as u64,
-            });
-        }
-    }
-    anyhow::bail!("partition {partition_num} not found in image")
+                name: None,
+                partition_type: PartitionType::Mbr(p.sys),
+                filesystem: None,
+            })
+        })
+        .collect())
 }
 
 pub fn is_gpt<P: AsRef<Path>>(path: P) -> Result<bool> {
-    let mut file = File::open(path.as_ref()).context("is_gpt: failed to open image")?;
+    let mut file = ImageFile::open(path.as_ref(), false).context("is_gpt: failed to open image")?;
     Ok(gptman::GPT::find_from(&mut file).is_ok())
 }
+
+/// Grows partition `num` to `count` sectors. Partitions behind it are moved
+/// towards the end of the image if the gap after the partition is too small
+/// and the image file is extended as needed.
+pub fn grow_partition<P: AsRef<Path>>(path: P, num: u32, count: u64) -> Result<()> {
+    let path = path.as_ref();
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("grow_partition: failed to open image: {}", path.display()))?;
+
+    match gptman::GPT::find_from(&mut file) {
+        Ok(gpt) => grow_gpt_partition(&mut file, gpt, num, count),
+        Err(_) => {
+            file.seek(SeekFrom::Start(0))
+                .context("grow_partition: failed to seek to start of image")?;
+            let mbr = mbrman::MBR::read_from(&mut file, SECTOR_SIZE as u32)
+                .context("grow_partition: image is neither valid GPT nor MBR")?;
+            grow_mbr_partition(&mut file, mbr, num as usize, count)
+        }
+    }
+}
+
+fn grow_gpt_partition(file: &mut File, mut gpt: gptman::GPT, num: u32, count: u64) -> Result<()> {
+    anyhow::ensure!(
+        gpt.sector_size == SECTOR_SIZE,
+        "grow_partition: sector size {} is not supported",
+        gpt.sector_size
+    );
+    anyhow::ensure!(
+        gpt.iter().any(|(i, e)| i == num && e.is_used()),
+        "grow_partition: partition {num} not found"
+    );
+
+    let start = gpt[num].starting_lba;
+    let old_end = gpt[num].ending_lba + 1;
+    let end = start + count;
+    let later: Vec<u32> = gpt
+        .iter()
+        .filter(|(_, e)| e.is_used() && e.starting_lba > start)
+        .map(|(i, _)| i)
+        .collect();
+    let next = later.iter().map(|i| gpt[*i].starting_lba).min();
+    let data_end = gpt
+        .iter()
+        .filter(|(_, e)| e.is_used())
+        .map(|(_, e)| e.ending_lba + 1)
+        .max()
+        .unwrap_or(end);
+
+    let shift = required_shift(end, next);
+    if let Some(next) = next
+        && shift > 0
+    {
+        move_sectors(file, next, data_end, shift)?;
+    }
+
+    for i in later {
+        gpt[i].starting_lba += shift;
+        gpt[i].ending_lba += shift;
+    }
+    gpt[num].ending_lba = end - 1;
+
+    // the backup GPT at the end of the disk needs room for its partition
+    // entries and header
+    let entry_sectors = (gpt.header.number_of_partition_entries as u64
+        * gpt.header.size_of_partition_entry as u64)
+        .div_ceil(SECTOR_SIZE);
+    let last_end = (data_end + shift).max(end);
+
+    if last_end > gpt.header.last_usable_lba + 1 {
+        let old_backup_lba = gpt.header.backup_lba;
+        let sectors = (last_end + entry_sectors + 1)
+            .next_multiple_of(PARTITION_ALIGNMENT)
+            .max(file.metadata()?.len() / SECTOR_SIZE);
+        file.set_len(sectors * SECTOR_SIZE)
+            .context("grow_partition: failed to extend image")?;
+
+        gpt.header.backup_lba = sectors - 1;
+        gpt.header.last_usable_lba = sectors - entry_sectors - 2;
+
+        // don't leave a stale backup header behind, which might be picked up
+        // by partitioning tools, unless it was overwritten by moved partitions
+        let overwritten = gpt.iter().any(|(i, e)| {
+            i != num && e.is_used() && (e.starting_lba..=e.ending_lba).contains(&old_backup_lba)
+        });
+        if !overwritten && !(start..old_end).contains(&old_backup_lba) {
+            file.seek(SeekFrom::Start(old_backup_lba * SECTOR_SIZE))
+                .and_then(|_| file.write_all(&[0; SECTOR_SIZE as usize]))
+                .context("grow_partition: failed to clear old backup GPT header")?;
+        }
+
+        gptman::GPT::write_protective_mbr_into(file, SECTOR_SIZE)
+            .context("grow_partition: failed to write protective MBR")?;
+    }
+
+    gpt.write_into(file)
+        .context("grow_partition: failed to write GPT")?;
+
+    debug!(
+        "grow_partition: partition {num} now has {count} sectors, moved later partitions by {shift} sectors"
+    );
+
+    Ok(())
+}
+
+fn grow_mbr_partition(file: &mut File, mut mbr: mbrman::MBR, num: usize, count: u64) -> Result<()> {
+    anyhow::ensure!(
+        mbr.iter()
+            .any(|(i, p)| i == num && p.is_used() && !p.is_extended()),
+        "grow_partition: partition {num} not found"
+    );
+
+    let start = mbr[num].starting_lba as u64;
+    let end = start + count;
+
+    // logical partitions are preceded by their extended boot record
+    let later: Vec<(usize, u64)> = mbr
+        .iter()
+        .filter(|(_, p)| p.is_used() && p.starting_lba as u64 > start)
+        .map(|(i, p)| match i {
+            1..=4 => (i, p.starting_lba as u64),
+            _ => (i, mbr.logical_partitions[i - 5].absolute_ebr_lba as u64),
+        })
+        .collect();
+    let next = later.iter().map(|(_, first)| *first).min();
+    let data_end = mbr
+        .iter()
+        .filter(|(_, p)| p.is_used())
+        .map(|(_, p)| p.starting_lba as u64 + p.sectors as u64)
+        .max()
+        .unwrap_or(end);
+
+    let shift = required_shift(end, next);
+    let last_end = (data_end + shift).max(end);
+    anyhow::ensure!(
+        last_end <= u32::MAX as u64,
+        "grow_partition: partitions would exceed the 2TiB limit of MBR"
+    );
+
+    if let Some(next) = next
+        && shift > 0
+    {
+        move_sectors(file, next, data_end, shift)?;
+    }
+
+    for (i, _) in later {
+        mbr[i].starting_lba += shift as u32;
+        if i > 4 {
+            mbr.logical_partitions[i - 5].absolute_ebr_lba += shift as u32;
+        }
+    }
+    mbr[num].sectors = count as u32;
+
+    if num > 4 {
+        // the size stored in the link to this partition's EBR is outdated
+        mbr.logical_partitions[num - 5].ebr_sectors = None;
+
+        // the extended partition has to enclose all logical partitions
+        let extended = (1..=4)
+            .find(|i| mbr[*i].is_extended())
+            .context("grow_partition: extended partition not found")?;
+        let logical_end = mbr
+            .logical_partitions
+            .iter()
+            .map(|l| l.partition.starting_lba as u64 + l.partition.sectors as u64)
+            .max()
+            .unwrap_or(end);
+        let ext_start = mbr[extended].starting_lba as u64;
+        let ext_end = (ext_start + mbr[extended].sectors as u64 + shift).max(logical_end);
+        mbr[extended].sectors = (ext_end - ext_start) as u32;
+    }
+
+    if last_end * SECTOR_SIZE > file.metadata()?.len() {
+        file.set_len(last_end.next_multiple_of(PARTITION_ALIGNMENT) * SECTOR_SIZE)
+            .context("grow_partition: failed to extend image")?;
+    }
+
+    mbr.write_into(file)
+        .context("grow_partition: failed to write MBR")?;
+
+    debug!(
+        "grow_partition: partition {num} now has {count} sectors, moved later partitions by {shift} sectors"
+    );
+
+    Ok(())
+}
+
+// sectors by which the partitions starting at `next` have to be moved, so that
+// a partition can end at `end`
+fn required_shift(end: u64, next: Option<u64>) -> u64 {
+    match next {
+        Some(next) if end > next => (end - next).next_multiple_of(PARTITION_ALIGNMENT),
+        _ => 0,
+    }
+}
+
+// Moves the sectors `first..end` by `shift` sectors towards the end of the
+// file. Copying starts at the end, so that overlapping ranges work.
+fn move_sectors(file: &mut File, first: u64, end: u64, shift: u64) -> Result<()> {
+    let file_len = file.metadata()?.len();
+    let mut buf = vec![0u8; MOVE_CHUNK_SIZE as usize];
+    let mut pos = end * SECTOR_SIZE;
+    let first = first * SECTOR_SIZE;
+    let shift = shift * SECTOR_SIZE;
+
+    debug!("move_sectors: moving {first}..{pos} by {shift} bytes");
+
+    while pos > first {
+        let len = (pos - first).min(MOVE_CHUNK_SIZE);
+        pos -= len;
+        let chunk = &mut buf[..len as usize];
+
+        file.seek(SeekFrom::Start(pos))
+            .and_then(|_| file.read_exact(chunk))
+            .with_context(|| format!("move_sectors: failed to read {len} bytes at {pos}"))?;
+
+        // keep the image sparse, beyond its old end there's nothing to overwrite
+        if pos + shift >= file_len && chunk.iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        file.seek(SeekFrom::Start(pos + shift))
+            .and_then(|_| file.write_all(chunk))
+            .with_context(|| {
+                format!(
+                    "move_sectors: failed to write {len} bytes at {}",
+                    pos + shift
+                )
+            })?;
+    }
+
+    Ok(())
+}
+
+/// Finds the partition with GPT partition name or filesystem label `name`.
+/// Returns `None` if no partition of the image has a name or label at all,
+/// so that the caller can fall back to a fixed partition layout.
+pub fn find_partition<'a>(
+    partitions: &'a [PartitionData],
+    name: &str,
+) -> Result<Option<&'a PartitionData>> {
+    if partitions
+        .iter()
+        .all(|p| p.name.is_none() && p.label().is_none())
+    {
+        return Ok(None);
+    }
+
+    unique_partition(
+        partitions
+            .iter()
+            .filter(|p| p.name.as_deref() == Some(name) || p.label() == Some(name)),
+        &format!("with name or label \"{name}\""),
+    )
+    .map(Some)
+}
+
+/// Finds the partition with GPT partition name `name`.
+pub fn find_partition_by_name<'a>(
+    partitions: &'a [PartitionData],
+    name: &str,
+) -> Result<&'a PartitionData> {
+    unique_partition(
+        partitions
+            .iter()
+            .filter(|p| p.name.as_deref() == Some(name)),
+        &format!("with GPT partition name \"{name}\""),
+    )
+}
+
+/// Finds the partition with filesystem label `label`.
+pub fn find_partition_by_label<'a>(
+    partitions: &'a [PartitionData],
+    label: &str,
+) -> Result<&'a PartitionData> {
+    unique_partition(
+        partitions.iter().filter(|p| p.label() == Some(label)),
+        &format!("with filesystem label \"{label}\""),
+    )
+}
+
+fn unique_partition<'a>(
+    mut matches: impl Iterator<Item = &'a PartitionData>,
+    what: &str,
+) -> Result<&'a PartitionData> {
+    let first = matches
+        .next()
+        .with_context(|| format!("no partition {what} found in image"))?;
+    let others: Vec<u32> = matches.map(|p| p.num).collect();
+    anyhow::ensure!(
+        others.is_empty(),
+        "partition {what} is ambiguous: partitions {} and {others:?} match",
+        first.num
+    );
+    Ok(first)
+}
+
+/// Makes sure that a partition holds a filesystem of type `expected`, or any
+/// supported one if `expected` is not given, and that its GPT partition type
+/// doesn't contradict it. Returns the type of the filesystem.
+pub fn check_filesystem(
+    partition: &PartitionData,
+    expected: Option<FilesystemType>,
+) -> Result<FilesystemType> {
+    let num = partition.num;
+    let fs_type = match (&partition.filesystem, expected) {
+        (Some(fs), Some(expected)) => {
+            anyhow::ensure!(
+                fs.fs_type == expected,
+                "partition {num} contains a {} filesystem, but {expected} is expected",
+                fs.fs_type
+            );
+            expected
+        }
+        (Some(fs), None) => fs.fs_type,
+        (None, Some(expected)) => {
+            anyhow::bail!("partition {num} doesn't contain the expected {expected} filesystem")
+        }
+        (None, None) => anyhow::bail!("partition {num} doesn't contain an ext4 or FAT filesystem"),
+    };
+
+    if let Some(name) = partition.partition_type.incompatible_with(fs_type) {
+        anyhow::bail!(
+            "partition {num} has GPT partition type {name} ({}), which doesn't fit its {fs_type} filesystem",
+            partition.partition_type
+        );
+    }
+
+    Ok(fs_type)
+}
+
+// Detects an ext4 or FAT filesystem at the start of a partition by its
+// superblock or boot sector.
+fn probe_filesystem(
+    file: &mut ImageFile,
+    start: u64,
+    count: u64,
+) -> Result<Option<FilesystemInfo>> {
+    file.seek(SeekFrom::Start(start * 512))?;
+
+    let mut buf = vec![0u8; 2048.min(count * 512) as usize];
+    let len = read_up_to(file, &mut buf)?;
+    let buf = &buf[..len];
+
+    // ext4 superblock at offset 1024 with magic 0xEF53
+    if buf.len() >= 2048 && buf[1024 + 0x38..1024 + 0x3A] == [0x53, 0xEF] {
+        return Ok(Some(FilesystemInfo {
+            fs_type: FilesystemType::Ext4,
+            label: volume_label(&buf[1024 + 0x78..1024 + 0x88]),
+        }));
+    }
+
+    // FAT boot sector with a plausible BIOS parameter block
+    if buf.len() >= 512 && buf[510..512] == [0x55, 0xAA] {
+        let bytes_per_sector = u16::from_le_bytes([buf[0x0B], buf[0x0C]]);
+        let sectors_per_cluster = buf[0x0D];
+        let reserved_sectors = u16::from_le_bytes([buf[0x0E], buf[0x0F]]);
+        let fats = buf[0x10];
+        if matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors > 0
+            && fats > 0
+        {
+            // FAT32 has no 16 bit FAT size and an extended BPB at 0x40
+            let ebpb = if u16::from_le_bytes([buf[0x16], buf[0x17]]) == 0 {
+                0x40
+            } else {
+                0x24
+            };
+            let label = (buf[ebpb + 2] == 0x29)
+                .then(|| volume_label(&buf[ebpb + 7..ebpb + 18]))
+                .flatten()
+                .filter(|l| l != "NO NAME");
+            return Ok(Some(FilesystemInfo {
+                fs_type: FilesystemType::Fat,
+                label,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+fn volume_label(raw: &[u8]) -> Option<String> {
+    let label = String::from_utf8_lossy(raw);
+    let label = label.trim_end_matches(['\0', ' ']);
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+// like read_exact, but stops at the end of the file
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+// An uncompressed image file or a compressed one which supports random
+// access, the latter is read-only.
+enum ImageFile {
+    Plain(File),
+    Compressed(SeekableImage),
+}
+
+impl ImageFile {
+    fn open(path: &Path, write: bool) -> Result<Self> {
+        if let Some(image) = SeekableImage::open(path)? {
+            return Ok(ImageFile::Compressed(image));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(write)
+            .open(path)
+            .with_context(|| format!("failed to open image: {}", path.display()))?;
+        Ok(ImageFile::Plain(file))
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            ImageFile::Plain(file) => Ok(file.metadata()?.len()),
+            ImageFile::Compressed(image) => Ok(image.len()),
+        }
+    }
+}
+
+impl Read for ImageFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ImageFile::Plain(file) => file.read(buf),
+            ImageFile::Compressed(image) => image.read(buf),
+        }
+    }
+}
+
+impl Write for ImageFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ImageFile::Plain(file) => file.write(buf),
+            ImageFile::Compressed(_) => Err(io::Error::new(
+                io::ErrorKind::ReadOnlyFilesystem,
+                "compressed images are read-only",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ImageFile::Plain(file) => file.flush(),
+            ImageFile::Compressed(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for ImageFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            ImageFile::Plain(file) => file.seek(pos),
+            ImageFile::Compressed(image) => image.seek(pos),
+        }
+    }
+}
+
+/// Gives access to a byte range of an image file, e.g. a partition, as if it
+/// was a device on its own. Regions written through the device are checked
+/// for zeroed blocks on flush, which are punched as holes to keep the image
+/// sparse. Compressed images with an index of their blocks can be read, but
+/// not written.
+pub struct PartitionDevice {
+    file: ImageFile,
+    offset: u64,
+    len: u64,
+    pos: u64,
+    // (start, end) ranges relative to `offset` which were written
+    written: Vec<(u64, u64)>,
+}
+
+impl PartitionDevice {
+    pub fn open<P: AsRef<Path>>(path: P, offset: u64, len: u64) -> Result<Self> {
+        Self::with_file(ImageFile::open(path.as_ref(), true)?, offset, len)
+    }
+
+    /// Opens the image read-only, so that writes fail.
+    pub fn open_read_only<P: AsRef<Path>>(path: P, offset: u64, len: u64) -> Result<Self> {
+        Self::with_file(ImageFile::open(path.as_ref(), false)?, offset, len)
+    }
+
+    fn with_file(file: ImageFile, offset: u64, len: u64) -> Result<Self> {
+        let size = file.len()?;
+        anyhow::ensure!(
+            offset.checked_add(len).is_some_and(|end| end <= size),
+            "partition range {offset}+{len} exceeds image size {size}"
+        );
+
+        Ok(PartitionDevice {
+            file,
+            offset,
+            len,
+            pos: 0,
+            written: vec![],
+        })
+    }
+
+    fn punch_holes(&mut self) -> io::Result<()> {
+        let mut ranges = std::mem::take(&mut self.written);
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, end) in ranges {
+            let start = (self.offset + start) / HOLE_BLOCK_SIZE * HOLE_BLOCK_SIZE;
+            let end = (self.offset + end).next_multiple_of(HOLE_BLOCK_SIZE);
+            match merged.last_mut() {
+                Some((_, e)) if *e >= start => *e = (*e).max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let size = self.file.len()?;
+        let mut buf = vec![0u8; HOLE_BLOCK_SIZE as usize];
+
+        for (start, end) in merged {
+            let end = end.min(size);
+            let mut hole: Option<u64> = None;
+            let mut block = start;
+
+            while block < end {
+                let n = (end - block).min(HOLE_BLOCK_SIZE) as usize;
+                self.file.seek(SeekFrom::Start(block))?;
+                self.file.read_exact(&mut buf[..n])?;
+                let zero = n == HOLE_BLOCK_SIZE as usize && buf.iter().all(|b| *b == 0);
+
+                match (zero, hole) {
+                    (true, None) => hole = Some(block),
+                    (false, Some(h)) => {
+                        self.punch_hole(h, block)?;
+                        hole = None;
+                    }
+                    _ => {}
+                }
+                block += n as u64;
+            }
+
+            if let Some(h) = hole {
+                self.punch_hole(h, block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn punch_hole(&self, start: u64, end: u64) -> io::Result<()> {
+        // nothing is written to compressed images
+        let ImageFile::Plain(file) = &self.file else {
+            return Ok(());
+        };
+
+        // SAFETY: fallocate only operates on the given file descriptor which
+        // stays valid for the lifetime of `self.file`
+        let res = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                start as libc::off_t,
+                (end - start) as libc::off_t,
+            )
+        };
+
+        if res != 0 {
+            let err = io::Error::last_os_error();
+            // not all filesystems support holes, the data is correct anyway
+            if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                debug!("punch_hole: not supported: {err}");
+                return Ok(());
+            }
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for PartitionDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.len.saturating_sub(self.pos)).min(buf.len() as u64) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.file.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let n = self.file.read(&mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for PartitionDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos + buf.len() as u64 > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "write of {} bytes at {} exceeds partition size {}",
+                    buf.len(),
+                    self.pos,
+                    self.len
+                ),
+            ));
+        }
+        self.file.seek(SeekFrom::Start(self.offset + self.pos))?;
+        let n = self.file.write(buf)?;
+        self.written.push((self.pos, self.pos + n as u64));
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.punch_holes()?;
+        self.file.flush()
+    }
+}
+
+impl Seek for PartitionDevice {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(num: u32, name: Option<&str>, label: Option<&str>) -> PartitionData {
+        PartitionData {
+            num,
+            start: num as u64 * 2048,
+            count: 2048,
+            name: name.map(str::to_string),
+            partition_type: PartitionType::Mbr(0x83),
+            filesystem: Some(FilesystemInfo {
+                fs_type: FilesystemType::Ext4,
+                label: label.map(str::to_string),
+            }),
+        }
+    }
+
+    #[test]
+    fn find_partitions() {
+        let unlabeled = [partition(1, None, None), partition(2, None, None)];
+        assert!(find_partition(&unlabeled, "rootA").unwrap().is_none());
+
+        let mbr = [
+            partition(1, None, Some("boot")),
+            partition(2, None, Some("rootA")),
+            partition(5, None, Some("factory")),
+        ];
+        assert_eq!(find_partition(&mbr, "factory").unwrap().unwrap().num, 5);
+        assert!(find_partition(&mbr, "cert").is_err());
+        assert!(find_partition_by_name(&mbr, "factory").is_err());
+        assert_eq!(find_partition_by_label(&mbr, "rootA").unwrap().num, 2);
+
+        let gpt = [
+            partition(1, Some("boot"), Some("boot")),
+            partition(2, Some("rootA"), Some("rootfs")),
+            partition(3, Some("rootB"), Some("rootfs")),
+        ];
+        assert_eq!(find_partition(&gpt, "rootB").unwrap().unwrap().num, 3);
+        assert_eq!(find_partition_by_name(&gpt, "rootA").unwrap().num, 2);
+        assert!(find_partition_by_label(&gpt, "rootfs").is_err());
+
+        let ambiguous = [
+            partition(2, Some("rootA"), None),
+            partition(3, None, Some("rootA")),
+        ];
+        let err = find_partition(&ambiguous, "rootA").unwrap_err();
+        assert!(err.to_string().contains("ambiguous"));
+    }
+
+    #[test]
+    fn filesystem_checks() {
+        // EFI System and Linux swap as stored on disk
+        let esp = PartitionType::Gpt([
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B,
+        ]);
+        let swap = PartitionType::Gpt([
+            0x6D, 0xFD, 0x57, 0x06, 0xAB, 0xA4, 0xC4, 0x43, 0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B,
+            0x4F, 0x4F,
+        ]);
+        assert_eq!(esp.to_string(), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+        assert_eq!(PartitionType::Mbr(0x83).to_string(), "0x83");
+
+        let mut p = partition(2, None, Some("rootA"));
+        assert_eq!(
+            check_filesystem(&p, Some(FilesystemType::Ext4)).unwrap(),
+            FilesystemType::Ext4
+        );
+        assert_eq!(check_filesystem(&p, None).unwrap(), FilesystemType::Ext4);
+        assert!(check_filesystem(&p, Some(FilesystemType::Fat)).is_err());
+
+        p.partition_type = esp;
+        assert!(check_filesystem(&p, Some(FilesystemType::Ext4)).is_err());
+
+        p.partition_type = swap;
+        assert!(check_filesystem(&p, None).is_err());
+
+        p.partition_type = PartitionType::Mbr(0x83);
+        p.filesystem = None;
+        assert!(check_filesystem(&p, Some(FilesystemType::Ext4)).is_err());
+        assert!(check_filesystem(&p, None).is_err());
+    }
+}
@@ -0,0 +1,599 @@
+use anyhow::{Context, Result};
+use log::debug;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FOOTER_MAGIC: &[u8] = b"YZ";
+const XZ_HEADER_SIZE: u64 = 12;
+const XZ_FOOTER_SIZE: u64 = 12;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+// the seek table is a skippable frame at the end of the file, see
+// https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+const ZSTD_SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const ZSTD_SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const ZSTD_CHECKSUM_FLAG: u8 = 0x80;
+
+// images with larger blocks are decompressed as a whole instead
+const MAX_BLOCK_SIZE: u64 = 256 * 1024 * 1024;
+// decompressed blocks kept in memory, the last used block is always kept
+const CACHE_SIZE: usize = 128 * 1024 * 1024;
+
+/// Returns whether `image_file` is compressed as independent blocks with an
+/// index, i.e. as multi-block xz or seekable zstd, so that it can be read
+/// without decompressing it as a whole.
+pub fn is_seekable(image_file: &Path) -> Result<bool> {
+    Ok(SeekableImage::open(image_file)?.is_some())
+}
+
+/// Returns the decompressed size of a xz or seekable zstd image as stored in
+/// its index.
+pub(crate) fn decompressed_size(image_file: &Path) -> Result<Option<u64>> {
+    let file = File::open(image_file).context(format!(
+        "decompressed_size: cannot open {}",
+        image_file.display()
+    ))?;
+
+    Ok(read_index(&file, image_file)?.map(|blocks| blocks.iter().map(|b| b.len).sum()))
+}
+
+// part of the image which can be decompressed on its own
+#[derive(Debug)]
+struct Block {
+    // position and size in the compressed file
+    offset: u64,
+    size: u64,
+    // position and size in the decompressed image
+    start: u64,
+    len: u64,
+    format: BlockFormat,
+}
+
+#[derive(Debug)]
+enum BlockFormat {
+    // a xz block is decompressed as stream of its own, which needs the header
+    // of its original stream and its size without padding
+    Xz {
+        header: [u8; XZ_HEADER_SIZE as usize],
+        unpadded_size: u64,
+    },
+    Zstd,
+}
+
+/// Read-only random access to a compressed image. Only the blocks covering
+/// the read data are decompressed.
+pub(crate) struct SeekableImage {
+    file: File,
+    // device and inode of `file`, which identify its blocks in the cache
+    id: (u64, u64),
+    blocks: Vec<Block>,
+    len: u64,
+    pos: u64,
+}
+
+// device, inode and offset of a block in the compressed file
+type BlockKey = (u64, u64, u64);
+
+// Decompressed blocks, least recently used first. The cache is shared, because
+// the image is opened again for every step, e.g. reading the partition table
+// and opening a filesystem.
+static CACHE: Mutex<Vec<(BlockKey, Arc<Vec<u8>>)>> = Mutex::new(vec![]);
+
+impl SeekableImage {
+    /// Opens a multi-block xz or seekable zstd image. Returns `None` for all
+    /// other files, including compressed images consisting of a single huge
+    /// block.
+    pub fn open(path: &Path) -> Result<Option<SeekableImage>> {
+        let file = File::open(path).context(format!(
+            "SeekableImage::open: cannot open {}",
+            path.display()
+        ))?;
+        let metadata = file.metadata()?;
+
+        let Some(blocks) = read_index(&file, path)? else {
+            return Ok(None);
+        };
+
+        if let Some(block) = blocks.iter().find(|b| b.len > MAX_BLOCK_SIZE) {
+            debug!(
+                "SeekableImage::open: {} has blocks of {} bytes, which are too large for random access",
+                path.display(),
+                block.len
+            );
+            return Ok(None);
+        }
+
+        let len = blocks.last().map_or(0, |b| b.start + b.len);
+        debug!(
+            "SeekableImage::open: {} has {} bytes in {} blocks",
+            path.display(),
+            len,
+            blocks.len()
+        );
+
+        Ok(Some(SeekableImage {
+            file,
+            id: (metadata.dev(), metadata.ino()),
+            blocks,
+            len,
+            pos: 0,
+        }))
+    }
+
+    /// Size of the decompressed image.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    // decompressed data of block `i`, either from the cache or the file
+    fn block(&self, i: usize) -> io::Result<Arc<Vec<u8>>> {
+        let block = &self.blocks[i];
+        let key = (self.id.0, self.id.1, block.offset);
+        let mut cache = CACHE
+            .lock()
+            .map_err(|_| io::Error::other("block cache poisoned"))?;
+
+        if let Some(pos) = cache.iter().position(|(k, _)| *k == key) {
+            let entry = cache.remove(pos);
+            let data = entry.1.clone();
+            cache.push(entry);
+            return Ok(data);
+        }
+
+        let data = Arc::new(decompress_block(&self.file, block).map_err(io::Error::other)?);
+        cache.push((key, data.clone()));
+
+        let mut cached: usize = cache.iter().map(|(_, data)| data.len()).sum();
+        while cached > CACHE_SIZE && cache.len() > 1 {
+            cached -= cache.remove(0).1.len();
+        }
+
+        Ok(data)
+    }
+}
+
+impl Read for SeekableImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+
+        let pos = self.pos;
+        let i = self.blocks.partition_point(|b| b.start + b.len <= pos);
+        let offset = (pos - self.blocks[i].start) as usize;
+        let data = self.block(i)?;
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SeekableImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len.checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        self.pos = new.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+// Reads the blocks of a xz file or a zstd file with seek table. Returns `None`
+// for all other files.
+fn read_index(file: &File, path: &Path) -> Result<Option<Vec<Block>>> {
+    let file_len = file.metadata()?.len();
+
+    let mut magic = [0u8; XZ_MAGIC.len()];
+    if file_len < magic.len() as u64 {
+        return Ok(None);
+    }
+    file.read_exact_at(&mut magic, 0)?;
+
+    let blocks = if magic.starts_with(XZ_MAGIC) {
+        xz_blocks(file, file_len).map(Some)
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        zstd_blocks(file, file_len)
+    } else {
+        return Ok(None);
+    }
+    .context(format!(
+        "read_index: cannot read block index of {}",
+        path.display()
+    ))?;
+
+    if blocks.is_none() {
+        debug!("read_index: {} has no seek table", path.display());
+    }
+
+    Ok(blocks)
+}
+
+// Collects the blocks of all streams of a xz file from their indexes. Streams
+// are read backwards, starting at the end of the file.
+fn xz_blocks(file: &File, file_len: u64) -> Result<Vec<Block>> {
+    let mut streams = vec![];
+    let mut end = file_len;
+
+    while end > 0 {
+        anyhow::ensure!(
+            end.is_multiple_of(4),
+            "xz_blocks: invalid file size {file_len}"
+        );
+
+        // skip stream padding
+        let mut word = [0u8; 4];
+        file.read_exact_at(&mut word, end - 4)?;
+        if word == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        anyhow::ensure!(
+            end >= XZ_HEADER_SIZE + XZ_FOOTER_SIZE,
+            "xz_blocks: truncated stream"
+        );
+        let mut footer = [0u8; XZ_FOOTER_SIZE as usize];
+        file.read_exact_at(&mut footer, end - XZ_FOOTER_SIZE)?;
+        anyhow::ensure!(
+            &footer[10..] == XZ_FOOTER_MAGIC,
+            "xz_blocks: invalid stream footer at {}",
+            end - XZ_FOOTER_SIZE
+        );
+
+        let index_size = (le32(&footer[4..8]) as u64 + 1) * 4;
+        let index_start = (end - XZ_FOOTER_SIZE)
+            .checked_sub(index_size)
+            .context("xz_blocks: invalid index size")?;
+        let mut index = vec![0u8; index_size as usize];
+        file.read_exact_at(&mut index, index_start)?;
+        let records = xz_index_records(&index)?;
+
+        let blocks_size: u64 = records
+            .iter()
+            .map(|(unpadded_size, _)| unpadded_size.next_multiple_of(4))
+            .sum();
+        let stream_start = index_start
+            .checked_sub(blocks_size + XZ_HEADER_SIZE)
+            .context("xz_blocks: index doesn't match the stream")?;
+
+        let mut header = [0u8; XZ_HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, stream_start)?;
+        anyhow::ensure!(
+            header.starts_with(XZ_MAGIC) && header[6..8] == footer[8..10],
+            "xz_blocks: invalid stream header at {stream_start}"
+        );
+
+        streams.push((stream_start, header, records));
+        end = stream_start;
+    }
+
+    let mut blocks = vec![];
+    let mut start = 0;
+
+    for (stream_start, header, records) in streams.into_iter().rev() {
+        let mut offset = stream_start + XZ_HEADER_SIZE;
+        for (unpadded_size, len) in records {
+            let size = unpadded_size.next_multiple_of(4);
+            blocks.push(Block {
+                offset,
+                size,
+                start,
+                len,
+                format: BlockFormat::Xz {
+                    header,
+                    unpadded_size,
+                },
+            });
+            offset += size;
+            start += len;
+        }
+    }
+
+    Ok(blocks)
+}
+
+// (unpadded size, uncompressed size) of the blocks listed in a xz index
+fn xz_index_records(index: &[u8]) -> Result<Vec<(u64, u64)>> {
+    let (body, crc) = index.split_at(index.len() - 4);
+    anyhow::ensure!(
+        crc32(body) == le32(crc),
+        "xz_index_records: index checksum mismatch"
+    );
+    anyhow::ensure!(
+        body.first() == Some(&0),
+        "xz_index_records: invalid index indicator"
+    );
+
+    let mut pos = 1;
+    let count = read_varint(body, &mut pos)?;
+    let mut records = vec![];
+
+    for _ in 0..count {
+        let unpadded_size = read_varint(body, &mut pos)?;
+        let len = read_varint(body, &mut pos)?;
+        records.push((unpadded_size, len));
+    }
+
+    Ok(records)
+}
+
+// Collects the frames of a seekable zstd file from its seek table. Returns
+// `None` if there is no seek table.
+fn zstd_blocks(file: &File, file_len: u64) -> Result<Option<Vec<Block>>> {
+    if file_len < ZSTD_SEEK_TABLE_FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut footer = [0u8; ZSTD_SEEK_TABLE_FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, file_len - ZSTD_SEEK_TABLE_FOOTER_SIZE)?;
+    if le32(&footer[5..9]) != ZSTD_SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+
+    let frames = le32(&footer[0..4]) as u64;
+    let entry_size = if footer[4] & ZSTD_CHECKSUM_FLAG != 0 {
+        12
+    } else {
+        8
+    };
+    // skippable frame header, entries and footer
+    let table_size = 8 + frames * entry_size + ZSTD_SEEK_TABLE_FOOTER_SIZE;
+    let table_start = file_len
+        .checked_sub(table_size)
+        .context("zstd_blocks: seek table exceeds the file")?;
+
+    let mut table = vec![0u8; (table_size - ZSTD_SEEK_TABLE_FOOTER_SIZE) as usize];
+    file.read_exact_at(&mut table, table_start)?;
+    anyhow::ensure!(
+        le32(&table[0..4]) == ZSTD_SEEK_TABLE_MAGIC && le32(&table[4..8]) as u64 == table_size - 8,
+        "zstd_blocks: invalid seek table frame at {table_start}"
+    );
+
+    let mut blocks = vec![];
+    let mut offset = 0;
+    let mut start = 0;
+
+    for entry in table[8..].chunks_exact(entry_size as usize) {
+        let size = le32(&entry[0..4]) as u64;
+        let len = le32(&entry[4..8]) as u64;
+        blocks.push(Block {
+            offset,
+            size,
+            start,
+            len,
+            format: BlockFormat::Zstd,
+        });
+        offset += size;
+        start += len;
+    }
+
+    anyhow::ensure!(
+        offset == table_start,
+        "zstd_blocks: seek table doesn't match the frames"
+    );
+
+    Ok(Some(blocks))
+}
+
+fn decompress_block(file: &File, block: &Block) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; block.size as usize];
+    file.read_exact_at(&mut data, block.offset)
+        .context(format!(
+            "decompress_block: cannot read block at {}",
+            block.offset
+        ))?;
+
+    let mut out = Vec::with_capacity(block.len as usize);
+    match &block.format {
+        BlockFormat::Xz {
+            header,
+            unpadded_size,
+        } => {
+            let trailer = xz_block_trailer(header, *unpadded_size, block.len);
+            xz2::read::XzDecoder::new(header.chain(&data[..]).chain(&trailer[..]))
+                .read_to_end(&mut out)
+        }
+        BlockFormat::Zstd => zstd::stream::read::Decoder::new(&data[..])?.read_to_end(&mut out),
+    }
+    .context(format!(
+        "decompress_block: cannot decompress block at {}",
+        block.offset
+    ))?;
+
+    anyhow::ensure!(
+        out.len() as u64 == block.len,
+        "decompress_block: block at {} has {} bytes instead of {}",
+        block.offset,
+        out.len(),
+        block.len
+    );
+
+    Ok(out)
+}
+
+// Index and footer of a xz stream consisting of a single block, so that the
+// block can be decompressed and verified by the standard decoder.
+fn xz_block_trailer(header: &[u8], unpadded_size: u64, len: u64) -> Vec<u8> {
+    let mut index = vec![0];
+    write_varint(&mut index, 1);
+    write_varint(&mut index, unpadded_size);
+    write_varint(&mut index, len);
+    index.resize(index.len().next_multiple_of(4), 0);
+    index.extend(crc32(&index).to_le_bytes());
+
+    let mut footer = ((index.len() / 4 - 1) as u32).to_le_bytes().to_vec();
+    footer.extend(&header[6..8]);
+
+    let mut trailer = index;
+    trailer.extend(crc32(&footer).to_le_bytes());
+    trailer.extend(footer);
+    trailer.extend(XZ_FOOTER_MAGIC);
+    trailer
+}
+
+// xz's variable length integers, 7 bits per byte with the least significant
+// bits first
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0;
+
+    for i in 0..9 {
+        let byte = *buf.get(*pos).context("read_varint: truncated index")?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    anyhow::bail!("read_varint: invalid integer in index")
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // incompressible enough to produce several blocks, but reproducible
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                if i % 3 == 0 { 0 } else { state as u8 }
+            })
+            .collect()
+    }
+
+    fn xz(data: &[u8], block_size: u64) -> Vec<u8> {
+        let stream = xz2::stream::MtStreamBuilder::new()
+            .threads(2)
+            .preset(1)
+            .block_size(block_size)
+            .encoder()
+            .unwrap();
+        let mut enc = xz2::write::XzEncoder::new_stream(vec![], stream);
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    fn check_reads(image: &mut SeekableImage, data: &[u8]) {
+        assert_eq!(image.len(), data.len() as u64);
+
+        for (pos, len) in [(0, 512), (70_000, 200_000), (150_000, 10), (299_990, 100)] {
+            let end = (pos + len).min(data.len());
+            let mut buf = vec![0u8; end - pos];
+            image.seek(SeekFrom::Start(pos as u64)).unwrap();
+            image.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, data[pos..end]);
+        }
+
+        let mut all = vec![];
+        image.rewind().unwrap();
+        image.read_to_end(&mut all).unwrap();
+        assert!(all == data);
+    }
+
+    #[test]
+    fn seekable_xz() {
+        let data = test_data(300_000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.wic.xz");
+
+        // two concatenated streams with stream padding in between
+        let mut file = xz(&data[..100_000], 32 * 1024);
+        file.extend([0; 8]);
+        file.extend(xz(&data[100_000..], 64 * 1024));
+        std::fs::write(&path, file).unwrap();
+
+        let mut image = SeekableImage::open(&path).unwrap().unwrap();
+        assert_eq!(image.blocks.len(), 4 + 4);
+        check_reads(&mut image, &data);
+    }
+
+    #[test]
+    fn seekable_zstd() {
+        let data = test_data(300_000);
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.wic.zst");
+
+        let mut file = vec![];
+        let mut table = vec![];
+        let frames = data.chunks(64 * 1024).collect::<Vec<_>>();
+        for frame in frames.iter() {
+            let compressed = zstd::bulk::compress(frame, 3).unwrap();
+            table.extend((compressed.len() as u32).to_le_bytes());
+            table.extend((frame.len() as u32).to_le_bytes());
+            file.extend(compressed);
+        }
+
+        std::fs::write(&path, &file).unwrap();
+        assert!(!is_seekable(&path).unwrap());
+
+        file.extend(ZSTD_SEEK_TABLE_MAGIC.to_le_bytes());
+        file.extend((table.len() as u32 + 9).to_le_bytes());
+        file.extend(table);
+        file.extend((frames.len() as u32).to_le_bytes());
+        file.push(0);
+        file.extend(ZSTD_SEEKABLE_MAGIC.to_le_bytes());
+        std::fs::write(&path, &file).unwrap();
+
+        let mut image = SeekableImage::open(&path).unwrap().unwrap();
+        assert_eq!(image.blocks.len(), frames.len());
+        check_reads(&mut image, &data);
+    }
+
+    #[test]
+    fn not_seekable() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("image.wic");
+
+        std::fs::write(&path, test_data(1000)).unwrap();
+        assert!(!is_seekable(&path).unwrap());
+
+        let mut enc = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+        enc.write_all(&test_data(1000)).unwrap();
+        std::fs::write(&path, enc.finish().unwrap()).unwrap();
+        assert!(!is_seekable(&path).unwrap());
+
+        // a corrupted index is an error rather than a reason to fall back
+        let mut file = xz(&test_data(1000), 1024);
+        let len = file.len();
+        file[len - 20] ^= 0xff;
+        std::fs::write(&path, file).unwrap();
+        assert!(is_seekable(&path).is_err());
+    }
+}
@@ -1,19 +1,21 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
 use std::path::Path;
 
+use crate::file::compression::Compression;
 use crate::file::functions::Partition;
-use crate::file::functions::read_file_from_image;
+use crate::file::functions::{PartitionTable, partition_table, read_file_from_image};
 use anyhow::{Context, Result};
-use regex::Regex;
-use std::sync::LazyLock;
+use log::warn;
+use serde::Serialize;
 
-// NOTE (2024-05-29 Tobias Langer): /etc/os-release is a symlink in our yocto
-// builds. The e2tools-suite cannot handle symlinks so we use its target
-// directly.
-const OS_RELEASE_PATH: &str = "/usr/lib/os-release";
+// see os-release(5): /etc/os-release takes precedence over
+// /usr/lib/os-release. In our yocto builds the former is a link to the latter,
+// which is followed.
+const OS_RELEASE_PATHS: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
 const OS_RELEASE_PARTITION: Partition = Partition::rootA;
-
-static ARCH_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r#"OMNECT_TARGET_ARCH="(?<arch>.*)""#).unwrap());
+const ARCH_KEY: &str = "OMNECT_TARGET_ARCH";
+const VERSION_KEY: &str = "VERSION_ID";
 
 #[allow(non_camel_case_types)]
 pub enum Architecture {
@@ -41,16 +43,114 @@ impl TryInto<Architecture> for &str {
 }
 
 pub fn image_arch(image: impl AsRef<Path>) -> Result<Architecture> {
-    let os_release_info = read_file_from_image(OS_RELEASE_PATH, OS_RELEASE_PARTITION, image)
-        .context("image_arch: could not read os-release info")?;
+    let os_release = os_release(image).context("image_arch: could not read os-release info")?;
 
-    let arch = ARCH_REGEX
-        .captures(&os_release_info)
-        .ok_or(anyhow::anyhow!(
-            "image_arch: os-release does not contain architecture information"
-        ))?;
+    let arch = os_release.get(ARCH_KEY).ok_or(anyhow::anyhow!(
+        "image_arch: os-release does not contain architecture information"
+    ))?;
 
-    arch["arch"]
+    arch.as_str()
         .try_into()
-        .context(format!("Unsupported architecture type: {}", &arch["arch"]))
+        .context(format!("Unsupported architecture type: {arch}"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    compression: Option<String>,
+    architecture: Option<String>,
+    omnect_version: Option<String>,
+    partition_table: PartitionTable,
+    os_release: Option<BTreeMap<String, String>>,
+}
+
+impl Display for ImageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let or_unknown = |v: &Option<String>| v.clone().unwrap_or("unknown".to_string());
+
+        writeln!(
+            f,
+            "compression: {}",
+            self.compression.as_deref().unwrap_or("none")
+        )?;
+        writeln!(f, "architecture: {}", or_unknown(&self.architecture))?;
+        writeln!(f, "omnect version: {}", or_unknown(&self.omnect_version))?;
+        writeln!(f, "{}", self.partition_table)?;
+        write!(f, "os-release:")?;
+
+        match &self.os_release {
+            Some(os_release) => {
+                for (key, value) in os_release.iter() {
+                    write!(f, "\n  {key}={value}")?;
+                }
+            }
+            None => write!(f, " unknown")?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects partition layout, free space and os-release information of an
+/// uncompressed image. `compression` is the compression of the original file.
+/// A missing os-release only leaves the related fields empty.
+pub fn image_info(image: impl AsRef<Path>, compression: Option<Compression>) -> Result<ImageInfo> {
+    let image = image.as_ref();
+    let partition_table =
+        partition_table(image).context("image_info: could not read partition table")?;
+    let os_release = os_release(image)
+        .inspect_err(|e| warn!("image_info: could not read os-release info: {e:#}"))
+        .ok();
+    let get = |key: &str| os_release.as_ref().and_then(|o| o.get(key).cloned());
+
+    Ok(ImageInfo {
+        compression: compression.map(|c| c.to_string()),
+        architecture: get(ARCH_KEY),
+        omnect_version: get(VERSION_KEY),
+        partition_table,
+        os_release,
+    })
+}
+
+fn os_release(image: impl AsRef<Path>) -> Result<BTreeMap<String, String>> {
+    let [etc, usr_lib] = OS_RELEASE_PATHS;
+    let content = read_file_from_image(etc, OS_RELEASE_PARTITION, &image)
+        .or_else(|_| read_file_from_image(usr_lib, OS_RELEASE_PARTITION, &image))?;
+
+    Ok(parse_os_release(&content))
+}
+
+// see os-release(5): KEY=VALUE lines, values may be quoted, "#" starts a comment
+fn parse_os_release(content: &str) -> BTreeMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q).and_then(|v| v.strip_suffix(*q)))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.replace("\\\"", "\""))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn os_release_parsing() {
+        let os_release = parse_os_release(
+            "# comment\nID=omnect-os\nVERSION_ID=4.0.18.0\nNAME=\"omnect \\\"os\\\"\"\n\nOMNECT_TARGET_ARCH='aarch64'\ninvalid\n",
+        );
+
+        assert_eq!(os_release.len(), 4);
+        assert_eq!(os_release["ID"], "omnect-os");
+        assert_eq!(os_release["VERSION_ID"], "4.0.18.0");
+        assert_eq!(os_release["NAME"], "omnect \"os\"");
+        assert_eq!(os_release["OMNECT_TARGET_ARCH"], "aarch64");
+    }
 }
@@ -7,62 +7,78 @@ pub mod docker;
 pub mod file;
 pub mod image;
 pub mod ssh;
+mod temp_path;
 mod validators;
 use anyhow::{Context, Result};
 use cli::{
     Command,
     Docker::Inject,
-    File::{CopyFromImage, CopyToImage},
+    File::{CopyFromImage, CopyToImage, Ls, Mv, Rm},
     IdentityConfig::{
         SetConfig, SetDeviceCertificate, SetDeviceCertificateNoEst, SetEdgeCaCertificate,
         SetIotLeafSasConfig, SetIotedgeGatewayConfig,
     },
+    Image::{Flash, Info, ResizePartition},
     IotHubDeviceUpdate::{self, SetDeviceConfig as IotHubDeviceUpdateSet},
     SshConfig::{SetCertificate, SetConnection},
 };
-use file::{compression::Compression, functions::FileCopyToParams};
-use log::error;
-use std::{fs, path::Path, path::PathBuf};
-use tokio::fs::remove_dir_all;
+use file::{
+    compression::Compression,
+    functions::{FileCopyFromParams, FileCopyToParams},
+    glob::CopyFilter,
+};
+use log::debug;
+use std::{
+    ffi::CString,
+    fs,
+    mem::MaybeUninit,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+    path::PathBuf,
+};
 use uuid::Uuid;
 
 use crate::file::compression;
+use crate::temp_path::TempPath;
 
-struct TempDirGuard(PathBuf);
-
-impl Drop for TempDirGuard {
-    fn drop(&mut self) {
-        let Ok(rt) = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-        else {
-            error!("cannot create tokio runtime");
-            return;
-        };
-
-        rt.block_on(async {
-            if let Err(e) = remove_dir_all(self.0.clone()).await {
-                error!("cannot remove tmp dir: {e}")
-            }
-        })
-    }
+// Options of commands which modify an image, i.e. where and how the result is
+// stored.
+struct ImageOptions {
+    generate_bmap: bool,
+    compress_image: Option<Compression>,
+    compression_level: Option<u32>,
+    threads: Option<u32>,
+    work_dir: Option<PathBuf>,
+    // the result replaces the image unless it is written to `output`
+    output: Option<PathBuf>,
 }
 
-fn run_image_command<F>(
-    image_file: PathBuf,
-    generate_bmap: bool,
-    target_compression: Option<Compression>,
-    command: F,
-) -> Result<()>
+fn run_image_command<F>(image_file: PathBuf, options: ImageOptions, command: F) -> Result<()>
 where
     F: FnOnce(&PathBuf) -> Result<()>,
 {
-    if let Ok("true") | Ok("1") = std::env::var("CONTAINERIZED").as_deref() {
-        anyhow::ensure!(
-            !generate_bmap,
-            "run_image_command: generating bmap file is not supported in containerized environments."
-        );
-    }
+    let ImageOptions {
+        generate_bmap,
+        compress_image,
+        compression_level,
+        threads,
+        work_dir,
+        output,
+    } = options;
+
+    // the output image is packed according to its extension
+    let target_compression = match &output {
+        Some(output) => Compression::from_extension(output),
+        None => compress_image,
+    };
+
+    let target_compression = match (target_compression, compression_level, threads) {
+        (Some(c), Some(level), _) => Some(c.with_level(level)?),
+        (None, Some(_), _) | (None, _, Some(_)) => anyhow::bail!(
+            "run_image_command: --compression-level and --threads require a packed output image"
+        ),
+        (c, _, _) => c,
+    };
 
     anyhow::ensure!(
         image_file.try_exists().is_ok_and(|exists| exists),
@@ -71,15 +87,24 @@ where
     );
 
     let mut dest_image_file = image_file.clone();
-
-    // create /tmp/{uuid}/ and copy image into
-    let tmp_dir = PathBuf::from(format!("/tmp/{}", Uuid::new_v4()));
-    fs::create_dir_all(tmp_dir.clone()).context(format!(
-        "run_image_command: couldn't create destination path {}",
-        tmp_dir.to_str().context("cannot get tmp dir name")?
-    ))?;
-
-    let _guard = TempDirGuard(tmp_dir.clone());
+    let source_compression = Compression::from_file(&image_file)?;
+
+    // create {work dir}/{uuid}/ and copy image into
+    let guard = create_tmp_dir(&image_file, source_compression.is_some(), work_dir)?;
+    let tmp_dir = guard.path();
+
+    // the result is written next to the output or the image
+    let dest_dir = match output.as_ref().unwrap_or(&image_file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    check_dest_dir_space(
+        &dest_dir,
+        tmp_dir,
+        &image_file,
+        source_compression.is_some(),
+        target_compression.is_some(),
+    )?;
 
     let mut tmp_image_file = tmp_dir.join(
         image_file
@@ -88,9 +113,12 @@ where
     );
 
     // if applicable decompress image to *.wic
-    if let Some(source_compression) = Compression::from_file(&image_file)? {
-        std::fs::copy(&image_file, &tmp_image_file)?;
-        tmp_image_file = compression::decompress(&tmp_image_file, &source_compression)?;
+    if let Some(source_compression) = source_compression {
+        tmp_image_file = tmp_dir.join(compression::decompressed_file_name(
+            &image_file,
+            &source_compression,
+        ));
+        compression::decompress(&image_file, &tmp_image_file, &source_compression)?;
         dest_image_file.set_extension("");
     } else {
         // copy sparse file (std::fs::copy isn't able)
@@ -103,53 +131,276 @@ where
     // run command
     command(&tmp_image_file)?;
 
-    // create and copy back bmap file if one was created
+    let tmp_image_file_name = tmp_image_file
+        .file_name()
+        .context("cannot get image file name")?
+        .to_str()
+        .context("cannot get image file name")?;
+
+    // create bmap file next to the image, named after the uncompressed image
     if generate_bmap {
-        let mut target_bmap = image_file
-            .parent()
-            .context("cannot get parent dir of image path")?
-            .to_path_buf();
-        let tmp_bmap = PathBuf::from(format!(
-            "{}.bmap",
-            tmp_image_file
-                .to_str()
-                .context("cannot get image file path")?
+        let target_bmap = match &output {
+            Some(output) if target_compression.is_some() => {
+                PathBuf::from(format!("{}.bmap", output.with_extension("").display()))
+            }
+            Some(output) => PathBuf::from(format!("{}.bmap", output.display())),
+            None => image_file
+                .parent()
+                .context("cannot get parent dir of image path")?
+                .join(format!("{tmp_image_file_name}.bmap")),
+        };
+        file::functions::generate_bmap_file(&tmp_image_file, &target_bmap)?;
+    }
+
+    if let Some(output) = output {
+        dest_image_file = output;
+    } else if let Some(c) = &target_compression {
+        dest_image_file.set_file_name(compression::compressed_file_name(
+            Path::new(tmp_image_file_name),
+            c,
         ));
-        file::functions::generate_bmap_file(
-            tmp_image_file
-                .to_str()
-                .context("cannot get image file path")?,
-        )?;
-        target_bmap.push(tmp_bmap.file_name().context("cannot get bmap file name")?);
-        std::fs::copy(&tmp_bmap, &target_bmap).context(format!(
-            "error: std::fs::copy({:?}, {:?})",
-            tmp_bmap, target_bmap
-        ))?;
     }
 
-    // if applicable compress image
-    if let Some(c) = target_compression {
-        tmp_image_file = compression::compress(&tmp_image_file, &c)?;
-        dest_image_file.set_file_name(
-            tmp_image_file
-                .file_name()
-                .context("cannot get image file name")?,
+    write_atomically(&dest_image_file, |dest_tmp_file| {
+        // if applicable compress image
+        if let Some(c) = target_compression {
+            compression::compress(
+                &tmp_image_file,
+                dest_tmp_file,
+                &c,
+                threads.unwrap_or(num_cpus::get() as u32),
+            )
+        } else {
+            // copy sparse file (std::fs::copy isn't able)
+            libfs::copy_file(&tmp_image_file, dest_tmp_file).context(format!(
+                "error: libfs::copy_file({:?}, {:?})",
+                tmp_image_file, dest_tmp_file
+            ))?;
+            Ok(())
+        }
+    })
+}
+
+// Writes `dest_file` via a temporary file in the same directory, which is
+// synced and renamed, so that an existing destination is replaced atomically
+// and an interrupted write leaves no partially written destination behind.
+fn write_atomically<F>(dest_file: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let dest_dir = match dest_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let dest_name = dest_file
+        .file_name()
+        .context("write_atomically: cannot get destination file name")?;
+    let tmp_file = TempPath::new(dest_dir.join(format!(
+        ".{}.{}",
+        dest_name.to_string_lossy(),
+        Uuid::new_v4()
+    )));
+
+    write(tmp_file.path())?;
+
+    let file = fs::File::open(tmp_file.path()).context(format!(
+        "write_atomically: cannot open {}",
+        tmp_file.path().display()
+    ))?;
+    // a replaced image keeps its permissions
+    if let Ok(metadata) = fs::metadata(dest_file) {
+        file.set_permissions(metadata.permissions())
+            .context("write_atomically: cannot set permissions")?;
+    }
+    file.sync_all().context(format!(
+        "write_atomically: cannot sync {}",
+        tmp_file.path().display()
+    ))?;
+
+    tmp_file.persist(dest_file).context(format!(
+        "write_atomically: cannot rename to {}",
+        dest_file.display()
+    ))?;
+
+    // make the rename itself durable
+    fs::File::open(dest_dir)
+        .and_then(|dir| dir.sync_all())
+        .context(format!(
+            "write_atomically: cannot sync {}",
+            dest_dir.display()
+        ))
+}
+
+// Runs a command which only reads the image, which is never written.
+// Uncompressed images and compressed ones with an index of their blocks are
+// read in place, other compressed images are decompressed to a temporary file.
+fn run_image_read_command<F>(
+    image_file: PathBuf,
+    work_dir: Option<PathBuf>,
+    command: F,
+) -> Result<()>
+where
+    F: FnOnce(&PathBuf) -> Result<()>,
+{
+    anyhow::ensure!(
+        image_file.try_exists().is_ok_and(|exists| exists),
+        "run_image_read_command: image doesn't exist {}",
+        image_file.to_str().context("cannot get image file path")?
+    );
+
+    let Some(source_compression) = Compression::from_file(&image_file)? else {
+        return command(&image_file);
+    };
+
+    if file::seekable::is_seekable(&image_file)? {
+        return command(&image_file);
+    }
+
+    let guard = create_tmp_dir(&image_file, true, work_dir)?;
+    let tmp_image_file = guard.path().join(compression::decompressed_file_name(
+        &image_file,
+        &source_compression,
+    ));
+    compression::decompress(&image_file, &tmp_image_file, &source_compression)?;
+
+    command(&tmp_image_file)
+}
+
+// Creates {work dir}/{uuid}/ for the uncompressed copy of `image_file`, which
+// is removed when the guard is dropped or the process is interrupted. The work
+// dir defaults to $TMPDIR or /tmp.
+fn create_tmp_dir(
+    image_file: &Path,
+    compressed: bool,
+    work_dir: Option<PathBuf>,
+) -> Result<TempPath> {
+    let guard = TempPath::new(
+        work_dir
+            .unwrap_or_else(std::env::temp_dir)
+            .join(Uuid::new_v4().to_string()),
+    );
+    fs::create_dir_all(guard.path()).context(format!(
+        "create_tmp_dir: couldn't create destination path {}",
+        guard.path().to_str().context("cannot get tmp dir name")?
+    ))?;
+
+    check_work_dir_space(guard.path(), image_file, compressed)?;
+
+    Ok(guard)
+}
+
+// Fails early if the work dir can't hold the uncompressed copy of the image.
+fn check_work_dir_space(tmp_dir: &Path, image_file: &Path, compressed: bool) -> Result<()> {
+    let Some(required) = image_copy_size(image_file, compressed)? else {
+        debug!(
+            "check_work_dir_space: decompressed size of {} is unknown",
+            image_file.display()
         );
-        std::fs::copy(&tmp_image_file, &dest_image_file).context(format!(
-            "error: std::fs::copy({:?}, {:?})",
-            tmp_image_file, dest_image_file
-        ))?;
+        return Ok(());
+    };
+
+    let available = available_space(tmp_dir)?;
+    debug!(
+        "check_work_dir_space: {} bytes required, {available} bytes available in {}",
+        required,
+        tmp_dir.display()
+    );
+
+    anyhow::ensure!(
+        required <= available,
+        "check_work_dir_space: {} needs up to {required} bytes, but only {available} bytes are available in {}; choose another directory via --work-dir or TMPDIR",
+        image_file.display(),
+        tmp_dir.parent().unwrap_or(tmp_dir).display()
+    );
+
+    Ok(())
+}
+
+// Fails early if the destination dir can't hold the resulting image, which is
+// written next to the replaced image before the latter is removed. An
+// uncompressed result needs as much as the work copy. A recompressed one is
+// assumed to be about as large as the compressed image and otherwise needs up
+// to the size of the work copy. If the destination dir shares its filesystem
+// with the work dir, the work copy has to fit as well.
+fn check_dest_dir_space(
+    dest_dir: &Path,
+    tmp_dir: &Path,
+    image_file: &Path,
+    compressed: bool,
+    recompressed: bool,
+) -> Result<()> {
+    let Some(copy) = image_copy_size(image_file, compressed)? else {
+        debug!(
+            "check_dest_dir_space: decompressed size of {} is unknown",
+            image_file.display()
+        );
+        return Ok(());
+    };
+
+    let mut required = if compressed && recompressed {
+        fs::metadata(image_file)?.len()
     } else {
-        // copy sparse file (std::fs::copy isn't able)
-        libfs::copy_file(&tmp_image_file, &dest_image_file).context(format!(
-            "error: libfs::copy_file({:?}, {:?})",
-            tmp_image_file, dest_image_file
-        ))?;
+        copy
+    };
+    let dest_dev = fs::metadata(dest_dir)
+        .context(format!(
+            "check_dest_dir_space: cannot access {}",
+            dest_dir.display()
+        ))?
+        .dev();
+    if dest_dev == fs::metadata(tmp_dir)?.dev() {
+        required += copy;
     }
 
+    let available = available_space(dest_dir)?;
+    debug!(
+        "check_dest_dir_space: {} bytes required, {available} bytes available in {}",
+        required,
+        dest_dir.display()
+    );
+
+    anyhow::ensure!(
+        required <= available,
+        "check_dest_dir_space: the resulting image of {} needs up to {required} bytes, but only {available} bytes are available in {}",
+        image_file.display(),
+        dest_dir.display()
+    );
+
     Ok(())
 }
 
+// Size of the uncompressed copy of an image. Copies of uncompressed images
+// keep their holes, so only their allocated blocks count. Decompressed images
+// need up to their full size, which is only known for xz and seekable zstd.
+fn image_copy_size(image_file: &Path, compressed: bool) -> Result<Option<u64>> {
+    if compressed {
+        file::seekable::decompressed_size(image_file)
+    } else {
+        Ok(Some(fs::metadata(image_file)?.blocks() * 512))
+    }
+}
+
+// bytes available to unprivileged users on the filesystem of `path`
+fn available_space(path: &Path) -> Result<u64> {
+    let c_path =
+        CString::new(path.as_os_str().as_bytes()).context("available_space: invalid path")?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: c_path is a valid nul terminated string and stat is only read
+    // after statvfs succeeded
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error()).context(format!(
+                "available_space: cannot get filesystem statistics of {}",
+                path.display()
+            ));
+        }
+        stat.assume_init()
+    };
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
 struct CertInfo {
     cert_path: PathBuf,
     key_path: PathBuf,
@@ -199,49 +450,77 @@ pub fn run() -> Result<()> {
             dest,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            anyhow::ensure!(
-                dest.to_string_lossy().ends_with(".tar.gz"),
-                format!(
-                    "invalid destination file path \"{}\". Must end in \".tar.gz\".",
-                    dest.to_string_lossy(),
-                ),
-            );
-
-            let arch = image::image_arch(img)?;
-
-            let docker_path = docker::pull_image(&docker_image, arch)?;
-
-            let result = file::copy_to_image(
-                &[FileCopyToParams::new(
-                    &docker_path,
-                    partition.clone(),
-                    &dest,
-                )],
-                img,
-            );
-            std::fs::remove_file(docker_path)?;
-
-            if result.is_ok() {
-                println!(
-                    "Stored {} to {}:{}",
-                    docker_image,
-                    partition,
-                    dest.to_string_lossy(),
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img| {
+                anyhow::ensure!(
+                    dest.to_string_lossy().ends_with(".tar.gz"),
+                    format!(
+                        "invalid destination file path \"{}\". Must end in \".tar.gz\".",
+                        dest.to_string_lossy(),
+                    ),
                 );
-            }
 
-            result
-        })?,
+                let arch = image::image_arch(img)?;
+
+                let docker_path = docker::pull_image(&docker_image, arch)?;
+
+                let result = file::copy_to_image(
+                    &[FileCopyToParams::new(
+                        &docker_path,
+                        partition.clone(),
+                        &dest,
+                    )],
+                    img,
+                );
+                std::fs::remove_file(docker_path)?;
+
+                if result.is_ok() {
+                    println!(
+                        "Stored {} to {}:{}",
+                        docker_image,
+                        partition,
+                        dest.to_string_lossy(),
+                    );
+                }
+
+                result
+            },
+        )?,
         Command::Identity(SetConfig {
             config,
             image,
             payload,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_identity_config(&config, img, payload.as_deref())
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img| file::set_identity_config(&config, img, payload.as_deref()),
+        )?,
         Command::Identity(SetDeviceCertificate {
             intermediate_full_chain_cert,
             intermediate_key,
@@ -250,6 +529,10 @@ pub fn run() -> Result<()> {
             days,
             generate_bmap,
             compress_image,
+            compression_level,
+            threads,
+            work_dir,
+            output,
         }) => {
             let cert_info = create_image_cert(
                 &image,
@@ -264,14 +547,25 @@ pub fn run() -> Result<()> {
             )
             .context("set_device_certificate: could not create certificate")?;
 
-            run_image_command(image, generate_bmap, compress_image, |img| {
-                file::set_device_cert(
-                    Some(&intermediate_full_chain_cert),
-                    &cert_info.cert_path,
-                    &cert_info.key_path,
-                    img,
-                )
-            })?
+            run_image_command(
+                image,
+                ImageOptions {
+                    generate_bmap,
+                    compress_image,
+                    compression_level,
+                    threads,
+                    work_dir,
+                    output,
+                },
+                |img| {
+                    file::set_device_cert(
+                        Some(&intermediate_full_chain_cert),
+                        &cert_info.cert_path,
+                        &cert_info.key_path,
+                        img,
+                    )
+                },
+            )?
         }
         Command::Identity(SetEdgeCaCertificate {
             intermediate_full_chain_cert,
@@ -281,6 +575,10 @@ pub fn run() -> Result<()> {
             days,
             generate_bmap,
             compress_image,
+            compression_level,
+            threads,
+            work_dir,
+            output,
         }) => {
             let cert_info = create_image_cert(
                 &image,
@@ -295,14 +593,25 @@ pub fn run() -> Result<()> {
             )
             .context("set_edge_ca_certificate: could not create certificate")?;
 
-            run_image_command(image, generate_bmap, compress_image, |img| {
-                file::set_edge_ca_cert(
-                    Some(&intermediate_full_chain_cert),
-                    &cert_info.cert_path,
-                    &cert_info.key_path,
-                    img,
-                )
-            })?
+            run_image_command(
+                image,
+                ImageOptions {
+                    generate_bmap,
+                    compress_image,
+                    compression_level,
+                    threads,
+                    work_dir,
+                    output,
+                },
+                |img| {
+                    file::set_edge_ca_cert(
+                        Some(&intermediate_full_chain_cert),
+                        &cert_info.cert_path,
+                        &cert_info.key_path,
+                        img,
+                    )
+                },
+            )?
         }
         Command::Identity(SetDeviceCertificateNoEst {
             device_cert: device_cert_pem,
@@ -310,9 +619,22 @@ pub fn run() -> Result<()> {
             image,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img| {
-            file::set_device_cert(None, &device_cert_pem, &device_key_pem, img)
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img| file::set_device_cert(None, &device_cert_pem, &device_key_pem, img),
+        )?,
         Command::Identity(SetIotedgeGatewayConfig {
             config,
             image,
@@ -321,40 +643,96 @@ pub fn run() -> Result<()> {
             device_identity_key,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iotedge_gateway_config(
-                &config,
-                img,
-                &root_ca,
-                &device_identity,
-                &device_identity_key,
-            )
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| {
+                file::set_iotedge_gateway_config(
+                    &config,
+                    img,
+                    &root_ca,
+                    &device_identity,
+                    &device_identity_key,
+                )
+            },
+        )?,
         Command::Identity(SetIotLeafSasConfig {
             config,
             image,
             root_ca,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_leaf_sas_config(&config, img, &root_ca)
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| file::set_iot_leaf_sas_config(&config, img, &root_ca),
+        )?,
         Command::Ssh(SetCertificate {
             image,
             root_ca,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_ssh_tunnel_certificate(img, &root_ca)
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| file::set_ssh_tunnel_certificate(img, &root_ca),
+        )?,
         Command::IotHubDeviceUpdate(IotHubDeviceUpdateSet {
             iot_hub_device_update_config,
             image,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::set_iot_hub_device_update_config(&iot_hub_device_update_config, img)
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| {
+                file::set_iot_hub_device_update_config(&iot_hub_device_update_config, img)
+            },
+        )?,
         Command::IotHubDeviceUpdate(IotHubDeviceUpdate::ImportUpdate {
             import_manifest: import_manifest_path,
             storage_container_name,
@@ -466,18 +844,171 @@ pub fn run() -> Result<()> {
         }
         Command::File(CopyToImage {
             file_copy_params,
+            include,
+            exclude,
             image,
             generate_bmap,
             compress_image,
-        }) => run_image_command(image, generate_bmap, compress_image, |img: &PathBuf| {
-            file::copy_to_image(&file_copy_params, img)
-        })?,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => {
+            let filter = CopyFilter::new(include, exclude);
+            let file_copy_params: Vec<FileCopyToParams> = file_copy_params
+                .into_iter()
+                .map(|p| p.with_filter(filter.clone()))
+                .collect();
+
+            run_image_command(
+                image,
+                ImageOptions {
+                    generate_bmap,
+                    compress_image,
+                    compression_level,
+                    threads,
+                    work_dir,
+                    output,
+                },
+                |img: &PathBuf| file::copy_to_image(&file_copy_params, img),
+            )?
+        }
         Command::File(CopyFromImage {
             file_copy_params,
+            include,
+            exclude,
+            image,
+            no_dereference,
+            work_dir,
+        }) => {
+            let filter = CopyFilter::new(include, exclude);
+            let file_copy_params: Vec<FileCopyFromParams> = file_copy_params
+                .into_iter()
+                .map(|p| p.with_filter(filter.clone()))
+                .collect();
+
+            run_image_read_command(image, work_dir, |img: &PathBuf| {
+                file::copy_from_image(&file_copy_params, img, !no_dereference)
+            })?
+        }
+        Command::File(Rm {
+            paths,
+            image,
+            recursive,
+            generate_bmap,
+            compress_image,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| file::remove_from_image(&paths, img, recursive),
+        )?,
+        Command::File(Mv {
+            source,
+            destination,
+            image,
+            generate_bmap,
+            compress_image,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
             image,
-        }) => run_image_command(image, false, None, |img: &PathBuf| {
-            file::copy_from_image(&file_copy_params, img)
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| file::move_in_image(&source, &destination, img),
+        )?,
+        Command::File(Ls {
+            paths,
+            image,
+            recursive,
+            json,
+            work_dir,
+        }) => run_image_read_command(image, work_dir, |img: &PathBuf| {
+            let entries = file::list_image(&paths, img, recursive)?;
+
+            if json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&entries)
+                        .context("ls: cannot serialize entries")?
+                );
+            } else {
+                entries.iter().for_each(|e| println!("{e}"));
+            }
+
+            Ok(())
         })?,
+        Command::Image(Info {
+            image,
+            json,
+            work_dir,
+        }) => {
+            let original = image.clone();
+
+            run_image_read_command(image, work_dir, |img: &PathBuf| {
+                // the compression of the original, img might be a decompressed copy
+                let compression = Compression::from_file(&original)?;
+                let info = image::image_info(img, compression)?;
+
+                if json {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&info)
+                            .context("info: cannot serialize image info")?
+                    );
+                } else {
+                    println!("{info}");
+                }
+
+                Ok(())
+            })?
+        }
+        Command::Image(ResizePartition {
+            image,
+            partition,
+            size,
+            generate_bmap,
+            compress_image,
+            compression_level,
+            threads,
+            work_dir,
+            output,
+        }) => run_image_command(
+            image,
+            ImageOptions {
+                generate_bmap,
+                compress_image,
+                compression_level,
+                threads,
+                work_dir,
+                output,
+            },
+            |img: &PathBuf| file::resize_partition(&partition, &size, img),
+        )?,
+        Command::Image(Flash {
+            image,
+            target,
+            bmap,
+            force,
+        }) => file::flash::flash_image(&image, bmap.as_deref(), &target, force)?,
     }
 
     Ok(())
@@ -0,0 +1,153 @@
+use log::{debug, error};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once};
+use std::{process, thread};
+
+// temporary files and directories which still exist
+static PATHS: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+// write end of the pipe which passes caught signals to the cleanup thread
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Temporary file or directory, which is removed when dropped and also if the
+/// process is terminated by SIGINT or SIGTERM.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    /// Registers `path` for removal. It doesn't need to exist yet, so that it
+    /// is also removed if the process is terminated while creating it.
+    pub fn new(path: PathBuf) -> TempPath {
+        install_signal_handler();
+        lock_paths().push(path.clone());
+        TempPath(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Renames the file to `to`, which is atomically replaced if it exists.
+    pub fn persist(self, to: &Path) -> io::Result<()> {
+        // a signal either removes the file before or doesn't see it anymore
+        let mut paths = lock_paths();
+        fs::rename(&self.0, to)?;
+        paths.retain(|p| *p != self.0);
+        Ok(())
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let mut paths = lock_paths();
+        paths.retain(|p| *p != self.0);
+        remove(&self.0);
+    }
+}
+
+fn lock_paths() -> std::sync::MutexGuard<'static, Vec<PathBuf>> {
+    // the paths stay consistent even if a thread panicked while holding the lock
+    PATHS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn remove(path: &Path) {
+    let res = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            error!("cannot remove {}: {e}", path.display())
+        }
+        _ => {}
+    }
+}
+
+// Only passes the signal on, as the handler may merely call async-signal-safe
+// functions like write.
+extern "C" fn on_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    // SAFETY: the pipe stays open for the lifetime of the process and the
+    // buffer is valid for one byte
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
+
+// Removes the temporary paths and exits on SIGINT and SIGTERM. The cleanup
+// runs in a thread of its own, which gets the signals through a pipe.
+fn install_signal_handler() {
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for both ends of the pipe
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            error!(
+                "cannot install signal handler: {}",
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        SIGNAL_PIPE.store(fds[1], Ordering::Relaxed);
+
+        // SAFETY: the read end of the pipe is owned by this file only
+        let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+        thread::spawn(move || {
+            let mut signal = [0u8];
+            if pipe.read_exact(&mut signal).is_ok() {
+                debug!("caught signal {}, removing temporary files", signal[0]);
+                for path in lock_paths().iter() {
+                    remove(path);
+                }
+                // exit code of a shell for processes killed by a signal
+                process::exit(128 + signal[0] as i32);
+            }
+        });
+
+        for signal in [libc::SIGINT, libc::SIGTERM] {
+            // SAFETY: on_signal is async-signal-safe
+            unsafe {
+                libc::signal(signal, on_signal as *const () as libc::sighandler_t);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("dir");
+        let file = tmp.path().join("file");
+        let dest = tmp.path().join("dest");
+
+        let temp_dir = TempPath::new(dir.clone());
+        fs::create_dir(temp_dir.path()).unwrap();
+        fs::write(dir.join("content"), "content").unwrap();
+        drop(temp_dir);
+        assert!(!dir.exists());
+
+        fs::write(&dest, "old").unwrap();
+        let temp_file = TempPath::new(file.clone());
+        fs::write(temp_file.path(), "new").unwrap();
+        temp_file.persist(&dest).unwrap();
+        assert!(!file.exists());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!lock_paths().contains(&file));
+
+        // paths which were never created are fine
+        drop(TempPath::new(tmp.path().join("missing")));
+    }
+}
@@ -1,9 +1,11 @@
-use data_encoding::HEXUPPER;
+use data_encoding::{HEXLOWER, HEXUPPER};
 use env_logger::{Builder, Env};
+use regex::Regex;
 use ring::digest::{Context, SHA256};
-use std::fs::{File, copy, create_dir_all, remove_dir_all};
-use std::io::{BufReader, Read};
+use std::fs::{File, copy, create_dir_all, remove_dir_all, remove_file};
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::PathBuf;
+use std::process::Command;
 use std::sync::LazyLock;
 
 const TMPDIR_FORMAT_STR: &str = "/tmp/omnect-cli-integration-tests/";
@@ -56,6 +58,92 @@ impl Testrunner {
 
         HEXUPPER.encode(context.finish().as_ref())
     }
+
+    /// Checks the file checksum and all range checksums of a bmap file
+    /// against the image, i.e. the same as bmaptool does when copying.
+    pub fn verify_bmap(image: &PathBuf, bmap: &PathBuf) {
+        let xml = std::fs::read_to_string(bmap).unwrap();
+        let value = |tag: &str| {
+            Regex::new(&format!("<{tag}> *([^ <]+) *</{tag}>"))
+                .unwrap()
+                .captures(&xml)
+                .unwrap_or_else(|| panic!("{tag} missing"))[1]
+                .to_string()
+        };
+
+        let file_checksum = value("BmapFileChecksum");
+        let zeroed = xml.replace(&file_checksum, &"0".repeat(file_checksum.len()));
+        let mut context = Context::new(&SHA256);
+        context.update(zeroed.as_bytes());
+        assert_eq!(HEXLOWER.encode(context.finish().as_ref()), file_checksum);
+
+        let image_size: u64 = value("ImageSize").parse().unwrap();
+        let block_size: u64 = value("BlockSize").parse().unwrap();
+        let mut input = File::open(image).unwrap();
+        assert_eq!(input.metadata().unwrap().len(), image_size);
+
+        let range =
+            Regex::new(r#"<Range chksum="([0-9a-f]+)"> *(\d+)(?:-(\d+))? *</Range>"#).unwrap();
+        let mut ranges = 0;
+        for c in range.captures_iter(&xml) {
+            let first: u64 = c[2].parse().unwrap();
+            let last: u64 = c.get(3).map_or(first, |l| l.as_str().parse().unwrap());
+            let start = first * block_size;
+            let end = ((last + 1) * block_size).min(image_size);
+
+            let mut data = vec![0; (end - start) as usize];
+            input.seek(SeekFrom::Start(start)).unwrap();
+            input.read_exact(&mut data).unwrap();
+
+            let mut context = Context::new(&SHA256);
+            context.update(&data);
+            assert_eq!(HEXLOWER.encode(context.finish().as_ref()), c[1]);
+            ranges += 1;
+        }
+        assert!(ranges > 0);
+    }
+
+    /// Runs `e2fsck -fn` on all ext4 partitions of an uncompressed image.
+    pub fn check_ext4_partitions(&self, image: &PathBuf) {
+        let output = Command::new(env!("CARGO_BIN_EXE_omnect-cli"))
+            .args(["image", "info", "--json", "-i"])
+            .arg(image)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+        let mut input = File::open(image).unwrap();
+        for p in info["partition_table"]["partitions"].as_array().unwrap() {
+            if p["filesystem"] != "ext4" {
+                continue;
+            }
+
+            let partition = self.pathbuf().join(format!("partition{}.ext4", p["num"]));
+            let mut output = File::create(&partition).unwrap();
+            input
+                .seek(SeekFrom::Start(p["start"].as_u64().unwrap()))
+                .unwrap();
+            std::io::copy(
+                &mut (&mut input).take(p["size"].as_u64().unwrap()),
+                &mut output,
+            )
+            .unwrap();
+
+            let fsck = Command::new("e2fsck")
+                .arg("-fn")
+                .arg(&partition)
+                .output()
+                .unwrap();
+            assert!(
+                fsck.status.success(),
+                "e2fsck of partition {}: {}",
+                p["num"],
+                String::from_utf8_lossy(&fsck.stdout)
+            );
+            remove_file(&partition).unwrap();
+        }
+    }
 }
 
 impl Drop for Testrunner {
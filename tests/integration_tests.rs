@@ -605,6 +605,26 @@ fn check_set_device_cert_no_est() {
         device_crt_key_path.to_str().unwrap(),
         device_cert_key_out_path
     ));
+
+    // the test image has no key service account, so keys are owned by root
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("cert:/priv")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(
+                r"(?m)^-rw-------\s+0\s+0 .* cert:/priv/device_id_cert_key.pem$",
+            )
+            .unwrap(),
+        )
+        .stdout(
+            predicate::str::is_match(r"(?m)^-rw-r--r--\s+0\s+0 .* cert:/priv/device_id_cert.pem$")
+                .unwrap(),
+        );
 }
 
 #[test]
@@ -685,8 +705,47 @@ fn check_set_iot_hub_device_update_template() {
     let adu_config_file_path = tr.to_pathbuf("conf/du-config.json.template");
     let image_path = tr.to_pathbuf("testfiles/image.wic");
 
+    // the config takes the modification time of its source file
+    std::fs::File::options()
+        .write(true)
+        .open(&adu_config_file_path)
+        .unwrap()
+        .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1600000000))
+        .unwrap();
+
+    let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = set_iot_hub_device_update_config
+        .env_remove("SOURCE_DATE_EPOCH")
+        .arg("iot-hub-device-update")
+        .arg("set-device-config")
+        .arg("-c")
+        .arg(&adu_config_file_path)
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mtime = || {
+        let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+        let output = ls
+            .arg("file")
+            .arg("ls")
+            .arg("--json")
+            .arg("-i")
+            .arg(&image_path)
+            .arg("factory:/etc/adu/du-config.json")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        entries[0]["mtime"].clone()
+    };
+    assert_eq!(mtime(), "2020-09-13T12:26:40Z");
+
+    // SOURCE_DATE_EPOCH takes precedence
     let mut set_iot_hub_device_update_config = Command::cargo_bin("omnect-cli").unwrap();
     let assert = set_iot_hub_device_update_config
+        .env("SOURCE_DATE_EPOCH", "1700000000")
         .arg("iot-hub-device-update")
         .arg("set-device-config")
         .arg("-c")
@@ -695,6 +754,7 @@ fn check_set_iot_hub_device_update_template() {
         .arg(&image_path)
         .assert();
     assert.success();
+    assert_eq!(mtime(), "2023-11-14T22:13:20Z");
 
     let mut adu_config_file_out_path = tr.pathbuf();
     adu_config_file_out_path.push("dir1");
@@ -893,14 +953,57 @@ fn check_file_copy(tr: Testrunner, partition: &str) {
     assert_eq!(image_path_hash1, image_path_hash2);
     assert!(file_diff::diff(in_file3, out_file3));
     assert!(file_diff::diff(in_file4, out_file4));
+
+    tr.check_ext4_partitions(&image_path);
 }
 
 #[test]
-fn check_bmap_generation_wic() {
+fn check_file_copy_symlink() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
     let image_path = tr.to_pathbuf("testfiles/image.wic");
-    let image_path_copy = PathBuf::from(format!("{}.copy", image_path.to_str().unwrap()));
-    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let mut out_file1 = tr.pathbuf();
+    out_file1.push("os-release");
+    let out_file1 = out_file1.to_str().unwrap();
+    let mut out_file2 = tr.pathbuf();
+    out_file2.push("os-release-link");
+    let out_file2 = out_file2.to_str().unwrap();
+
+    // /etc/os-release is a relative link to /usr/lib/os-release
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("rootA:/etc/os-release,{out_file1}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(
+        std::fs::read_to_string(out_file1)
+            .unwrap()
+            .contains("OMNECT_TARGET_ARCH=\"aarch64\"")
+    );
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("--no-dereference")
+        .arg("-f")
+        .arg(format!("rootA:/etc/os-release,{out_file2}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::read_link(out_file2).unwrap(),
+        std::path::PathBuf::from("../usr/lib/os-release")
+    );
+
+    // writing through a link replaces the content of its target
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
     let in_file = in_file.to_str().unwrap();
 
@@ -909,78 +1012,258 @@ fn check_bmap_generation_wic() {
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{in_file},rootA:/etc/os-release"))
         .arg("-i")
         .arg(&image_path)
         .assert();
+    assert.success();
 
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("rootA:/usr/lib/os-release,{out_file1}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
     assert.success();
 
-    assert!(!bmap_path.try_exists().is_ok_and(|exists| exists));
+    assert!(file_diff::diff(in_file, out_file1));
+
+    tr.check_ext4_partitions(&image_path);
+}
+
+#[test]
+fn check_file_ls() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{in_file},boot:/dir/boot.scr"))
         .arg("-i")
         .arg(&image_path)
-        .arg("-b")
         .assert();
     assert.success();
 
-    assert!(bmap_path.try_exists().is_ok_and(|exists| exists));
-
-    // use bmaptool to verify that the checksum of the bmap file and the image
-    // still match after the copy operations
-    let assert = Command::new("bmaptool")
-        .arg("copy")
-        .args(["--bmap", &bmap_path.to_string_lossy()])
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
         .arg(&image_path)
-        .arg(&image_path_copy)
-        .assert();
-    assert.success();
+        .arg("rootA:/etc")
+        .arg("boot:/")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            "rootA:/etc/os-release -> ../usr/lib/os-release",
+        ))
+        .stdout(
+            predicate::str::is_match(r"(?m)^-rw-r--r--\s+0\s+0\s+21 .* rootA:/etc/hosts$").unwrap(),
+        )
+        .stdout(predicate::str::is_match(r"(?m)^drwxr-xr-x .* boot:/dir$").unwrap())
+        .stdout(predicate::str::contains("boot:/dir/boot.scr").not());
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    let output = ls
+        .arg("file")
+        .arg("ls")
+        .arg("--recursive")
+        .arg("--json")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("rootA:/usr")
+        .arg("boot:/")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entries = entries.as_array().unwrap();
+    let entry = |path: &str| {
+        entries
+            .iter()
+            .find(|e| e["path"] == path)
+            .unwrap_or_else(|| panic!("{path} not listed"))
+    };
+
+    assert_eq!(entry("/usr/lib")["type"], "dir");
+    assert_eq!(entry("/usr/lib/os-release")["type"], "file");
+    assert_eq!(entry("/usr/lib/os-release")["size"], 1123);
+    assert_eq!(entry("/usr/lib/os-release")["mode"], "0664");
+    assert_eq!(entry("/usr/lib/os-release")["uid"], 1000);
+    assert_eq!(entry("/dir/boot.scr")["partition"], "boot");
+    assert_eq!(
+        entry("/dir/boot.scr")["size"],
+        std::fs::metadata(in_file).unwrap().len()
+    );
 }
 
 #[test]
-fn check_bmap_generation_wic_xz() {
+fn check_file_rm_mv_dos_partition() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
-    let image_path_wic = image_path_wic_xz.with_extension("");
-    let image_path_wic_copy = image_path_wic_xz.with_extension("copy");
-    let image_path_bmap = image_path_wic_xz.with_extension("bmap");
+    check_file_rm_mv(tr, "boot");
+}
+
+#[test]
+fn check_file_rm_mv_ext4() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    check_file_rm_mv(tr, "factory");
+}
+
+fn check_file_rm_mv(tr: Testrunner, partition: &str) {
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
     let in_file = in_file.to_str().unwrap();
+    let mut out_file = tr.pathbuf();
+    out_file.push("moved.scr");
+    let out_file = out_file.to_str().unwrap();
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{in_file},{partition}:/dir/sub/file1.scr"))
+        .arg("-f")
+        .arg(format!("{in_file},{partition}:/dir/file2.scr"))
+        .arg("-f")
+        .arg(format!("{in_file},{partition}:/stale.json"))
         .arg("-i")
-        .arg(&image_path_wic_xz)
-        .arg("-b")
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    // use bmaptool to verify that the checksum of the bmap file and the image
-    // still match after the copy operations
-    let assert = Command::new("bmaptool")
-        .arg("copy")
-        .args(["--bmap", &image_path_bmap.to_string_lossy()])
-        .arg(&image_path_wic)
-        .arg(&image_path_wic_copy)
+    // rename a file and move it into an existing directory
+    let mut mv = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = mv
+        .arg("file")
+        .arg("mv")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/dir/sub/file1.scr"))
+        .arg(format!("{partition}:/moved.scr"))
+        .assert();
+    assert.success();
+
+    let mut mv = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = mv
+        .arg("file")
+        .arg("mv")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/moved.scr"))
+        .arg(format!("{partition}:/dir/sub"))
+        .assert();
+    assert.success();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("{partition}:/dir/sub/moved.scr,{out_file}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file));
+
+    // moving between partitions is not supported
+    let mut mv = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = mv
+        .arg("file")
+        .arg("mv")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/stale.json"))
+        .arg("cert:/stale.json")
+        .assert();
+    assert.failure();
+
+    // renaming a directory, even if only the case of its name changes
+    for (from, to) in [("/dir", "/Dir"), ("/Dir", "/dir")] {
+        let mut mv = Command::cargo_bin("omnect-cli").unwrap();
+        mv.arg("file")
+            .arg("mv")
+            .arg("-i")
+            .arg(&image_path)
+            .arg(format!("{partition}:{from}"))
+            .arg(format!("{partition}:{to}"))
+            .assert()
+            .success();
+
+        let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+        ls.arg("file")
+            .arg("ls")
+            .arg("-i")
+            .arg(&image_path)
+            .arg(format!("{partition}:/"))
+            .assert()
+            .success()
+            .stdout(predicate::str::contains(format!("{partition}:{to}")))
+            .stdout(predicate::str::contains(format!("{partition}:{from}")).not());
+    }
+
+    // directories are only removed with --recursive
+    let mut rm = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = rm
+        .arg("file")
+        .arg("rm")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/dir"))
+        .assert();
+    assert.failure();
+
+    let mut rm = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = rm
+        .arg("file")
+        .arg("rm")
+        .arg("--recursive")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/dir"))
+        .arg(format!("{partition}:/stale.json"))
         .assert();
     assert.success();
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("{partition}:/dir")).not())
+        .stdout(predicate::str::contains(format!("{partition}:/stale.json")).not());
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("{partition}:/dir/file2.scr,{out_file}"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+
+    tr.check_ext4_partitions(&image_path);
 }
 
 #[test]
-fn check_image_compression() {
+fn check_file_copy_partition_selectors() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
-    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
     let in_file = in_file.to_str().unwrap();
 
@@ -989,72 +1272,1228 @@ fn check_image_compression() {
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{in_file},rootB:/rootB.scr"))
+        .arg("-f")
+        .arg(format!("{in_file},7:/etc.scr"))
+        .arg("-f")
+        .arg(format!("{in_file},label=data:/data.scr"))
         .arg("-i")
-        .arg(&image_path_wic)
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(!image_path_wic_xz.try_exists().is_ok_and(|exists| exists));
+    for (partition, file) in [("3", "rootB.scr"), ("etc", "etc.scr"), ("data", "data.scr")] {
+        let mut out_file = tr.pathbuf();
+        out_file.push(file);
+        let out_file = out_file.to_str().unwrap();
+
+        let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_from_img
+            .arg("file")
+            .arg("copy-from-image")
+            .arg("-f")
+            .arg(format!("{partition}:/{file},{out_file}"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.success();
+
+        assert!(file_diff::diff(in_file, out_file));
+    }
+
+    // the test image has a MBR without partition names
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("name=data:/")
+        .assert()
+        .failure();
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("label=unknown:/")
+        .assert()
+        .failure();
+
+    // different selectors of the same partition
+    let mut mv = Command::cargo_bin("omnect-cli").unwrap();
+    mv.arg("file")
+        .arg("mv")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("label=data:/data.scr")
+        .arg("8:/moved.scr")
+        .assert()
+        .success();
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("data:/moved.scr")
+        .assert()
+        .success();
+}
+
+#[test]
+fn check_file_copy_unexpected_filesystem() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    // destroy the ext4 superblock magic of the cert partition (6)
+    let mut image = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&image_path)
+        .unwrap();
+    image
+        .seek(SeekFrom::Start(40960 * 512 + 1024 + 0x38))
+        .unwrap();
+    image.write_all(&[0, 0]).unwrap();
+    drop(image);
+
+    let hash = Testrunner::file_hash(&image_path);
+
+    for partition in ["cert", "6"] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},{partition}:/boot.scr"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert();
+        assert.failure();
+    }
+
+    assert_eq!(hash, Testrunner::file_hash(&image_path));
 
+    // the FAT boot partition isn't mistaken for an ext4 one
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!("{in_file},label=boot:/boot.scr"))
         .arg("-i")
-        .arg(&image_path_wic)
-        .arg("-p")
-        .arg("xz")
+        .arg(&image_path)
         .assert();
     assert.success();
+}
 
-    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+#[test]
+fn check_file_copy_no_space_left() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let small_file = tr.to_pathbuf("testfiles/boot.scr");
+    let small_file = small_file.to_str().unwrap();
+    let big_file = tr.pathbuf().join("big_file");
+    std::fs::write(&big_file, vec![0xa5; 2 * 1024 * 1024]).unwrap();
+    let big_file = big_file.to_str().unwrap();
 
+    let hash = Testrunner::file_hash(&image_path);
+
+    // nothing is written, not even to partitions with enough space
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
-    let assert = copy_to_img
+    copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},factory:/my-file"))
+        .arg(format!("{small_file},boot:/boot.scr"))
+        .arg("-f")
+        .arg(format!("{big_file},factory:/big_file"))
         .arg("-i")
-        .arg(&image_path_wic_xz)
-        .arg("-p")
-        .arg("xz")
-        .assert();
-    assert.success();
+        .arg(&image_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "not enough space on partition factory",
+        ))
+        .stderr(predicate::str::is_match(r"\d+ bytes missing").unwrap());
+
+    assert_eq!(hash, Testrunner::file_hash(&image_path));
+
+    // space of an overwritten file is available again
+    std::fs::write(tr.pathbuf().join("big_file"), vec![0xa5; 600 * 1024]).unwrap();
+    for _ in 0..2 {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{big_file},factory:/big_file"))
+            .arg("-i")
+            .arg(&image_path)
+            .assert()
+            .success();
+    }
 
-    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+    // the space of a file overwritten twice is only available once
+    let medium_file = tr.pathbuf().join("medium_file");
+    std::fs::write(&medium_file, vec![0xa5; 500 * 1024]).unwrap();
+    let medium_file = medium_file.to_str().unwrap();
 
-    assert_ne!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+    let hash = Testrunner::file_hash(&image_path);
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{small_file},factory:/big_file"))
+        .arg("-f")
+        .arg(format!("{big_file},factory:/big_file"))
+        .arg("-f")
+        .arg(format!("{medium_file},factory:/medium_file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "not enough space on partition factory",
+        ));
+    assert_eq!(hash, Testrunner::file_hash(&image_path));
+
+    tr.check_ext4_partitions(&image_path);
 }
 
 #[test]
-fn check_image_decompression() {
+fn check_file_copy_attributes() {
     let tr = Testrunner::new(function_name!().split("::").last().unwrap());
-    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
-    let image_path_wic = image_path_wic_xz.with_extension("");
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
     let in_file = tr.to_pathbuf("testfiles/boot.scr");
     let in_file = in_file.to_str().unwrap();
-    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
 
     let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
     let assert = copy_to_img
         .arg("file")
         .arg("copy-to-image")
         .arg("-f")
-        .arg(format!("{in_file},boot:/my-file"))
+        .arg(format!(
+            "{in_file},factory:/etc/secret.pem,uid=1001,gid=70000,mode=0600,mtime=2023-11-14T22:13:20Z"
+        ))
+        .arg("-f")
+        .arg(format!("{in_file},boot:/readonly.scr,mode=0444,mtime=1700000000"))
         .arg("-i")
-        .arg(&image_path_wic_xz)
+        .arg(&image_path)
         .assert();
     assert.success();
 
-    assert!(image_path_wic.try_exists().is_ok_and(|exists| exists));
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    let output = ls
+        .arg("file")
+        .arg("ls")
+        .arg("--json")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("factory:/etc/secret.pem")
+        .arg("boot:/readonly.scr")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
 
-    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+    let entries: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let entries = entries.as_array().unwrap();
 
-    assert_eq!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+    assert_eq!(entries[0]["path"], "/etc/secret.pem");
+    assert_eq!(entries[0]["uid"], 1001);
+    assert_eq!(entries[0]["gid"], 70000);
+    assert_eq!(entries[0]["mode"], "0600");
+    assert_eq!(entries[0]["mtime"], "2023-11-14T22:13:20Z");
+    assert_eq!(entries[1]["path"], "/readonly.scr");
+    assert_eq!(entries[1]["mode"], "0444");
+    assert_eq!(entries[1]["mtime"], "2023-11-14T22:13:20Z");
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/etc/secret.pem,mode=rw"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure();
+
+    tr.check_ext4_partitions(&image_path);
+}
+
+#[test]
+fn check_file_copy_dir_dos_partition() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    check_file_copy_dir(tr, "boot");
+}
+
+#[test]
+fn check_file_copy_dir_ext4() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    check_file_copy_dir(tr, "factory");
+}
+
+fn check_file_copy_dir(tr: Testrunner, partition: &str) {
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let in_dir = tr.pathbuf().join("in");
+    let out_dir = tr.pathbuf().join("out");
+
+    std::fs::create_dir_all(in_dir.join("sub/empty")).unwrap();
+    std::fs::create_dir_all(in_dir.join("skip")).unwrap();
+    std::fs::copy(in_file, in_dir.join("a.scr")).unwrap();
+    std::fs::copy(in_file, in_dir.join("sub/b.scr")).unwrap();
+    std::fs::copy(in_file, in_dir.join("skip/c.scr")).unwrap();
+    std::fs::write(in_dir.join("sub/notes.txt"), "notes").unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{},{partition}:/dir", in_dir.to_str().unwrap()))
+        .arg("--exclude")
+        .arg("skip")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("--recursive")
+        .arg("-i")
+        .arg(&image_path)
+        .arg(format!("{partition}:/dir"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!("{partition}:/dir/a.scr")))
+        .stdout(predicate::str::contains(format!(
+            "{partition}:/dir/sub/b.scr"
+        )))
+        .stdout(predicate::str::contains(format!(
+            "{partition}:/dir/sub/notes.txt"
+        )))
+        .stdout(predicate::str::contains(format!(
+            "{partition}:/dir/sub/empty"
+        )))
+        .stdout(predicate::str::contains(format!("{partition}:/dir/skip")).not());
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("{partition}:/dir,{}", out_dir.to_str().unwrap()))
+        .arg("--include")
+        .arg("*.scr")
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(
+        in_file,
+        out_dir.join("a.scr").to_str().unwrap()
+    ));
+    assert!(file_diff::diff(
+        in_file,
+        out_dir.join("sub/b.scr").to_str().unwrap()
+    ));
+    assert!(!out_dir.join("sub/notes.txt").exists());
+    assert!(!out_dir.join("sub/empty").exists());
+
+    tr.check_ext4_partitions(&image_path);
+}
+
+#[test]
+fn check_bmap_generation_wic() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let bmap_path = PathBuf::from(format!("{}.bmap", image_path.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+
+    assert.success();
+
+    assert!(!bmap_path.try_exists().is_ok_and(|exists| exists));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    assert!(bmap_path.try_exists().is_ok_and(|exists| exists));
+
+    // verify that the checksums of the bmap file and the image still match
+    // after the copy operations
+    Testrunner::verify_bmap(&image_path, &bmap_path);
+}
+
+#[test]
+fn check_bmap_generation_wic_xz() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let image_path_wic = image_path_wic_xz.with_extension("");
+    let image_path_bmap = image_path_wic_xz.with_extension("bmap");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-b")
+        .assert();
+    assert.success();
+
+    // verify that the checksums of the bmap file and the image still match
+    // after the copy operations
+    Testrunner::verify_bmap(&image_path_wic, &image_path_bmap);
+}
+
+#[test]
+fn check_image_compression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .assert();
+    assert.success();
+
+    assert!(!image_path_wic_xz.try_exists().is_ok_and(|exists| exists));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},factory:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+
+    assert_ne!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+}
+
+#[test]
+fn check_image_compression_zstd() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_zst = PathBuf::from(format!("{}.zst", image_path_wic.to_str().unwrap()));
+    let out_file_path = tr.pathbuf().join("my-file");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    // the level is validated for the chosen algorithm
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-p")
+        .arg("xz")
+        .arg("--compression-level")
+        .arg("19")
+        .assert();
+    assert.failure().stderr(predicate::str::contains(
+        "invalid compression level 19 for xz",
+    ));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-p")
+        .arg("zstd")
+        .arg("--compression-level")
+        .arg("3")
+        .assert();
+    assert.success();
+
+    assert!(image_path_wic_zst.try_exists().is_ok_and(|exists| exists));
+
+    let mut image_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = image_info
+        .arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&image_path_wic_zst)
+        .arg("--json")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains(r#""compression": "zstd""#));
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path_wic_zst)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+}
+
+#[test]
+fn check_image_read_seekable_xz() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let out_file_path = tr.pathbuf().join("my-file");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    // reading the packed image must not decompress it next to itself
+    std::fs::remove_file(&image_path_wic).unwrap();
+    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = ls
+        .arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("boot:/")
+        .assert();
+    assert.success().stdout(predicate::str::contains("my-file"));
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+    assert!(!image_path_wic.try_exists().is_ok_and(|exists| exists));
+    assert_eq!(
+        image_path_wic_xz_hash1,
+        Testrunner::file_hash(&image_path_wic_xz)
+    );
+}
+
+#[test]
+fn check_image_read_in_place() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_gzip = PathBuf::from(format!("{}.gzip", image_path_wic.to_str().unwrap()));
+    let out_file_path = tr.pathbuf().join("my-file");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    for pack in [&[][..], &["-p", "gzip"]] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-i")
+            .arg(&image_path_wic)
+            .args(pack)
+            .assert();
+        assert.success();
+    }
+
+    // uncompressed images are neither copied nor written back
+    let modified = std::fs::metadata(&image_path_wic)
+        .unwrap()
+        .modified()
+        .unwrap();
+    let image_path_wic_hash = Testrunner::file_hash(&image_path_wic);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .assert();
+    assert.success();
+
+    let mut image_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = image_info
+        .arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&image_path_wic)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+    assert_eq!(
+        modified,
+        std::fs::metadata(&image_path_wic)
+            .unwrap()
+            .modified()
+            .unwrap()
+    );
+    assert_eq!(image_path_wic_hash, Testrunner::file_hash(&image_path_wic));
+
+    // images without random access are decompressed, but not written back
+    std::fs::remove_file(&image_path_wic).unwrap();
+    std::fs::remove_file(&out_file_path).unwrap();
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&image_path_wic_gzip)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+    assert!(!image_path_wic.try_exists().is_ok_and(|exists| exists));
+}
+
+#[test]
+fn check_image_work_dir() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic.xz");
+    let work_dir = tr.pathbuf().join("work");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .arg("--work-dir")
+        .arg(&work_dir)
+        .assert();
+    assert.success();
+
+    // the temporary files are removed again
+    assert_eq!(std::fs::read_dir(&work_dir).unwrap().count(), 0);
+
+    // TMPDIR is used without --work-dir, a file can't hold temporary files
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .env("TMPDIR", in_file)
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert();
+    assert.failure().stderr(predicate::str::contains(format!(
+        "destination path {in_file}/"
+    )));
+}
+
+#[test]
+fn check_image_output() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let output_zst = tr.pathbuf().join("personalized.wic.zst");
+    let output_wic = tr.pathbuf().join("personalized.wic");
+    let out_file_path = tr.pathbuf().join("my-file");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let image_path_wic_xz_hash = Testrunner::file_hash(&image_path_wic_xz);
+
+    for output in [&output_zst, &output_wic] {
+        let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+        let assert = copy_to_img
+            .arg("file")
+            .arg("copy-to-image")
+            .arg("-f")
+            .arg(format!("{in_file},boot:/my-file"))
+            .arg("-i")
+            .arg(&image_path_wic_xz)
+            .arg("-o")
+            .arg(output)
+            .arg("-b")
+            .assert();
+        assert.success();
+    }
+
+    // the input image is neither modified nor decompressed next to itself
+    assert_eq!(
+        image_path_wic_xz_hash,
+        Testrunner::file_hash(&image_path_wic_xz)
+    );
+    assert!(
+        !image_path_wic_xz
+            .with_extension("")
+            .try_exists()
+            .is_ok_and(|exists| exists)
+    );
+
+    // the bmap file is named after the uncompressed output image
+    Testrunner::verify_bmap(&output_wic, &tr.pathbuf().join("personalized.wic.bmap"));
+
+    let mut image_info = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = image_info
+        .arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&output_zst)
+        .arg("--json")
+        .assert();
+    assert
+        .success()
+        .stdout(predicate::str::contains(r#""compression": "zstd""#));
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&output_zst)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+
+    // packing is chosen by the output extension
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-o")
+        .arg(&output_wic)
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.failure();
+}
+
+#[test]
+fn check_image_decompression() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic_xz = tr.to_pathbuf("testfiles/image.wic.xz");
+    let image_path_wic = image_path_wic_xz.with_extension("");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+    let image_path_wic_xz_hash1 = Testrunner::file_hash(&image_path_wic_xz);
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .assert();
+    assert.success();
+
+    assert!(image_path_wic.try_exists().is_ok_and(|exists| exists));
+
+    let image_path_wic_xz_hash2 = Testrunner::file_hash(&image_path_wic_xz);
+
+    assert_eq!(image_path_wic_xz_hash1, image_path_wic_xz_hash2);
+}
+
+#[test]
+fn check_image_info() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic.xz");
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    info.arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("compression: xz"))
+        .stdout(predicate::str::contains("architecture: aarch64"))
+        .stdout(predicate::str::contains("omnect version: 4.0.18.0"))
+        .stdout(predicate::str::contains("partition table: mbr"))
+        .stdout(
+            predicate::str::is_match(
+                r"(?m)^\s+1\s+4194304\s+1048576\s+0x0c\s+-\s+FAT\s+boot\s+\d+$",
+            )
+            .unwrap(),
+        )
+        .stdout(predicate::str::contains("  ID=omnect-os"));
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    let output = info
+        .arg("image")
+        .arg("info")
+        .arg("--json")
+        .arg("-i")
+        .arg(tr.to_pathbuf("testfiles/image.wic"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let partition = |label: &str| {
+        info["partition_table"]["partitions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["label"] == label)
+            .unwrap_or_else(|| panic!("{label} not listed"))
+            .clone()
+    };
+
+    assert_eq!(info["compression"], serde_json::Value::Null);
+    assert_eq!(info["architecture"], "aarch64");
+    assert_eq!(info["omnect_version"], "4.0.18.0");
+    assert_eq!(info["os_release"]["MACHINE"], "raspberrypi4-64");
+    assert_eq!(info["partition_table"]["type"], "mbr");
+    assert_eq!(partition("rootA")["num"], 2);
+    assert_eq!(partition("rootA")["start"], 16384 * 512);
+    assert_eq!(partition("rootA")["filesystem"], "ext4");
+    assert!(partition("data")["free"].as_u64().unwrap() > 0);
+
+    // /etc/os-release takes precedence over /usr/lib/os-release
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let os_release = tr.pathbuf().join("os-release");
+    std::fs::write(
+        &os_release,
+        "OMNECT_TARGET_ARCH=\"x86_64\"\nVERSION_ID=5.0.0.0\n",
+    )
+    .unwrap();
+    let mut rm = Command::cargo_bin("omnect-cli").unwrap();
+    rm.arg("file")
+        .arg("rm")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("rootA:/etc/os-release")
+        .assert()
+        .success();
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{},rootA:/etc/os-release", os_release.display()))
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success();
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    info.arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("architecture: x86_64"))
+        .stdout(predicate::str::contains("omnect version: 5.0.0.0"));
+
+    // images without os-release still get their partitions listed
+    let mut rm = Command::cargo_bin("omnect-cli").unwrap();
+    rm.arg("file")
+        .arg("rm")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("rootA:/etc/os-release")
+        .arg("rootA:/usr/lib/os-release")
+        .assert()
+        .success();
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    info.arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("architecture: unknown"))
+        .stdout(predicate::str::contains("partition table: mbr"))
+        .stdout(predicate::str::contains("os-release: unknown"));
+
+    // a filesystem that cannot be opened only lacks its free space
+    let mut image = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&image_path)
+        .unwrap();
+    image
+        .seek(SeekFrom::Start(40960 * 512 + 1024 + 0x18))
+        .unwrap();
+    image.write_all(&[0xff]).unwrap();
+    drop(image);
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    let output = info
+        .arg("image")
+        .arg("info")
+        .arg("--json")
+        .arg("-i")
+        .arg(&image_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let partition = |num: u64| {
+        info["partition_table"]["partitions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["num"] == num)
+            .unwrap_or_else(|| panic!("{num} not listed"))
+            .clone()
+    };
+    assert_eq!(partition(6)["filesystem"], "ext4");
+    assert_eq!(partition(6)["free"], serde_json::Value::Null);
+    assert!(partition(1)["free"].as_u64().unwrap() > 0);
+
+    let mut info = Command::cargo_bin("omnect-cli").unwrap();
+    info.arg("image")
+        .arg("info")
+        .arg("-i")
+        .arg(tr.pathbuf().join("missing.wic"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("image doesn"));
+}
+
+#[test]
+fn check_image_flash() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path_wic = tr.to_pathbuf("testfiles/image.wic");
+    let image_path_wic_xz = PathBuf::from(format!("{}.xz", image_path_wic.to_str().unwrap()));
+    let image_path_bmap = image_path_wic.with_extension("wic.bmap");
+    let target_path = tr.pathbuf().join("target.wic");
+    let out_file_path = tr.pathbuf().join("my-file");
+    let in_file = tr.to_pathbuf("testfiles/boot.scr");
+    let in_file = in_file.to_str().unwrap();
+
+    // creates image.wic.xz and image.wic.bmap, image.wic stays unchanged
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{in_file},boot:/my-file"))
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-b")
+        .arg("-p")
+        .arg("xz")
+        .assert();
+    assert.success();
+
+    // a regular file as target is truncated
+    std::fs::write(&target_path, "old content").unwrap();
+
+    // the bmap file is found next to the image
+    let mut flash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = flash
+        .arg("image")
+        .arg("flash")
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-t")
+        .arg(&target_path)
+        .assert();
+    assert.success();
+
+    assert_eq!(
+        std::fs::metadata(&target_path).unwrap().len(),
+        std::fs::metadata(&image_path_wic).unwrap().len()
+    );
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("boot:/my-file,{}", out_file_path.to_str().unwrap()))
+        .arg("-i")
+        .arg(&target_path)
+        .assert();
+    assert.success();
+
+    assert!(file_diff::diff(in_file, out_file_path.to_str().unwrap()));
+
+    // the unchanged image doesn't match the bmap file
+    let mut flash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = flash
+        .arg("image")
+        .arg("flash")
+        .arg("-i")
+        .arg(&image_path_wic)
+        .arg("-m")
+        .arg(&image_path_bmap)
+        .arg("-t")
+        .arg(&target_path)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("checksum mismatch"));
+
+    // the image itself is never a valid target
+    let mut flash = Command::cargo_bin("omnect-cli").unwrap();
+    let assert = flash
+        .arg("image")
+        .arg("flash")
+        .arg("-i")
+        .arg(&image_path_wic_xz)
+        .arg("-t")
+        .arg(&image_path_wic_xz)
+        .assert();
+    assert
+        .failure()
+        .stderr(predicate::str::contains("same file"));
+}
+
+#[test]
+fn check_image_resize_partition() {
+    let tr = Testrunner::new(function_name!().split("::").last().unwrap());
+    let image_path = tr.to_pathbuf("testfiles/image.wic");
+    let big_file = tr.pathbuf().join("big_file");
+    std::fs::write(&big_file, vec![0xa5; 2 * 1024 * 1024]).unwrap();
+    let big_file = big_file.to_str().unwrap();
+
+    let image_info = || {
+        let output = Command::cargo_bin("omnect-cli")
+            .unwrap()
+            .arg("image")
+            .arg("info")
+            .arg("--json")
+            .arg("-i")
+            .arg(&image_path)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        info["partition_table"]["partitions"]
+            .as_array()
+            .unwrap()
+            .clone()
+    };
+    let partition = |partitions: &[serde_json::Value], num: u64| {
+        partitions.iter().find(|p| p["num"] == num).unwrap().clone()
+    };
+
+    let before = image_info();
+
+    // shrinking and FAT filesystems are refused
+    for (partition, size) in [("factory", "512K"), ("boot", "+1M")] {
+        let mut resize = Command::cargo_bin("omnect-cli").unwrap();
+        resize
+            .arg("image")
+            .arg("resize-partition")
+            .arg("-a")
+            .arg(partition)
+            .arg("-s")
+            .arg(size)
+            .arg("-i")
+            .arg(&image_path)
+            .assert()
+            .failure();
+    }
+
+    // factory is a logical partition which is directly followed by cert
+    let mut resize = Command::cargo_bin("omnect-cli").unwrap();
+    resize
+        .arg("image")
+        .arg("resize-partition")
+        .arg("-a")
+        .arg("factory")
+        .arg("-s")
+        .arg("+3M")
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success();
+
+    let after = image_info();
+    assert_eq!(partition(&after, 5)["size"], 4 * 1024 * 1024);
+    assert!(partition(&after, 5)["free"].as_u64().unwrap() > 3 * 1024 * 1024);
+    for num in [1, 2, 3] {
+        assert_eq!(partition(&before, num), partition(&after, num));
+    }
+    for num in [6, 7, 8] {
+        let (before, after) = (partition(&before, num), partition(&after, num));
+        assert!(after["start"].as_u64().unwrap() > before["start"].as_u64().unwrap());
+        assert_eq!(before["size"], after["size"]);
+        assert_eq!(before["label"], after["label"]);
+        assert_eq!(before["free"], after["free"]);
+    }
+
+    // the grown filesystem is usable and moved partitions are intact
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{big_file},factory:/big_file"))
+        .arg("-f")
+        .arg(format!("{big_file},cert:/big_file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "not enough space on partition cert",
+        ));
+
+    let mut copy_to_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_to_img
+        .arg("file")
+        .arg("copy-to-image")
+        .arg("-f")
+        .arg(format!("{big_file},factory:/big_file"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success();
+
+    let mut ls = Command::cargo_bin("omnect-cli").unwrap();
+    ls.arg("file")
+        .arg("ls")
+        .arg("-i")
+        .arg(&image_path)
+        .arg("factory:/big_file")
+        .arg("data:/")
+        .arg("etc:/")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("2097152"));
+
+    tr.check_ext4_partitions(&image_path);
+
+    // growing beyond the first block group adds groups with backup
+    // superblocks, beyond 16 groups also a group descriptor block
+    for size in ["12M", "136M"] {
+        let mut resize = Command::cargo_bin("omnect-cli").unwrap();
+        resize
+            .arg("image")
+            .arg("resize-partition")
+            .arg("-a")
+            .arg("factory")
+            .arg("-s")
+            .arg(size)
+            .arg("-i")
+            .arg(&image_path)
+            .assert()
+            .success();
+
+        tr.check_ext4_partitions(&image_path);
+    }
+
+    let after = image_info();
+    assert_eq!(partition(&after, 5)["size"], 136 * 1024 * 1024);
+    assert!(partition(&after, 5)["free"].as_u64().unwrap() > 120 * 1024 * 1024);
+
+    let mut copy_from_img = Command::cargo_bin("omnect-cli").unwrap();
+    copy_from_img
+        .arg("file")
+        .arg("copy-from-image")
+        .arg("-f")
+        .arg(format!("factory:/big_file,{big_file}.out"))
+        .arg("-i")
+        .arg(&image_path)
+        .assert()
+        .success();
+    assert!(file_diff::diff(big_file, &format!("{big_file}.out")));
 }
 
 #[tokio::test]